@@ -0,0 +1,510 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::timeout,
+};
+
+use crate::{
+    client::{pool_manager::PoolManager, pool_tls::PoolTls},
+    ledger::now_millis,
+    state::Worker,
+    util::config::AlertConfig,
+};
+
+// How often idle miners and pools are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    // No share for `stale_secs`
+    Stale,
+    Offline,
+    RejectRate,
+    PoolDown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+// Body of the webhook POST
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertEvent {
+    // Name of the transit
+    pub instance: String,
+    pub kind: AlertKind,
+    // `wallet.worker` of a miner or the address of a pool
+    pub subject: String,
+    pub status: AlertStatus,
+    pub message: String,
+    // Unix milliseconds
+    pub time: u64,
+}
+
+#[derive(Default)]
+struct WorkerWatch {
+    last_share: Option<Instant>,
+    // (time, accepted, rejected) as reported, oldest first
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+// Turns miner reports and pool health into alerts. An alert is sent once when
+// it starts firing and once when it resolves.
+pub struct Alerter {
+    instance: String,
+    config: AlertConfig,
+    firing: HashSet<(AlertKind, String)>,
+    workers: HashMap<String, WorkerWatch>,
+}
+
+impl Alerter {
+    pub fn new(instance: &str, config: AlertConfig) -> Self {
+        Self {
+            instance: instance.to_string(),
+            config,
+            firing: HashSet::new(),
+            workers: HashMap::new(),
+        }
+    }
+
+    fn set(
+        &mut self, kind: AlertKind, subject: &str, firing: bool, message: String,
+    ) -> Option<AlertEvent> {
+        let key = (kind, subject.to_string());
+        let status = if firing {
+            if !self.firing.insert(key) {
+                return None;
+            }
+            AlertStatus::Firing
+        } else {
+            if !self.firing.remove(&key) {
+                return None;
+            }
+            AlertStatus::Resolved
+        };
+
+        Some(AlertEvent {
+            instance: self.instance.clone(),
+            kind,
+            subject: subject.to_string(),
+            status,
+            message,
+            time: now_millis(),
+        })
+    }
+
+    pub fn observe_worker(&mut self, worker: &Worker, now: Instant) -> Vec<AlertEvent> {
+        let mut events = vec![];
+        let subject = worker.worker.as_str();
+        if subject.is_empty() {
+            return events;
+        }
+
+        if !worker.is_online() {
+            self.workers.remove(subject);
+            if self.config.disconnect {
+                events.extend(self.set(
+                    AlertKind::Offline,
+                    subject,
+                    true,
+                    format!("Miner {} disconnected", subject),
+                ));
+            }
+            return events;
+        }
+        events.extend(self.set(
+            AlertKind::Offline,
+            subject,
+            false,
+            format!("Miner {} is back online", subject),
+        ));
+
+        let window = Duration::from_secs(self.config.reject_window_secs);
+        let watch = self.workers.entry(subject.to_string()).or_default();
+        watch.last_share = Some(worker.last_subwork_time);
+        // Counters start over on every login
+        if matches!(watch.samples.back(), Some((_, a, r)) if worker.accept_index < *a || worker.invalid_index < *r)
+        {
+            watch.samples.clear();
+        }
+        watch
+            .samples
+            .push_back((now, worker.accept_index, worker.invalid_index));
+        while matches!(watch.samples.front(), Some((t, _, _)) if now.duration_since(*t) > window)
+        {
+            watch.samples.pop_front();
+        }
+
+        if self.config.reject_rate > 0.0 {
+            let (accepted, rejected) = match (watch.samples.front(), watch.samples.back()) {
+                (Some((_, a0, r0)), Some((_, a1, r1))) => (a1 - a0, r1 - r0),
+                _ => (0, 0),
+            };
+            let total = accepted + rejected;
+            let rate = if total == 0 {
+                0.0
+            } else {
+                rejected as f64 * 100.0 / total as f64
+            };
+
+            if total >= self.config.reject_min_shares && rate >= self.config.reject_rate {
+                events.extend(self.set(
+                    AlertKind::RejectRate,
+                    subject,
+                    true,
+                    format!(
+                        "Miner {} rejected {:.1}% of {} shares in {:?}",
+                        subject, rate, total, window
+                    ),
+                ));
+            } else if rate < self.config.reject_rate {
+                events.extend(self.set(
+                    AlertKind::RejectRate,
+                    subject,
+                    false,
+                    format!("Miner {} rejects are back to {:.1}%", subject, rate),
+                ));
+            }
+        }
+
+        events
+    }
+
+    // Miners that stopped finding shares
+    pub fn check_idle(&mut self, now: Instant) -> Vec<AlertEvent> {
+        if self.config.stale_secs == 0 {
+            return vec![];
+        }
+
+        let limit = Duration::from_secs(self.config.stale_secs);
+        let idle: Vec<(String, Duration)> = self
+            .workers
+            .iter()
+            .filter_map(|(subject, watch)| {
+                let last = watch.last_share?;
+                Some((subject.clone(), now.saturating_duration_since(last)))
+            })
+            .collect();
+
+        let mut events = vec![];
+        for (subject, idle) in idle {
+            let firing = idle > limit;
+            let message = if firing {
+                format!("Miner {} found no share for {}s", subject, idle.as_secs())
+            } else {
+                format!("Miner {} finds shares again", subject)
+            };
+            events.extend(self.set(AlertKind::Stale, &subject, firing, message));
+        }
+        events
+    }
+
+    pub fn check_pool(&mut self, pool: &str, available: bool, error: &str) -> Option<AlertEvent> {
+        if !self.config.pool_down {
+            return None;
+        }
+
+        let message = if available {
+            format!("Pool {} is reachable again", pool)
+        } else {
+            format!("Pool {} is unreachable: {}", pool, error)
+        };
+        self.set(AlertKind::PoolDown, pool, !available, message)
+    }
+}
+
+// Cheap handle used to feed miner reports to the alert loop
+#[derive(Debug, Clone)]
+pub struct Alerts {
+    tx: UnboundedSender<Worker>,
+}
+
+impl Alerts {
+    pub fn new(instance: &str, config: AlertConfig) -> (Self, AlertRunner) {
+        let (tx, rx) = unbounded_channel();
+        (
+            Self { tx },
+            AlertRunner {
+                alerter: Alerter::new(instance, config),
+                rx,
+            },
+        )
+    }
+
+    pub fn observe(&self, worker: &Worker) {
+        // Nobody listens when the runner is gone, which only happens on exit
+        let _ = self.tx.send(worker.clone());
+    }
+}
+
+pub struct AlertRunner {
+    alerter: Alerter,
+    rx: UnboundedReceiver<Worker>,
+}
+
+impl AlertRunner {
+    pub async fn run(mut self, pools: Vec<Arc<PoolManager>>) -> Result<()> {
+        let webhooks = self
+            .alerter
+            .config
+            .webhooks
+            .iter()
+            .map(|url| parse_webhook(url))
+            .collect::<Result<Vec<_>>>()?;
+
+        // One queue so a firing and its resolve arrive in order
+        let (events_tx, mut events_rx) = unbounded_channel::<AlertEvent>();
+        tokio::spawn(async move {
+            while let Some(event) = events_rx.recv().await {
+                deliver(&webhooks, &event).await;
+            }
+        });
+
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            let events = tokio::select! {
+                Some(worker) = self.rx.recv() => {
+                    self.alerter.observe_worker(&worker, Instant::now())
+                },
+                _ = interval.tick() => {
+                    let now = Instant::now();
+                    let mut events = self.alerter.check_idle(now);
+                    for manager in &pools {
+                        for (endpoint, health) in manager.endpoints().iter().zip(manager.health()) {
+                            let pool = format!("{} {}", manager.name(), endpoint.address);
+                            events.extend(self.alerter.check_pool(
+                                &pool,
+                                health.is_available(now),
+                                &health.last_error,
+                            ));
+                        }
+                    }
+                    events
+                },
+            };
+
+            for event in events {
+                let _ = events_tx.send(event);
+            }
+        }
+    }
+}
+
+async fn deliver(webhooks: &[Webhook], event: &AlertEvent) {
+    match event.status {
+        AlertStatus::Firing => tracing::warn!("Alert {:?}: {}", event.kind, event.message),
+        AlertStatus::Resolved => tracing::info!("Resolved {:?}: {}", event.kind, event.message),
+    }
+
+    let body = match serde_json::to_vec(event) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Alert cannot be encoded: {}", e);
+            return;
+        }
+    };
+
+    for webhook in webhooks {
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            match webhook.post(&body).await {
+                Ok(()) => break,
+                Err(e) => {
+                    tracing::warn!(
+                        "Webhook {} failed ({}/{}): {}",
+                        webhook.url,
+                        attempt,
+                        WEBHOOK_ATTEMPTS,
+                        e
+                    );
+                    if attempt < WEBHOOK_ATTEMPTS {
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+pub fn parse_webhook(url: &str) -> Result<Webhook> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        bail!("Webhook {} must start with http:// or https://", url);
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse()?),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        bail!("Webhook {} has no host", url);
+    }
+
+    Ok(Webhook {
+        url: url.to_string(),
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+impl Webhook {
+    pub async fn post(&self, body: &[u8]) -> Result<()> {
+        let stream = timeout(
+            WEBHOOK_TIMEOUT,
+            TcpStream::connect((self.host.as_str(), self.port)),
+        )
+        .await??;
+
+        let exchange = async {
+            if self.tls {
+                let tls = PoolTls::default();
+                let stream = tls
+                    .connector()?
+                    .connect(tls.server_name(&self.host)?, stream)
+                    .await?;
+                self.exchange(stream, body).await
+            } else {
+                self.exchange(stream, body).await
+            }
+        };
+        match timeout(WEBHOOK_TIMEOUT, exchange).await {
+            Ok(res) => res,
+            Err(_) => bail!("no answer within {:?}", WEBHOOK_TIMEOUT),
+        }
+    }
+
+    async fn exchange<S>(&self, stream: S, body: &[u8]) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin {
+        let (r, mut w) = tokio::io::split(stream);
+        let host = if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: mining_proxy\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            host,
+            body.len()
+        );
+        w.write_all(head.as_bytes()).await?;
+        w.write_all(body).await?;
+        w.flush().await?;
+
+        let mut status = String::new();
+        BufReader::new(r).read_line(&mut status).await?;
+        match status.split_whitespace().nth(1).map(|code| code.parse::<u16>()) {
+            Some(Ok(code)) if (200..300).contains(&code) => Ok(()),
+            _ => bail!("answered {}", status.trim()),
+        }
+    }
+}
+
+#[test]
+fn test_alerts() {
+    let config = AlertConfig {
+        reject_min_shares: 10,
+        ..Default::default()
+    };
+    let mut alerter = Alerter::new("eth", config);
+    let now = Instant::now();
+
+    let mut worker = Worker::new("0xabc.rig1".into(), "rig1".into(), "0xabc".into(), true);
+    worker.accept_index = 100;
+    assert!(alerter.observe_worker(&worker, now).is_empty());
+
+    // 5 of 20 shares rejected is 25%
+    worker.accept_index = 115;
+    worker.invalid_index = 5;
+    let events = alerter.observe_worker(&worker, now + Duration::from_secs(60));
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].kind, events[0].status), (AlertKind::RejectRate, AlertStatus::Firing));
+    // Deduplicated
+    assert!(alerter.observe_worker(&worker, now + Duration::from_secs(90)).is_empty());
+
+    // The window moved past the rejects
+    worker.accept_index = 130;
+    let events = alerter.observe_worker(&worker, now + Duration::from_secs(700));
+    assert_eq!(events[0].status, AlertStatus::Resolved);
+
+    let events = alerter.check_idle(worker.last_subwork_time + Duration::from_secs(601));
+    assert_eq!(events[0].kind, AlertKind::Stale);
+
+    worker.online = false;
+    let events = alerter.observe_worker(&worker, now);
+    assert_eq!((events[0].kind, events[0].status), (AlertKind::Offline, AlertStatus::Firing));
+    worker.online = true;
+    let events = alerter.observe_worker(&worker, now);
+    assert_eq!((events[0].kind, events[0].status), (AlertKind::Offline, AlertStatus::Resolved));
+
+    assert!(alerter.check_pool("proxy pool a:1", false, "refused").is_some());
+    assert!(alerter.check_pool("proxy pool a:1", false, "refused").is_none());
+    assert!(alerter.check_pool("proxy pool a:1", true, "").is_some());
+
+    assert_eq!(parse_webhook("https://hooks.example.com/a/b").unwrap().port, 443);
+    assert_eq!(parse_webhook("http://[::1]:8080").unwrap().host, "::1");
+    assert!(parse_webhook("ftp://example.com").is_err());
+
+    // A local stand-in for the receiver
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (r, mut w) = tokio::io::split(stream);
+            let mut lines = BufReader::new(r).lines();
+            let mut length = 0;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    length = len.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            tokio::io::AsyncReadExt::read_exact(lines.get_mut(), &mut body).await.unwrap();
+            w.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            serde_json::from_slice::<AlertEvent>(&body).unwrap()
+        });
+
+        let webhook = parse_webhook(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        let event = alerter.check_pool("proxy pool b:1", false, "refused").unwrap();
+        webhook.post(&serde_json::to_vec(&event).unwrap()).await.unwrap();
+        assert_eq!(server.await.unwrap(), event);
+    });
+}
@@ -6,7 +6,10 @@ use tokio::{
 };
 use tracing::info;
 
-use crate::{state::Worker, util::config::Settings};
+use crate::{
+    state::Worker,
+    util::{aead::AeadStream, config::Settings},
+};
 
 use super::*;
 pub async fn accept_en_tcp(proxy: Arc<Proxy>) -> Result<()> {
@@ -32,12 +35,17 @@ pub async fn accept_en_tcp(proxy: Arc<Proxy>) -> Result<()> {
     tracing::info!("Local TCP encryption protocol port {} started successfully!!!", &address);
     loop {
         let (stream, addr) = listener.accept().await?;
+        if proxy.is_draining() {
+            debug!("IP: {} refused while draining", addr);
+            continue;
+        }
 
         let p = Arc::clone(&proxy);
 
         tokio::spawn(async move {
             // Miner Status Management
             let mut worker: Worker = Worker::default();
+            worker.ip = addr.ip().to_string();
             let worker_tx = p.worker_tx.clone();
             match transfer(p, &mut worker, stream).await {
                 Ok(_) => {
@@ -66,32 +74,13 @@ pub async fn accept_en_tcp(proxy: Arc<Proxy>) -> Result<()> {
 async fn transfer(
     proxy: Arc<Proxy>, worker: &mut Worker, tcp_stream: TcpStream,
 ) -> Result<()> {
-    let (worker_r, worker_w) = split(tcp_stream);
+    // Read per connection, the key can be reloaded
+    let key = proxy.config.read().await.encrypt_key.clone();
+    let stream = AeadStream::accept(tcp_stream, &key).await?;
+    let (worker_r, worker_w) = split(stream);
     let worker_r = BufReader::new(worker_r);
 
-    let mut pool_address: Vec<String> = Vec::new();
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
-    }
-
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("Not matched to a mining pool or neither can be linked. Please modify and try again");
-            }
-        };
 
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        true,
-    )
-    .await
+    handle_tcp_random(worker, worker_r, worker_w, proxy, true).await
     //handle_tcp_random(worker, worker_r, worker_w, &pools, proxy, true).await
 }
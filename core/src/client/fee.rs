@@ -1,17 +1,24 @@
-use std::sync::{Arc, RwLockReadGuard};
+use std::{
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 
 use tokio::{
     io::{AsyncRead, AsyncWrite, BufReader, Lines, WriteHalf},
     select,
-    sync::mpsc::Receiver,
-    sync::RwLockWriteGuard,
+    sync::{mpsc::Receiver, watch},
 };
 
 use crate::{
-    protocol::ethjson::EthClientObject,
-    proxy::{Job, Proxy},
+    ledger::{Destination, FeeShare, Ledger, ShareRecord},
+    protocol::{
+        bridge::{EthStratumUpstream, UpstreamMessage},
+        ethjson::EthClientObject,
+        rpc::eth::RejectReason,
+    },
+    proxy::{job_store::JobStore, FeeRounds, Job, Proxy},
     util::config::Settings,
 };
 
@@ -19,49 +26,35 @@ use crate::{
     client::lines_unwrap,
     protocol::ethjson::{
         EthClientRootObject, EthClientWorkerObject, EthServer,
-        EthServerRootObject,
+        EthServerResponse, EthServerRootObject,
     },
 };
 
-use super::write_to_socket_byte;
+use super::{
+    pending::{PendingRequests, SUBMIT_TIMEOUT},
+    pool_manager::{PoolEndpoint, PoolLines, PoolManager, PoolWriter},
+    dev_pool_login, develop_worker, proxy_pool_login, write_to_socket,
+    write_to_socket_byte,
+};
 
 use tracing::{debug, info};
 
+// Developer fee loop. Like `proxy_fee`, on the pools of `develop_pool`.
 pub async fn develop_fee_ssl(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<
-            tokio::io::ReadHalf<
-                tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    mut w: tokio::io::WriteHalf<
-        tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    >,
-    worker_name: String, proxy: Arc<Proxy>,
+    mut rx: Receiver<FeeShare>, pools: Arc<PoolManager>, worker_name: String,
+    proxy: Arc<Proxy>, stratum: bool,
 ) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
+    let rounds = proxy.fee_rounds(stratum);
+    rounds.wanted().await;
+    let mut upstream = FeeUpstream::new(rounds, Destination::Develop, stratum, &worker_name, &proxy.ledger);
+    upstream.full_name = develop_worker(&worker_name);
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+    let mut preferred = pools.subscribe();
+    let (mut proxy_lines, mut w, mut endpoint) =
+        dev_pool_relogin(&pools, &worker_name, stratum).await;
 
     let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
     tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
 
     loop {
         select! {
@@ -69,14 +62,12 @@ pub async fn develop_fee_ssl(
                 let buffer = match lines_unwrap(res,&worker_name,"mining pool").await {
                     Ok(buf) => buf,
                     Err(_) => {
-
-                        let (dev_lines, dev_w) =
-                            crate::client::dev_pool_ssl_login(worker_name.clone())
-                                .await?;
-
+                        let (new_lines, new_w, new_endpoint) = dev_pool_relogin(&pools, &worker_name, stratum).await;
                         //Add 2 values at the same time
-                        w = dev_w;
-                        proxy_lines = dev_lines;
+                        w = new_w;
+                        proxy_lines = new_lines;
+                        endpoint = new_endpoint;
+                        upstream.reconnected();
                         info!(worker_name = ?worker_name,"Re-login successful!!");
 
                         continue;
@@ -84,69 +75,54 @@ pub async fn develop_fee_ssl(
                 };
                 #[cfg(debug_assertions)]
                 debug!("1 : mining pool -> mining machine {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-                    {
-                    let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-                    j.push_back(job_res)
-                    }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"Thread gets operation result {:?}",result_rpc.result);
-                    }
+                upstream.handle_line(&buffer, &worker_name);
+            },
+            Ok(()) = preferred.changed() => {
+                let best = *preferred.borrow();
+                if best < endpoint.priority {
+                    info!(worker_name = ?worker_name,"Fail back from {} to a pool with priority {}",endpoint.address,best);
+                    let (new_lines, new_w, new_endpoint) = dev_pool_relogin(&pools, &worker_name, stratum).await;
+                    w = new_w;
+                    proxy_lines = new_lines;
+                    endpoint = new_endpoint;
+                    upstream.reconnected();
                 }
             },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-                json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
+            Some(share) = rx.recv() => {
+                upstream.submit(&mut w, share, &worker_name).await?;
             },
             () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
+                upstream.poll(&mut w, &worker_name).await?;
                 sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
             },
         }
     }
-
-    Ok(())
 }
 
-pub async fn fee_ssl(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<
-            tokio::io::ReadHalf<
-                tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    mut w: tokio::io::WriteHalf<
-        tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    >,
-    worker_name: String, proxy: Arc<Proxy>,
+// Fee pool loop. The upstream comes from `proxy.fee_pool` and moves back to
+// a better pool as soon as one recovers.
+pub async fn proxy_fee(
+    mut rx: Receiver<FeeShare>, worker_name: String, proxy: Arc<Proxy>,
+    stratum: bool,
 ) -> Result<()> {
+    let rounds = proxy.fee_rounds(stratum);
+    rounds.wanted().await;
     let mut config: Settings;
     {
         let rconfig = proxy.config.read().await;
         config = rconfig.clone();
     }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
+    let mut upstream = FeeUpstream::new(rounds, Destination::Fee, stratum, &worker_name, &proxy.ledger);
+    upstream.full_name = format!("{}.{}", config.share_wallet, config.get_share_name()?);
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+    let mut reloads = proxy.subscribe_reloads();
+    let pools = proxy.fee_pool.clone();
+    let mut preferred = pools.subscribe();
+    let (mut proxy_lines, mut w, mut endpoint) =
+        proxy_pool_relogin(&config, &pools, &worker_name, stratum).await;
 
     let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
     tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
 
     loop {
         select! {
@@ -154,13 +130,12 @@ pub async fn fee_ssl(
                 let buffer = match lines_unwrap(res,&worker_name,"mining pool").await {
                     Ok(buf) => buf,
                     Err(_) => {
-                        //return Err(anyhow!("Pumping absenteeism dropped the line a"));
-                        // info!(worker_name =
-            //       ?worker_name,"quit. Log back into the pool!!");
-             let (new_lines, dev_w) = crate::client::proxy_pool_login_with_ssl(&config,config.share_name.clone()).await?;
+                        let (new_lines, new_w, new_endpoint) = proxy_pool_relogin(&config, &pools, &worker_name, stratum).await;
                         //Add 2 values at the same time
-                        w = dev_w;
+                        w = new_w;
                         proxy_lines = new_lines;
+                        endpoint = new_endpoint;
+                        upstream.reconnected();
                         info!(worker_name = ?worker_name,"Re-login successful!!");
 
                         continue;
@@ -168,107 +143,214 @@ pub async fn fee_ssl(
                 };
                 #[cfg(debug_assertions)]
                 debug!("1 : mining pool -> mining machine {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"Thread gets operation result {:?}",result_rpc.result);
-                    }
+                upstream.handle_line(&buffer, &worker_name);
+            },
+            Ok(()) = preferred.changed() => {
+                let best = *preferred.borrow();
+                if best < endpoint.priority {
+                    info!(worker_name = ?worker_name,"Fail back from {} to a pool with priority {}",endpoint.address,best);
+                    let (new_lines, new_w, new_endpoint) = proxy_pool_relogin(&config, &pools, &worker_name, stratum).await;
+                    w = new_w;
+                    proxy_lines = new_lines;
+                    endpoint = new_endpoint;
+                    upstream.reconnected();
                 }
             },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
+            // A new fee wallet logs in again, new fee pools are used from
+            // the next reconnect
+            Ok(()) = reloads.changed() => {
+                let reloaded = proxy.config.read().await.clone();
+                let relogin = (&reloaded.share_wallet, &reloaded.share_name) != (&config.share_wallet, &config.share_name);
+                config = reloaded;
+                if relogin {
+                    info!(worker_name = ?worker_name,"Fee wallet changed, logging in as {}",config.share_wallet);
+                    upstream.submit_rpc.worker = config.share_name.clone();
+                    upstream.full_name = format!("{}.{}", config.share_wallet, config.get_share_name()?);
+                    let (new_lines, new_w, new_endpoint) = proxy_pool_relogin(&config, &pools, &worker_name, stratum).await;
+                    w = new_w;
+                    proxy_lines = new_lines;
+                    endpoint = new_endpoint;
+                    upstream.reconnected();
+                }
+            },
+            Some(share) = rx.recv() => {
+                upstream.submit(&mut w, share, &worker_name).await?;
             },
             () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
+                upstream.poll(&mut w, &worker_name).await?;
                 sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
             },
         }
     }
-
-    Ok(())
 }
-pub async fn fee_tcp(
-    mut rx: Receiver<Vec<String>>, job: Job,
-    mut proxy_lines: Lines<
-        BufReader<tokio::io::ReadHalf<tokio::net::TcpStream>>,
-    >,
-    mut w: tokio::io::WriteHalf<tokio::net::TcpStream>, worker_name: String,
-    proxy: Arc<Proxy>,
-) -> Result<()> {
-    let mut config: Settings;
-    {
-        let rconfig = proxy.config.read().await;
-        config = rconfig.clone();
-    }
-    let mut get_work = EthClientRootObject {
-        id: 6,
-        method: "eth_getWork".into(),
-        params: vec![],
-    };
 
-    let mut json_rpc = EthClientWorkerObject {
-        id: 40,
-        method: "eth_submitWork".into(),
-        params: vec![],
-        worker: worker_name.clone(),
-    };
+// The part of a fee loop that depends on the dialect of its pool. ETHProxy
+// pools are polled with eth_getWork, EthereumStratum/1.0.0 pools push their
+// jobs and assign the extranonce the miners must use on them.
+struct FeeUpstream<'a> {
+    stratum: Option<EthStratumUpstream>,
+    job: &'a JobStore,
+    extranonce: &'a watch::Sender<String>,
+    ledger: &'a Ledger,
+    // Shares waiting for the pool verdict
+    pending: PendingRequests<ShareRecord>,
+    submit_rpc: EthClientWorkerObject,
+    // `wallet.worker` of mining.submit
+    full_name: String,
+}
 
-    let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(20));
-    tokio::pin!(sleep);
-    let mut share_job_idx: u64 = 0;
+impl<'a> FeeUpstream<'a> {
+    fn new(
+        rounds: &'a FeeRounds, destination: Destination, stratum: bool, worker_name: &str,
+        ledger: &'a Ledger,
+    ) -> Self {
+        Self {
+            stratum: if stratum { Some(EthStratumUpstream::new()) } else { None },
+            job: rounds.job(destination),
+            extranonce: match destination {
+                Destination::Develop => &rounds.develop_extranonce,
+                _ => &rounds.fee_extranonce,
+            },
+            ledger,
+            pending: PendingRequests::new(),
+            submit_rpc: EthClientWorkerObject {
+                id: 40,
+                method: "eth_submitWork".into(),
+                params: vec![],
+                worker: worker_name.to_string(),
+            },
+            full_name: worker_name.to_string(),
+        }
+    }
 
-    loop {
-        select! {
-            res = proxy_lines.next_line() => {
-                let buffer = match lines_unwrap(res,&worker_name,"mining pool").await {
-                    Ok(buf) => buf,
-                    Err(_) => {
+    // Logged in on a new connection. Shares sent on the old one are lost.
+    fn reconnected(&mut self) {
+        expire_shares(self.ledger, &mut self.pending, Duration::from_secs(0));
+        if self.stratum.is_some() {
+            self.stratum = Some(EthStratumUpstream::new());
+        }
+    }
 
-             let (new_lines, dev_w) = crate::client::proxy_pool_login(&config,config.share_name.clone()).await?;
-                        //Add 2 values at the same time
-                        w = dev_w;
-                        proxy_lines = new_lines;
-                        info!(worker_name = ?worker_name,"Re-login successful!!");
+    fn handle_line(&mut self, buffer: &str, worker_name: &str) {
+        if let Some(up) = self.stratum.as_mut() {
+            match up.handle_message(buffer) {
+                UpstreamMessage::Job(job) => {
+                    self.job.push(job);
+                }
+                UpstreamMessage::Subscribed | UpstreamMessage::Extranonce(_) => {
+                    self.extranonce.send_replace(up.extranonce.clone());
+                }
+                UpstreamMessage::Difficulty => {}
+                UpstreamMessage::Other => self.handle_answer(buffer, worker_name),
+            }
+        } else if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(buffer) {
+            if let Some(job_res) = job_rpc.get_job_result() {
+                self.job.push(job_res);
+            }
+        } else {
+            self.handle_answer(buffer, worker_name);
+        }
+    }
 
-                        continue;
-                    },
-                };
-                #[cfg(debug_assertions)]
-                debug!("1 : mining pool -> mining machine {} #{:?}",worker_name, buffer);
-                if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
-                    let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
+    fn handle_answer(&mut self, buffer: &str, worker_name: &str) {
+        if let Ok(result_rpc) = serde_json::from_str::<EthServerResponse>(buffer) {
+            if !result_rpc.is_accepted() {
+                tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"Thread gets operation result {:?}",result_rpc.result);
             }
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
-                    if result_rpc.result == false {
-                        tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"Thread gets operation result {:?}",result_rpc.result);
-                    }
+            record_verdict(self.ledger, &mut self.pending, &result_rpc);
+        }
+    }
+
+    async fn submit(
+        &mut self, w: &mut PoolWriter, share: FeeShare, worker_name: &String,
+    ) -> Result<()> {
+        match self.stratum.as_ref() {
+            Some(up) => match up.work_to_submit(&share.params, &self.full_name) {
+                Ok(mut submit) => {
+                    submit.id = self.pending.insert(share.record);
+                    write_to_socket(w, &submit, worker_name).await?;
                 }
+                // Found under an extranonce the pool has replaced since
+                Err(reason) => self.ledger.record(share.record.verdict(false, Some(reason))),
             },
-            Some(params) = rx.recv() => {
-                share_job_idx+=1;
-                json_rpc.id = share_job_idx;
-        json_rpc.params = params;
-                write_to_socket_byte(&mut w, json_rpc.to_vec()?, &worker_name).await?;
-            },
-            () = &mut sleep  => {
-                write_to_socket_byte(&mut w, get_work.to_vec()?, &worker_name).await?;
-                sleep.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(10));
-            },
+            None => {
+                self.submit_rpc.id = self.pending.insert(share.record);
+                self.submit_rpc.params = share.params;
+                write_to_socket_byte(w, self.submit_rpc.to_vec()?, worker_name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Every 10 seconds
+    async fn poll(&mut self, w: &mut PoolWriter, worker_name: &String) -> Result<()> {
+        expire_shares(self.ledger, &mut self.pending, SUBMIT_TIMEOUT);
+        if self.stratum.is_none() {
+            let mut get_work = EthClientRootObject {
+                id: 6,
+                method: "eth_getWork".into(),
+                params: vec![],
+            };
+            write_to_socket_byte(w, get_work.to_vec()?, worker_name).await?;
         }
+        Ok(())
     }
+}
+
+// Write the verdict of the fee pool for a forwarded share to the ledger.
+fn record_verdict(
+    ledger: &Ledger, pending: &mut PendingRequests<ShareRecord>,
+    res: &EthServerResponse,
+) {
+    if let Some(record) = pending.take(res.id) {
+        if res.is_accepted() {
+            ledger.record(record.verdict(true, None));
+        } else {
+            let reason = RejectReason::from_error(&res.error);
+            ledger.record(record.verdict(false, Some(reason)));
+        }
+    }
+}
 
-    Ok(())
+// Shares the pool did not answer in time, or sent to a pool that is gone,
+// count as rejected.
+fn expire_shares(
+    ledger: &Ledger, pending: &mut PendingRequests<ShareRecord>,
+    timeout: Duration,
+) {
+    for record in pending.expire(timeout) {
+        ledger.record(record.verdict(false, Some(RejectReason::Other)));
+    }
+}
+
+// Log in to the fee pool, waiting until one of them is reachable.
+async fn proxy_pool_relogin(
+    config: &Settings, pools: &PoolManager, worker_name: &str, stratum: bool,
+) -> (PoolLines, PoolWriter, PoolEndpoint) {
+    loop {
+        match proxy_pool_login(config, pools, stratum).await {
+            Ok(res) => return res,
+            Err(e) => {
+                tracing::error!(worker_name = ?worker_name,"Fee pool login failed: {}",e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+// Log in to the developer pool, waiting until one of them is reachable.
+async fn dev_pool_relogin(
+    pools: &PoolManager, worker_name: &str, stratum: bool,
+) -> (PoolLines, PoolWriter, PoolEndpoint) {
+    loop {
+        match dev_pool_login(pools, worker_name, stratum).await {
+            Ok(res) => return res,
+            Err(e) => {
+                tracing::error!(worker_name = ?worker_name,"Developer pool login failed: {}",e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
 }
 
 pub async fn fee<W: 'static, R: 'static>(
@@ -358,10 +440,7 @@ where
                 debug!("1 : mining pool -> mining machine {} #{:?}",worker_name, buffer);
                 if let Ok(job_rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
                     let job_res = job_rpc.get_job_result().unwrap();
-            {
-            let mut j = RwLockWriteGuard::map(job.write().await, |f| f);
-            j.push_back(job_res)
-            }
+            job.push(job_res);
                 } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
                     if result_rpc.result == false {
                         tracing::debug!(worker_name = ?worker_name,rpc = ?buffer,"Thread gets operation result {:?}",result_rpc.result);
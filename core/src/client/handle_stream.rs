@@ -5,40 +5,65 @@ use tracing::{debug, info};
 use tokio::{
     io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf},
     select,
-    sync::RwLockReadGuard,
+    sync::{mpsc::error::TrySendError, RwLockReadGuard},
     time,
 };
 
 use crate::{
-    client::*,
+    client::{
+        pending::{PendingRequests, SUBMIT_TIMEOUT},
+        pool_manager::{
+            BoxPoolStream, PoolEndpoint, PoolLines, PoolManager, PoolWriter,
+        },
+        *,
+    },
     protocol::{
+        bridge::{
+            EthStratumUpstream, UpstreamMessage, POOL_PROTOCOL_AUTO,
+            POOL_PROTOCOL_ETHSTRATUM,
+        },
+        eth_stratum::{EthStratumResult, EthStratumSession},
         ethjson::{EthServerRoot, EthServerRootObject},
-        CLIENT_LOGIN, CLIENT_SUBMITWORK,
+        rpc::eth::{handle_error_for_worker, RejectReason},
+        CLIENT_LOGIN, PROTOCOL,
+    },
+    ledger::{Destination, FeeShare, ShareRecord},
+    proxy::{
+        job_store::{job_height, JobStore},
+        FeeRounds,
     },
     state::Worker,
-    util::{config::Settings, is_fee_random},
+    util::{
+        config::Settings,
+        fee_scheduler::{new_fee_scheduler, FeeScheduler},
+        target_to_diff,
+    },
 };
 
 use crate::{
     protocol::ethjson::{
-        login, new_eth_get_work, new_eth_submit_hashrate, new_eth_submit_work,
-        EthServer, EthServerRootObjectJsonRpc,
+        login, new_eth_get_work, new_eth_submit_hashrate,
+        EthServerResponse, EthServerRootObjectJsonRpc,
     },
     DEVELOP_FEE,
 };
 
-pub async fn handle_stream<R, W, PR, PW>(
+const REDIAL_ATTEMPTS: u32 = 3;
+const REDIAL_DELAY: time::Duration = time::Duration::from_secs(2);
+// Sessions fail back within this long of the preferred pool recovering, not
+// all at once
+const FAILBACK_JITTER_MS: u64 = 30_000;
+
+pub async fn handle_stream<R, W>(
     worker: &mut Worker,
     worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
     mut worker_w: WriteHalf<W>,
-    pool_r: tokio::io::BufReader<tokio::io::ReadHalf<PR>>,
-    mut pool_w: WriteHalf<PW>, proxy: Arc<Proxy>, is_encrypted: bool,
+    pool: BoxPoolStream, endpoint: PoolEndpoint, proxy: Arc<Proxy>,
+    is_encrypted: bool,
 ) -> Result<()>
 where
     R: AsyncRead,
     W: AsyncWrite,
-    PR: AsyncRead,
-    PW: AsyncWrite,
 {
     let mut worker_name: String = String::new();
     let mut eth_server_result = EthServerRoot {
@@ -53,53 +78,66 @@ where
         result: vec![],
     };
 
-    let mut fee_job: Vec<String> = Vec::new();
-    let mut dev_fee_job: Vec<String> = Vec::new();
+    // Shares forwarded to the pool waiting for its answer
+    let mut pending: PendingRequests<MinerShare> = PendingRequests::new();
+    let mut expire = time::interval(time::Duration::from_secs(5));
+
+    // Set once the miner subscribes with EthereumStratum/1.0.0
+    let mut stratum: Option<EthStratumSession> = None;
 
     //last sent rpc_id
     let mut rpc_id = 0;
 
-    let mut worker_lines = worker_r.lines();
-    //let mut total_send_idx = 0;
-    // Packaging is in packet format.
-    let mut pool_lines = pool_r.lines();
-
-    //let mut send_job = Vec::new();
+    // Login sent to the pool. Replayed when the pool has to be dialed again.
+    let mut saved_login: Option<Vec<u8>> = None;
+    // Pools of the session. A routing rule can replace them at login.
+    let mut pools = proxy.pool.clone();
+    // Lowers when a better pool is back, the session then fails back to it
+    let mut preferred = pools.subscribe();
 
-    // if is_encrypted {
-    //     worker_lines = worker_r.split(SPLIT);
-    // } else {
-    //     worker_lines = worker_r.split(b'\n');
-    // }
+    let mut worker_lines = worker_r.lines();
+    let (pool_r, mut pool_w) = tokio::io::split(pool);
+    let mut pool_lines = tokio::io::BufReader::new(pool_r).lines();
+    // Priority of the pool the session is on
+    let mut priority = endpoint.priority;
 
-    use rand::SeedableRng;	
+    use rand::SeedableRng;
     let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
     let send_time = rand::Rng::gen_range(&mut rng, 1..360) as u64;
     let workers_queue = proxy.worker_tx.clone();
     let sleep = time::sleep(tokio::time::Duration::from_secs(send_time));
     tokio::pin!(sleep);
+    // Armed when a better pool is back
+    let failback = time::sleep(time::Duration::ZERO);
+    tokio::pin!(failback);
+    let mut failback_due = false;
 
-    // let mut chan = proxy.chan.subscribe();
-    // let mut dev_chan = proxy.dev_chan.subscribe();
-    let tx = proxy.tx.clone();
-    let dev_tx = proxy.dev_tx.clone();
-
-    // Current job height.
-    let _job_hight = 0;
-    // owed a few jobs
-    // let mut dev_fee_idx = 0;
-    // let mut fee_idx = 0;
-    // let mut idx = 0;
-
-    let mut wait_job: VecDeque<Vec<String>> = VecDeque::new();
-    let mut wait_dev_job: VecDeque<Vec<String>> = VecDeque::new();
-
-    let config: Settings;
+    let mut config: Settings;
     {
         let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
         config = rconfig.clone();
     }
 
+    let mut fee_jobs = FeeJobs::new(&config);
+    let mut reloads = proxy.subscribe_reloads();
+    let mut kicks = proxy.kick.subscribe();
+
+    // Set when the pool speaks EthereumStratum/1.0.0 instead of ETHProxy. With
+    // AUTO that is decided by the subscribe of the miner.
+    let mut upstream = if config.pool_protocol == POOL_PROTOCOL_ETHSTRATUM {
+        write_to_socket(&mut pool_w, &EthStratumUpstream::subscribe_rpc(), &worker_name).await?;
+        Some(EthStratumUpstream::new())
+    } else {
+        None
+    };
+    // Fee and developer rounds go to pools of the same dialect
+    let mut fee_updates = proxy.fee_rounds(upstream.is_some()).fee_job.subscribe();
+    let mut develop_updates = proxy.fee_rounds(upstream.is_some()).develop_job.subscribe();
+    // mining.subscribe of the miner waiting for the extranonce of the pool
+    let mut pending_subscribe: Option<u64> = None;
+    // Same for the eth_submitLogin of an ETHProxy miner
+    let mut pending_login: Option<u64> = None;
+
     loop {
         select! {
             res = worker_lines.next_line() => {
@@ -111,39 +149,47 @@ where
                         let res = match json_rpc.get_method().as_str() {
                             "eth_submitLogin" => {
                                 eth_server_result.id = rpc_id;
-                                login(worker,&mut pool_w,&mut json_rpc,&mut worker_name,&config).await?;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                                worker.set_protocol(PROTOCOL::ETH);
+                                if let Some((route_pools, lines, w, route_priority)) = route_login(worker,&proxy,json_rpc.as_ref()).await? {
+                                    pools = route_pools;
+                                    preferred = pools.subscribe();
+                                    pool_lines = lines;
+                                    pool_w = w;
+                                    priority = route_priority;
+                                    job_rpc.result.clear();
+                                    if upstream.is_some() {
+                                        upstream = Some(EthStratumUpstream::new());
+                                        write_to_socket(&mut pool_w, &EthStratumUpstream::subscribe_rpc(), &worker_name).await?;
+                                    }
+                                }
+                                proxy.fee_rounds(upstream.is_some()).want();
+                                if upstream.is_some() {
+                                    let mut authorize = match EthStratumUpstream::authorize_rpc(json_rpc.as_ref()) {
+                                        Some(authorize) => authorize,
+                                        None => bail!("Error requesting login. Possible brute force attack"),
+                                    };
+                                    login(worker,&mut pool_w,&mut authorize,&mut worker_name,&config).await?;
+                                    saved_login = Some(authorize.to_vec()?);
+                                } else {
+                                    login(worker,&mut pool_w,&mut json_rpc,&mut worker_name,&config).await?;
+                                    saved_login = Some(json_rpc.to_vec()?);
+                                }
+                                match upstream.as_ref() {
+                                    Some(up) if stratum.is_none() && !up.subscribed => pending_login = Some(rpc_id),
+                                    Some(up) if stratum.is_none() => answer_ethproxy_login(is_encrypted,&mut worker_w,rpc_id,&up.extranonce,&worker_name).await?,
+                                    _ => write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?,
+                                }
                                 Ok(())
                             },
                             "eth_submitWork" => {
                                 eth_server_result.id = rpc_id;
-                                if let Some(job_id) = json_rpc.get_job_id() {
+                                if json_rpc.get_job_id().is_some() {
                                     #[cfg(debug_assertions)]
                                     debug!("0 : Received submitted workload {} #{:?}",worker_name, json_rpc);
                                     let mut json_rpc = Box::new(EthClientWorkerObject{ id: json_rpc.get_id(), method: json_rpc.get_method(), params: json_rpc.get_params(), worker: worker.worker_name.clone()});
-                    if dev_fee_job.contains(&job_id) {
-//                    debug!("0 : Received developer workload {} #{:?}",worker_name, json_rpc);
-                                        match dev_tx.try_send(json_rpc.get_params()){
-                        Ok(_) => {},
-                        Err(e)=> {
-                        debug!("The developer channel is full.{}",e);
-                        },
-                    }
-                                    } else if fee_job.contains(&job_id) {
-                                        worker.fee_share_index_add();
-                                        worker.fee_share_accept();
-                    match tx.try_send(json_rpc.get_params()) {
-                        Ok(()) => {},
-                        Err(e) => {
-                        debug!("The transit channel is full.{}",e);
-                        },
-                    }
-                                    } else {
-                                        worker.share_index_add();
-                                        new_eth_submit_work(worker,&mut pool_w,&mut worker_w,&mut json_rpc,&worker_name,&config).await?;
+                                    if let Some(accepted) = submit_work(worker,&mut pool_w,&mut json_rpc,&mut fee_jobs,&proxy,upstream.as_ref(),&mut pending).await? {
+                                        write_share_result(is_encrypted,&mut worker_w,stratum.is_some(),rpc_id,accepted,&worker_name).await?;
                                     }
-
-                                    write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
                                     Ok(())
                                 } else {
                                     pool_w.shutdown().await?;
@@ -156,26 +202,112 @@ where
                                 let mut hash = json_rpc.get_submit_hashrate();
                                 hash = (hash as f64 * (config.hash_rate as f32 / 100.0) as f64) as u64;
                                 json_rpc.set_submit_hashrate(format!("0x{:x}", hash));
-                                new_eth_submit_hashrate(worker,&mut pool_w,&mut json_rpc,&worker_name).await?;
+                                if upstream.is_some() {
+                                    // EthereumStratum/1.0.0 has no hashrate report
+                                    worker.new_submit_hashrate(&mut json_rpc);
+                                } else {
+                                    new_eth_submit_hashrate(worker,&mut pool_w,&mut json_rpc,&worker_name).await?;
+                                }
                                 write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
                                 Ok(())
                             },
                             "eth_getWork" => {
-                                new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                // eth_server_result.id = rpc_id;
-                                // write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                                if upstream.is_some() {
+                                    // The pool pushes jobs. Answer with the last one sent.
+                                    if !job_rpc.result.is_empty() {
+                                        let job = EthServerRootObjectJsonRpc{ id: rpc_id, jsonrpc: "2.0".into(), result: job_rpc.result.clone()};
+                                        write_rpc(is_encrypted,&mut worker_w,&job,&worker_name).await?;
+                                    }
+                                } else {
+                                    new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
+                                }
                                 Ok(())
                             },
-                            "mining.subscribe" =>{ //GMiner
-                                new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
-                                eth_server_result.id = rpc_id;
-                                write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                            "mining.subscribe" => {
+                                if json_rpc.is_protocol_eth_statum() {
+                                    // Nothing went to the pool yet, it can still be spoken to in EthereumStratum
+                                    if upstream.is_none() && config.pool_protocol == POOL_PROTOCOL_AUTO && saved_login.is_none() {
+                                        write_to_socket(&mut pool_w, &EthStratumUpstream::subscribe_rpc(), &worker_name).await?;
+                                        upstream = Some(EthStratumUpstream::new());
+                                        fee_updates = proxy.stratum_fee.fee_job.subscribe();
+                                        develop_updates = proxy.stratum_fee.develop_job.subscribe();
+                                    }
+                                    // Its shares have no mix hash, which an ETHProxy pool needs
+                                    if upstream.is_none() {
+                                        let refused = EthStratumResult{ id: rpc_id, result: false, error: serde_json::json!([20, "EthereumStratum/1.0.0 needs pool_protocol AUTO or ETHEREUMSTRATUM", null])};
+                                        write_rpc(is_encrypted,&mut worker_w,&refused,&worker_name).await?;
+                                        bail!("EthereumStratum/1.0.0 miner on an ETHProxy pool");
+                                    }
+                                    proxy.stratum_fee.want();
+                                    let mut session = EthStratumSession::new();
+                                    worker.set_protocol(PROTOCOL::NICEHASHSTRATUM);
+                                    match upstream.as_ref() {
+                                        // Nonces have to start with the extranonce of the pool
+                                        Some(up) if up.subscribed => {
+                                            session.extranonce = up.extranonce.clone();
+                                            write_rpc(is_encrypted,&mut worker_w,&session.subscribe_result(rpc_id),&worker_name).await?;
+                                        },
+                                        _ => pending_subscribe = Some(rpc_id),
+                                    }
+                                    stratum = Some(session);
+                                } else { //GMiner
+                                    if upstream.is_none() {
+                                        new_eth_get_work(&mut pool_w,&mut json_rpc,&worker_name).await?;
+                                    }
+                                    eth_server_result.id = rpc_id;
+                                    write_rpc(is_encrypted,&mut worker_w,&eth_server_result,&worker_name).await?;
+                                }
                                 Ok(())
-                            }
+                            },
+                            "mining.extranonce.subscribe" if stratum.is_some() => {
+                                write_rpc(is_encrypted,&mut worker_w,&stratum_result(rpc_id,true),&worker_name).await?;
+                                Ok(())
+                            },
+                            "mining.authorize" if stratum.is_some() => {
+                                if let Some((route_pools, lines, w, route_priority)) = route_login(worker,&proxy,json_rpc.as_ref()).await? {
+                                    pools = route_pools;
+                                    preferred = pools.subscribe();
+                                    pool_lines = lines;
+                                    pool_w = w;
+                                    priority = route_priority;
+                                    job_rpc.result.clear();
+                                    upstream = Some(EthStratumUpstream::new());
+                                    write_to_socket(&mut pool_w, &EthStratumUpstream::subscribe_rpc(), &worker_name).await?;
+                                }
+                                // Same dialect on both sides, the subscribe made sure of it. The pool pushes the first job.
+                                login(worker,&mut pool_w,&mut json_rpc,&mut worker_name,&config).await?;
+                                saved_login = Some(json_rpc.to_vec()?);
+                                if let Some(session) = stratum.as_mut() {
+                                    session.authorized = true;
+                                }
+                                write_rpc(is_encrypted,&mut worker_w,&stratum_result(rpc_id,true),&worker_name).await?;
+                                if !job_rpc.result.is_empty() {
+                                    let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,fee_jobs.sent);
+                                    write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
+                                }
+                                Ok(())
+                            },
+                            "mining.submit" if stratum.is_some() => {
+                                let work = match stratum.as_ref() {
+                                    Some(session) if session.authorized => session.submit_to_work(&json_rpc.get_params()).ok_or(RejectReason::Malformed),
+                                    _ => Err(RejectReason::Unauthorized),
+                                };
+                                match work {
+                                    Ok(params) => {
+                                        let mut json_rpc = Box::new(EthClientWorkerObject{ id: rpc_id, method: "eth_submitWork".into(), params, worker: worker.worker_name.clone()});
+                                        if let Some(accepted) = submit_work(worker,&mut pool_w,&mut json_rpc,&mut fee_jobs,&proxy,upstream.as_ref(),&mut pending).await? {
+                                            write_share_result(is_encrypted,&mut worker_w,true,rpc_id,accepted,&worker_name).await?;
+                                        }
+                                    },
+                                    Err(reason) => {
+                                        tracing::warn!("Miner {} submitted a {} share {:?}",worker_name,reason,json_rpc);
+                                        worker.share_reject_reason(reason);
+                                        write_rpc(is_encrypted,&mut worker_w,&stratum_result(rpc_id,false),&worker_name).await?;
+                                    },
+                                }
+                                Ok(())
+                            },
                             _ => {
-                                // tracing::warn!("Not found method {:?}",json_rpc);
-                                // eth_server_result.id = rpc_id;
-                                // write_to_socket_byte(&mut pool_w,buffer.to_vec(),&mut worker_name).await?;
                                 pool_w.shutdown().await?;
                                 worker_w.shutdown().await?;
                                 return Ok(());
@@ -192,112 +324,221 @@ where
 
             },
             res = pool_lines.next_line() => {
-                let buffer = lines_unwrap(res,&worker_name,"mining pool").await?;
+                let buffer = match lines_unwrap(res,&worker_name,"mining pool").await {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        // Nothing to replay before the miner logged in
+                        let login = match saved_login.as_ref() {
+                            Some(login) => login.clone(),
+                            None => return Err(e),
+                        };
+                        tracing::warn!("{}. Switching miner {} to another pool",e,worker_name);
+
+                        let (lines, w, endpoint) = redial(&pools,&worker_name).await?;
+                        pool_lines = lines;
+                        pool_w = w;
+                        priority = endpoint.priority;
+                        drop_pending(is_encrypted,&mut worker_w,worker,&proxy,&mut pending,stratum.is_some(),&worker_name).await?;
+                        replay_login(&mut pool_w,&mut upstream,login,rpc_id,&worker_name).await?;
+                        worker.pool_switched(endpoint.address);
+                        continue;
+                    },
+                };
                 #[cfg(debug_assertions)]
                 debug!("1 :  Mining Pool -> Mining Machine {} #{:?}",worker_name, buffer);
 
-                if let Ok(rpc) = serde_json::from_str::<EthServerRootObject>(&buffer) {
+                let pool_job = match upstream.as_mut() {
+                    Some(up) => match up.handle_message(&buffer) {
+                        UpstreamMessage::Job(job) => Some(job),
+                        UpstreamMessage::Subscribed => {
+                            if let Some(id) = pending_login.take() {
+                                answer_ethproxy_login(is_encrypted,&mut worker_w,id,&up.extranonce,&worker_name).await?;
+                            }
+                            ethproxy_extranonce(stratum.is_none(),&up.extranonce)?;
+                            if let Some(session) = stratum.as_mut() {
+                                if let Some(id) = pending_subscribe.take() {
+                                    session.extranonce = up.extranonce.clone();
+                                    write_rpc(is_encrypted,&mut worker_w,&session.subscribe_result(id),&worker_name).await?;
+                                } else if fee_jobs.sent == Destination::Miner {
+                                    // A new pool after a switch. On a fee job the next job of the pool switches.
+                                    if let Some(set) = session.switch_extranonce(&up.extranonce) {
+                                        write_rpc(is_encrypted,&mut worker_w,&set,&worker_name).await?;
+                                    }
+                                }
+                            }
+                            continue;
+                        },
+                        UpstreamMessage::Extranonce(extranonce) => {
+                            ethproxy_extranonce(stratum.is_none(),&extranonce)?;
+                            if let Some(session) = stratum.as_mut() {
+                                if fee_jobs.sent == Destination::Miner {
+                                    if let Some(set) = session.switch_extranonce(&extranonce) {
+                                        write_rpc(is_encrypted,&mut worker_w,&set,&worker_name).await?;
+                                    }
+                                }
+                            }
+                            continue;
+                        },
+                        UpstreamMessage::Difficulty => continue,
+                        UpstreamMessage::Other => None,
+                    },
+                    None => serde_json::from_str::<EthServerRootObject>(&buffer).ok().map(|rpc| rpc.result),
+                };
+
+                if let Some(pool_job) = pool_job {
                     // add index
                     worker.send_job()?;
-                    if is_fee_random(*DEVELOP_FEE) {
+                    // Fee jobs handed out must not be older than the block the miner is on
+                    fee_jobs.height = job_height(&pool_job);
+                    let rounds = proxy.fee_rounds(upstream.is_some());
+                    if fee_round(stratum.as_ref(),rounds,Destination::Develop) && fee_jobs.develop_scheduler.is_fee_job() {
                         #[cfg(debug_assertions)]
                         debug!("Enter the developer rake round");
-                        //if let Some(job_res) = wait_dev_job.pop_back() {
-			let fee = RwLockReadGuard::map(proxy.develop_job.read().await, |f| f);			
-			if let Some(job_res) = fee.back() {
+                        if let Some(job_res) = rounds.develop_job.latest(fee_jobs.height) {
                             worker.send_develop_job()?;
                             #[cfg(debug_assertions)]
                             debug!("Get developer pumping task success {:?}",&job_res);
-                            job_rpc.result = job_res.clone();
+                            job_rpc.result = job_res;
                             let job_id = job_rpc.get_job_id().unwrap();
-                            dev_fee_job.push(job_id.clone());
+                            fee_jobs.develop.push(job_id.clone());
+                            fee_jobs.remember(&job_rpc.result);
+                            fee_jobs.sent = Destination::Develop;
                             #[cfg(debug_assertions)]
                             debug!("{} Send developer tasks #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                            let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,Destination::Develop);
+                            write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
                             continue;
-                        }			
-			
-			// if let Ok(job_res) = dev_chan.recv().await {
-                        //     worker.send_develop_job()?;
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("Get developer pumping task success {:?}",&job_res);
-                        //     job_rpc.result = job_res;
-                        //     let job_id = job_rpc.get_job_id().unwrap();
-                        //     dev_fee_job.push(job_id.clone());
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("{} Send developer tasks #{:?}",worker_name, job_rpc);
-                        //     write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                        //     continue;
-                        // }
-                    } else if is_fee_random(config.share_rate.into()) {
+                        }
+                    }
+                    // No developer job at hand, the fee may still be due
+                    if fee_round(stratum.as_ref(),rounds,Destination::Fee) && fee_jobs.fee_scheduler.is_fee_job() {
                         #[cfg(debug_assertions)]
                         debug!("Enter the normal draw round");
-
-
-			let fee = RwLockReadGuard::map(proxy.fee_job.read().await, |f| f);
-			if let Some(job_res) = fee.back() {
+                        if let Some(job_res) = rounds.fee_job.latest(fee_jobs.height) {
                             worker.send_fee_job()?;
-                            job_rpc.result = job_res.clone();
+                            job_rpc.result = job_res;
                             let job_id = job_rpc.get_job_id().unwrap();
-                            fee_job.push(job_id.clone());
+                            fee_jobs.fee.push(job_id.clone());
+                            fee_jobs.remember(&job_rpc.result);
+                            fee_jobs.sent = Destination::Fee;
                             #[cfg(debug_assertions)]
                             debug!("{} Send a pumping task #{:?}",worker_name, job_rpc);
-                            write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
+                            let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,Destination::Fee);
+                            write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
                             continue;
                         }
-			//                        if let Some(job_res) = wait_job.pop_back() {
-			// if let Ok(job_res) = chan.recv().await {
-                        //     worker.send_fee_job()?;
-                        //     job_rpc.result = job_res;
-                        //     let job_id = job_rpc.get_job_id().unwrap();
-                        //     fee_job.push(job_id.clone());
-                        //     #[cfg(debug_assertions)]
-                        //     debug!("{} Send a pumping task #{:?}",worker_name, job_rpc);
-                        //     write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                        //     continue;
-                        // }
                     }
 
-
-                    job_rpc.result = rpc.result;
-                    // let job_id = job_rpc.get_job_id().unwrap();
-                    // send_job.push(job_id);
+                    job_rpc.result = pool_job;
+                    fee_jobs.remember(&job_rpc.result);
+                    fee_jobs.sent = Destination::Miner;
                     #[cfg(debug_assertions)]
                     debug!("{} Send normal tasks #{:?}",worker_name, job_rpc);
-                    write_rpc(is_encrypted,&mut worker_w,&job_rpc,&worker_name).await?;
-                } else if let Ok(result_rpc) = serde_json::from_str::<EthServer>(&buffer) {
+                    let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,Destination::Miner);
+                    write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
+                } else if let Ok(result_rpc) = serde_json::from_str::<EthServerResponse>(&buffer) {
                     if result_rpc.id == CLIENT_LOGIN {
                         worker.logind();
-                    } else if result_rpc.id == CLIENT_SUBMITWORK && result_rpc.result {
-                        worker.share_accept();
-                    } else if result_rpc.id == CLIENT_SUBMITWORK {
-                        worker.share_reject();
+                    } else if let Some(share) = pending.take(result_rpc.id) {
+                        if result_rpc.is_accepted() {
+                            worker.share_accept();
+                            worker.add_work(share.record.difficulty);
+                            proxy.ledger.record(share.record.verdict(true,None));
+                            write_share_result(is_encrypted,&mut worker_w,stratum.is_some(),share.miner_id,true,&worker_name).await?;
+                        } else {
+                            let reason = handle_error_for_worker(&worker_name,buffer.as_bytes());
+                            worker.share_reject_reason(reason);
+                            proxy.ledger.record(share.record.verdict(false,Some(reason)));
+                            if result_rpc.error.is_null() {
+                                write_share_result(is_encrypted,&mut worker_w,stratum.is_some(),share.miner_id,false,&worker_name).await?;
+                            } else {
+                                write_pool_answer(is_encrypted,&mut worker_w,&buffer,share.miner_id,&worker_name).await?;
+                            }
+                        }
+                    } else {
+                        debug!("{} Pool answer without a pending request {:?}",worker_name,result_rpc);
                     }
                 }
             },
-            // Ok(job_res) = dev_chan.recv() => {
-            //     wait_dev_job.push_back(job_res);
-            // },
-            // Ok(job_res) = chan.recv() => {
-            //     wait_job.push_back(job_res);
-            // },
+            // A fee round follows new blocks of the fee pool instead of
+            // waiting for the next job of the miner's pool
+            Ok(()) = develop_updates.changed(), if fee_jobs.sent == Destination::Develop => {
+                let rounds = proxy.fee_rounds(upstream.is_some());
+                if !fee_round(stratum.as_ref(),rounds,Destination::Develop) {
+                    continue;
+                }
+                if let Some(job_res) = fee_jobs.fresh_job(&rounds.develop_job, Destination::Develop) {
+                    worker.send_develop_job()?;
+                    job_rpc.result = job_res;
+                    let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,Destination::Develop);
+                    write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
+                }
+            },
+            Ok(()) = fee_updates.changed(), if fee_jobs.sent == Destination::Fee => {
+                let rounds = proxy.fee_rounds(upstream.is_some());
+                if !fee_round(stratum.as_ref(),rounds,Destination::Fee) {
+                    continue;
+                }
+                if let Some(job_res) = fee_jobs.fresh_job(&rounds.fee_job, Destination::Fee) {
+                    worker.send_fee_job()?;
+                    job_rpc.result = job_res;
+                    let extranonce = job_extranonce(stratum.as_ref(),upstream.as_ref(),&proxy,Destination::Fee);
+                    write_job(is_encrypted,&mut worker_w,&mut stratum,&job_rpc,&extranonce,&worker_name).await?;
+                }
+            },
+            _ = expire.tick() => {
+                for share in pending.expire(SUBMIT_TIMEOUT) {
+                    tracing::warn!("Miner {} share got no answer from the pool in {:?}",worker_name,SUBMIT_TIMEOUT);
+                    worker.share_reject_reason(RejectReason::Other);
+                    proxy.ledger.record(share.record.verdict(false,Some(RejectReason::Other)));
+                    write_share_result(is_encrypted,&mut worker_w,stratum.is_some(),share.miner_id,false,&worker_name).await?;
+                }
+            },
+            Ok(()) = reloads.changed() => {
+                let reloaded = proxy.config.read().await.clone();
+                if (reloaded.share_alg, reloaded.share_rate) != (config.share_alg, config.share_rate) {
+                    fee_jobs.fee_scheduler = new_fee_scheduler(reloaded.share_alg, reloaded.share_rate.into());
+                    fee_jobs.develop_scheduler = new_fee_scheduler(reloaded.share_alg, *DEVELOP_FEE);
+                }
+                config = reloaded;
+            },
+            Ok(()) = preferred.changed() => {
+                if *preferred.borrow() < priority && !failback_due {
+                    let jitter = rand::Rng::gen_range(&mut rng, 0..FAILBACK_JITTER_MS);
+                    failback.as_mut().reset(time::Instant::now() + time::Duration::from_millis(jitter));
+                    failback_due = true;
+                }
+            },
+            () = &mut failback, if failback_due => {
+                failback_due = false;
+                let best = *preferred.borrow();
+                let login = match saved_login.as_ref() {
+                    Some(login) if best < priority => login.clone(),
+                    _ => continue,
+                };
+                info!("Fail back miner {} from {} to a pool with priority {}",worker_name,worker.pool,best);
+                let (lines, w, endpoint) = redial(&pools,&worker_name).await?;
+                pool_lines = lines;
+                pool_w = w;
+                priority = endpoint.priority;
+                drop_pending(is_encrypted,&mut worker_w,worker,&proxy,&mut pending,stratum.is_some(),&worker_name).await?;
+                replay_login(&mut pool_w,&mut upstream,login,rpc_id,&worker_name).await?;
+                worker.pool_switched(endpoint.address);
+            },
+            Ok(kicked) = kicks.recv() => {
+                if kicked == worker.worker {
+                    bail!("Miner {} kicked by the web supervisor", worker_name);
+                }
+            },
             () = &mut sleep  => {
-		if dev_fee_job.len() > 1000 {
-		     dev_fee_job  = dev_fee_job.drain(750..).collect();
-		}
-		
-		if fee_job.len() > 1000 {
-		    fee_job = fee_job.drain(750..).collect();
-		}
-		
-		if wait_dev_job.len() > 1000 {
-		    wait_dev_job = wait_dev_job.drain(900..).collect();
-		}
-		
-		if wait_job.len() > 1000 {
-		    wait_job = wait_job.drain(900..).collect();
-		}
-		
+                if fee_jobs.develop.len() > 1000 {
+                    fee_jobs.develop = fee_jobs.develop.drain(750..).collect();
+                }
+                if fee_jobs.fee.len() > 1000 {
+                    fee_jobs.fee = fee_jobs.fee.drain(750..).collect();
+                }
+
+                worker.update_effective_hash();
                 match workers_queue.send(worker.clone()) {
                     Ok(_) => {},
                     Err(_) => {
@@ -309,3 +550,377 @@ where
         }
     }
 }
+
+// An ETHProxy miner picks the whole nonce itself and cannot be told to keep
+// the extranonce of the pool in front, so every share would be refused.
+// Only a pool that changes its extranonce after the login gets here.
+fn ethproxy_extranonce(ethproxy_miner: bool, extranonce: &str) -> Result<()> {
+    if ethproxy_miner && !extranonce.is_empty() {
+        bail!("The pool assigned extranonce {}, ETHProxy miners need pool_protocol AUTO or ETHPROXY on such a pool", extranonce);
+    }
+    Ok(())
+}
+
+// Answer the eth_submitLogin of an ETHProxy miner once its EthereumStratum
+// pool assigned the extranonce. Refused right away if there is one.
+async fn answer_ethproxy_login<W>(
+    is_encrypted: bool, w: &mut WriteHalf<W>, id: u64, extranonce: &str,
+    worker_name: &String,
+) -> Result<()>
+where W: AsyncWrite {
+    if extranonce.is_empty() {
+        let rpc = EthServerRoot {
+            id,
+            jsonrpc: "2.0".into(),
+            result: true,
+        };
+        return write_rpc(is_encrypted, w, &rpc, worker_name).await;
+    }
+    let refused = serde_json::json!({
+        "id": id,
+        "jsonrpc": "2.0",
+        "result": false,
+        "error": [20, "The pool assigns an extranonce, ETHProxy miners need pool_protocol AUTO or ETHPROXY", null],
+    });
+    write_rpc(is_encrypted, w, &refused, worker_name).await?;
+    bail!("Miner {} refused, the pool assigned extranonce {} and ETHProxy cannot keep to it", worker_name, extranonce)
+}
+
+// Shares sent to a pool the session left will never be answered
+async fn drop_pending<W>(
+    is_encrypted: bool, worker_w: &mut WriteHalf<W>, worker: &mut Worker,
+    proxy: &Proxy, pending: &mut PendingRequests<MinerShare>, is_stratum: bool,
+    worker_name: &String,
+) -> Result<()>
+where W: AsyncWrite {
+    for share in pending.expire(time::Duration::from_secs(0)) {
+        worker.share_reject_reason(RejectReason::Other);
+        proxy.ledger.record(share.record.verdict(false, Some(RejectReason::Other)));
+        write_share_result(is_encrypted, worker_w, is_stratum, share.miner_id, false, worker_name).await?;
+    }
+    Ok(())
+}
+
+// Log the miner in again on a newly dialed pool
+async fn replay_login(
+    pool_w: &mut PoolWriter, upstream: &mut Option<EthStratumUpstream>,
+    login: Vec<u8>, rpc_id: u64, worker_name: &String,
+) -> Result<()> {
+    if upstream.is_some() {
+        *upstream = Some(EthStratumUpstream::new());
+        write_to_socket(pool_w, &EthStratumUpstream::subscribe_rpc(), worker_name).await?;
+    }
+    write_to_socket_byte(pool_w, login, worker_name).await?;
+    if upstream.is_none() {
+        let mut get_work: Box<dyn EthClientObject + Send + Sync> = Box::new(EthClientRootObject {
+            id: rpc_id,
+            method: "eth_getWork".into(),
+            params: vec![],
+        });
+        new_eth_get_work(pool_w, &mut get_work, worker_name).await?;
+    }
+    Ok(())
+}
+
+// Dial the pools again after the upstream dropped. The miner connection
+// stays open meanwhile.
+async fn redial(
+    pools: &PoolManager, worker_name: &String,
+) -> Result<(PoolLines, PoolWriter, PoolEndpoint)> {
+    let mut attempt = 1;
+    loop {
+        match pools.connect_lines().await {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt >= REDIAL_ATTEMPTS => return Err(e),
+            Err(e) => {
+                tracing::warn!("Miner {} redial #{} failed: {}", worker_name, attempt, e);
+                attempt += 1;
+                time::sleep(REDIAL_DELAY).await;
+            }
+        }
+    }
+}
+
+// Dial the pools of the first routing rule matching the login. Runs before
+// the login is sent so the miner only ever logs in on its own pools.
+async fn route_login(
+    worker: &mut Worker, proxy: &Proxy,
+    rpc: &(dyn EthClientObject + Send + Sync),
+) -> Result<Option<(Arc<PoolManager>, PoolLines, PoolWriter, u32)>> {
+    if !worker.route.is_empty() {
+        return Ok(None);
+    }
+
+    let route = match proxy.router.route_login(rpc, worker.ip.parse().ok()) {
+        Some(route) => route,
+        None => return Ok(None),
+    };
+
+    let (lines, w, endpoint) = route.pools.connect_lines().await?;
+    info!(
+        "Miner: {} routed by {} to {}",
+        rpc.get_eth_wallet().unwrap_or_default(),
+        route.rule.name,
+        endpoint.address
+    );
+    worker.route = route.rule.name.clone();
+    worker.pool = endpoint.address;
+
+    Ok(Some((route.pools.clone(), lines, w, endpoint.priority)))
+}
+
+// Send a job to the miner in the dialect it logged in with.
+async fn write_job<W>(
+    is_encrypted: bool, w: &mut WriteHalf<W>,
+    stratum: &mut Option<EthStratumSession>, job_rpc: &EthServerRootObjectJsonRpc,
+    extranonce: &str, worker_name: &String,
+) -> Result<()>
+where W: AsyncWrite {
+    if let Some(session) = stratum {
+        if !session.authorized {
+            return Ok(());
+        }
+
+        if let Some(set) = session.switch_extranonce(extranonce) {
+            write_rpc(is_encrypted, w, &set, worker_name).await?;
+        }
+
+        if let Some((set_diff, notify)) = session.job_to_notify(&job_rpc.result) {
+            if let Some(set_diff) = set_diff {
+                write_rpc(is_encrypted, w, &set_diff, worker_name).await?;
+            }
+            write_rpc(is_encrypted, w, &notify, worker_name).await?;
+        }
+        Ok(())
+    } else {
+        write_rpc(is_encrypted, w, job_rpc, worker_name).await
+    }
+}
+
+// Nonce prefix an EthereumStratum/1.0.0 miner needs for a job from
+// `destination`. Empty for ETHProxy miners.
+fn job_extranonce(
+    stratum: Option<&EthStratumSession>, upstream: Option<&EthStratumUpstream>,
+    proxy: &Proxy, destination: Destination,
+) -> String {
+    match (stratum, destination) {
+        (None, _) => String::new(),
+        (Some(_), Destination::Miner) => {
+            upstream.map(|up| up.extranonce.clone()).unwrap_or_default()
+        }
+        (Some(session), _) => session.fee_extranonce(
+            &proxy.fee_rounds(upstream.is_some()).extranonce(destination),
+        ),
+    }
+}
+
+// An ETHProxy miner picks the whole nonce, so it can only work for a fee or
+// developer pool that assigned no extranonce
+fn fee_round(
+    stratum: Option<&EthStratumSession>, rounds: &FeeRounds,
+    destination: Destination,
+) -> bool {
+    stratum.is_some() || rounds.extranonce(destination).is_empty()
+}
+
+fn stratum_result(id: u64, result: bool) -> EthStratumResult {
+    EthStratumResult {
+        id,
+        result,
+        error: serde_json::Value::Null,
+    }
+}
+
+// Answer a share in the dialect of the miner.
+async fn write_share_result<W>(
+    is_encrypted: bool, w: &mut WriteHalf<W>, is_stratum: bool, id: u64,
+    accepted: bool, worker_name: &String,
+) -> Result<()>
+where W: AsyncWrite {
+    if is_stratum {
+        write_rpc(is_encrypted, w, &stratum_result(id, accepted), worker_name)
+            .await
+    } else {
+        let rpc = EthServerRoot {
+            id,
+            jsonrpc: "2.0".into(),
+            result: accepted,
+        };
+        write_rpc(is_encrypted, w, &rpc, worker_name).await
+    }
+}
+
+// Relay a pool answer verbatim on the id the miner used.
+async fn write_pool_answer<W>(
+    is_encrypted: bool, w: &mut WriteHalf<W>, buf: &str, id: u64,
+    worker_name: &String,
+) -> Result<()>
+where W: AsyncWrite {
+    let mut rpc: serde_json::Value = serde_json::from_str(buf)?;
+    rpc["id"] = id.into();
+    write_rpc(is_encrypted, w, &rpc, worker_name).await
+}
+
+// How many recent jobs keep their difficulty for weighting shares
+const MAX_JOB_DIFFICULTY: usize = 64;
+
+// Job ids sent to the miner on behalf of the fee and developer pools, and
+// the schedulers deciding when to send them.
+struct FeeJobs {
+    fee: Vec<String>,
+    develop: Vec<String>,
+    fee_scheduler: Box<dyn FeeScheduler>,
+    develop_scheduler: Box<dyn FeeScheduler>,
+    // (job id, difficulty) of every job sent to the miner
+    difficulty: VecDeque<(String, f64)>,
+    // Block height of the miner's pool, 0 if unknown
+    height: u64,
+    // Where the last job sent to the miner came from
+    sent: Destination,
+}
+
+impl FeeJobs {
+    fn new(config: &Settings) -> Self {
+        Self {
+            fee: Vec::new(),
+            develop: Vec::new(),
+            fee_scheduler: new_fee_scheduler(config.share_alg, config.share_rate.into()),
+            develop_scheduler: new_fee_scheduler(config.share_alg, *DEVELOP_FEE),
+            difficulty: VecDeque::new(),
+            height: 0,
+            sent: Destination::Miner,
+        }
+    }
+
+    // The newest job of `store` unless the miner already has it
+    fn fresh_job(&mut self, store: &JobStore, destination: Destination) -> Option<Vec<String>> {
+        let job = store.latest(self.height)?;
+        let sent = match destination {
+            Destination::Develop => &mut self.develop,
+            Destination::Fee => &mut self.fee,
+            Destination::Miner => return None,
+        };
+        if sent.contains(&job[0]) {
+            return None;
+        }
+        sent.push(job[0].clone());
+        self.remember(&job);
+        Some(job)
+    }
+
+    fn remember(&mut self, job: &[String]) {
+        if let (Some(job_id), Some(target)) = (job.first(), job.get(2)) {
+            self.difficulty.push_back((job_id.clone(), target_to_diff(target)));
+            while self.difficulty.len() > MAX_JOB_DIFFICULTY {
+                self.difficulty.pop_front();
+            }
+        }
+    }
+
+    // Feed a share found for `job_id` to both schedulers. Returns the
+    // difficulty of its job.
+    fn record_share(&mut self, job_id: &str) -> f64 {
+        let difficulty = self
+            .difficulty
+            .iter()
+            .rev()
+            .find(|(id, _)| id == job_id)
+            .map(|(_, diff)| *diff)
+            .unwrap_or(1.0);
+        let develop = self.develop.iter().any(|id| id == job_id);
+        let fee = self.fee.iter().any(|id| id == job_id);
+        self.develop_scheduler.record_share(difficulty, develop);
+        self.fee_scheduler.record_share(difficulty, fee);
+        difficulty
+    }
+}
+
+// A miner share waiting for the pool verdict
+struct MinerShare {
+    miner_id: u64,
+    record: ShareRecord,
+}
+
+// Route a share to the developer, fee or miner pool depending on which job
+// it was found for. Returns the answer for the miner, or None when it has to
+// wait for the pool.
+async fn submit_work<W>(
+    worker: &mut Worker, pool_w: &mut WriteHalf<W>,
+    json_rpc: &mut Box<EthClientWorkerObject>, fee_jobs: &mut FeeJobs,
+    proxy: &Proxy, upstream: Option<&EthStratumUpstream>,
+    pending: &mut PendingRequests<MinerShare>,
+) -> Result<Option<bool>>
+where W: AsyncWrite {
+    let job_id = match json_rpc.get_job_id() {
+        Some(job_id) => job_id,
+        None => bail!("Share without job id"),
+    };
+    let difficulty = fee_jobs.record_share(&job_id);
+
+    // The rig did the work either way. Fee and developer verdicts arrive in
+    // the fee loops, so their shares count once forwarded.
+    if fee_jobs.develop.contains(&job_id) || fee_jobs.fee.contains(&job_id) {
+        worker.add_work(difficulty);
+    }
+
+    if fee_jobs.develop.contains(&job_id) {
+        worker.develop_share_index_add();
+        let share = FeeShare {
+            params: json_rpc.get_params(),
+            record: ShareRecord::new(worker, &job_id, Destination::Develop, difficulty),
+        };
+        if let Err(TrySendError::Full(share) | TrySendError::Closed(share)) =
+            proxy.fee_rounds(upstream.is_some()).dev_tx.try_send(share)
+        {
+            debug!("The developer channel is full. Share of {} dropped", worker.worker);
+            proxy.ledger.record(share.record.verdict(false, Some(RejectReason::Other)));
+        }
+        return Ok(Some(true));
+    }
+
+    if fee_jobs.fee.contains(&job_id) {
+        worker.fee_share_index_add();
+        worker.fee_share_accept();
+        let share = FeeShare {
+            params: json_rpc.get_params(),
+            record: ShareRecord::new(worker, &job_id, Destination::Fee, difficulty),
+        };
+        if let Err(TrySendError::Full(share) | TrySendError::Closed(share)) =
+            proxy.fee_rounds(upstream.is_some()).tx.try_send(share)
+        {
+            debug!("The transit channel is full. Share of {} dropped", worker.worker);
+            proxy.ledger.record(share.record.verdict(false, Some(RejectReason::Other)));
+        }
+        return Ok(Some(true));
+    }
+
+    worker.share_index_add();
+    let share = MinerShare {
+        miner_id: json_rpc.get_id(),
+        record: ShareRecord::new(worker, &job_id, Destination::Miner, difficulty),
+    };
+    if let Some(up) = upstream {
+        match up.work_to_submit(&json_rpc.get_params(), &worker.worker) {
+            Ok(mut submit) => {
+                submit.id = pending.insert(share);
+                write_to_socket(pool_w, &submit, &worker.worker).await?;
+            }
+            Err(reason) => {
+                tracing::warn!(
+                    "Miner {} share is outside the jobs or extranonce of the pool ({}) {:?}",
+                    worker.worker,
+                    reason,
+                    json_rpc
+                );
+                worker.share_reject_reason(reason);
+                proxy.ledger.record(share.record.verdict(false, Some(reason)));
+                return Ok(Some(false));
+            }
+        }
+    } else {
+        json_rpc.set_id(pending.insert(share));
+        write_to_socket_byte(pool_w, json_rpc.to_vec()?, &worker.worker).await?;
+    }
+
+    Ok(None)
+}
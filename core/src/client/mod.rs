@@ -5,6 +5,11 @@ pub mod handle_stream;
 pub mod handle_stream_all;
 pub mod handle_stream_nofee;
 pub mod monitor;
+pub mod mux;
+pub mod pending;
+pub mod pool_manager;
+pub mod pool_tls;
+pub mod router;
 pub mod pools;
 pub mod tcp;
 pub mod tls;
@@ -13,8 +18,6 @@ pub mod tls;
 use tokio::sync::broadcast::{Receiver,error::TryRecvError};
 use anyhow::{anyhow,bail,Result};
 
-use native_tls::TlsConnector;
-use rand::prelude::SliceRandom;
 use serde::Serialize;
 use std::{
     collections::VecDeque,
@@ -23,25 +26,23 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio_native_tls::TlsStream;
 
 use tracing::debug;
 
 
 use tokio::{
-    io::{
-        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
-        Lines, ReadHalf, WriteHalf,
-    },
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf},
     net::TcpStream,
     sync::mpsc::UnboundedSender,
 };
 
+use self::pool_manager::{PoolEndpoint, PoolLines, PoolManager, PoolWriter};
 use crate::{
     protocol::{
         ethjson::{
             EthClientObject, EthClientRootObject, EthClientWorkerObject,
         },
+        bridge::EthStratumUpstream,
         rpc::eth::{Client, ClientWithWorkerName, ServerRpc},
         CLIENT_LOGIN, CLIENT_SUBHASHRATE,
     },
@@ -80,6 +81,8 @@ pub fn get_pool_ip_and_type(
                 }
             }
             if let Some(url) = new_pool_url.get(1) {
+                // Drop the pool manager options after '?'
+                let url = url.split('?').next().unwrap_or_default();
                 pools.push(url.to_string());
             };
         }
@@ -114,6 +117,8 @@ pub fn get_pool_ip_and_type_from_vec(
                 }
             }
             if let Some(url) = new_pool_url.get(1) {
+                // Drop the pool manager options after '?'
+                let url = url.split('?').next().unwrap_or_default();
                 pools.push(url.to_string());
             };
         }
@@ -142,6 +147,8 @@ pub fn get_pool_ip_and_type_for_proxyer(
                 }
             }
             if let Some(url) = new_pool_url.get(1) {
+                // Drop the pool manager options after '?'
+                let url = url.split('?').next().unwrap_or_default();
                 pools.push(url.to_string());
             };
         }
@@ -150,49 +157,6 @@ pub fn get_pool_ip_and_type_for_proxyer(
         bail!("There is an error in the address setting of the pumping pool, please check");
     }
 }
-//vs.choose(&mut rand::thread_rng())
-pub fn get_pool_random_stream(
-    pool_tcp_address: &Vec<String>,
-) -> Option<(std::net::TcpStream, SocketAddr)> {
-    for _ in 0..pool_tcp_address.len() {
-        let address = match pool_tcp_address.choose(&mut rand::thread_rng()) {
-            Some(s) => s,
-            None => continue,
-        };
-
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("The mining pool address format failed");
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} cannot be accessed. Switch backup pools! ! ! !", address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} cannot be accessed. Switch backup pools! ! ! !", address);
-                continue;
-            }
-        };
-        std_stream.set_nonblocking(true).unwrap();
-        return Some((std_stream, addr));
-    }
-
-    None
-}
-
 pub fn get_pool_stream(
     pool_tcp_address: &Vec<String>,
 ) -> Option<(std::net::TcpStream, SocketAddr)> {
@@ -230,88 +194,6 @@ pub fn get_pool_stream(
     None
 }
 
-pub async fn get_pool_stream_with_tls(
-    pool_tcp_address: &Vec<String>,
-) -> Option<(
-    tokio_native_tls::TlsStream<tokio::net::TcpStream>,
-    SocketAddr,
-)> {
-    for address in pool_tcp_address {
-        let mut tcp = match address.to_socket_addrs() {
-            Ok(t) => t,
-            Err(_) => {
-                tracing::error!("The mining pool address format failed {}", address);
-                continue;
-            }
-        };
-
-        let addr = match tcp.next() {
-            Some(address) => address,
-            None => {
-                //debug!("{} {} cannot be accessed. Switch backup pools! ! ! !", name,
-                // address);
-                continue;
-            }
-        };
-
-        let std_stream = match std::net::TcpStream::connect_timeout(
-            &addr,
-            Duration::new(60, 0),
-        ) {
-            Ok(straem) => straem,
-            Err(_) => {
-                //debug!("{} {} cannot be accessed. Switch backup pools! ! ! !", name,
-                // address);
-                continue;
-            }
-        };
-
-        std_stream.set_nonblocking(true).unwrap();
-
-        let stream = match TcpStream::from_std(std_stream) {
-            Ok(stream) => stream,
-            Err(_) => {
-                //debug!("{} {} cannot be accessed. Switch backup pools! ! ! !", name,
-                // address);
-                continue;
-            }
-        };
-
-        stream.set_nodelay(true).unwrap();
-
-        let cx = match TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true)
-            .min_protocol_version(Some(native_tls::Protocol::Tlsv11))
-            //.disable_built_in_roots(true)
-            .build()
-        {
-            Ok(con) => con,
-            Err(_) => {
-                //debug!("{} {} SSL verification failed! ! ! !", name, address);
-                continue;
-            }
-        };
-
-        let cx = tokio_native_tls::TlsConnector::from(cx);
-
-        let domain: Vec<&str> = address.split(":").collect();
-        let server_stream = match cx.connect(domain[0], stream).await {
-            Ok(stream) => stream,
-            Err(_err) => {
-                //debug!("{} {} SSL link failed! ! ! ! {:?}", name, address,
-                // err);
-                continue;
-            }
-        };
-
-        //debug!("{} conteactd to {}", name, address);
-        return Some((server_stream, addr));
-    }
-
-    None
-}
-
 // pub async fn write_encrypt_socket<W, T>(
 //     w: &mut WriteHalf<W>, rpc: &T, worker: &String, key: String, iv: String,
 // ) -> Result<()>
@@ -386,6 +268,8 @@ where
             worker
         );
     }
+    // Encrypted streams hold what is written until flushed
+    w.flush().await?;
     Ok(())
 }
 
@@ -409,6 +293,7 @@ where W: AsyncWrite {
             worker
         );
     }
+    w.flush().await?;
     Ok(())
 }
 
@@ -424,6 +309,7 @@ where W: AsyncWrite {
             worker
         );
     }
+    w.flush().await?;
     Ok(())
 }
 
@@ -439,6 +325,7 @@ where W: AsyncWrite {
             worker
         );
     }
+    w.flush().await?;
     Ok(())
 }
 
@@ -526,61 +413,25 @@ where
 pub async fn handle_tcp_random<R, W>(
     worker: &mut Worker,
     worker_r: tokio::io::BufReader<tokio::io::ReadHalf<R>>,
-    worker_w: WriteHalf<W>, pools: &Vec<String>, proxy: Arc<Proxy>,
-    stream_type: i32, is_encrypted: bool,
+    worker_w: WriteHalf<W>, proxy: Arc<Proxy>, is_encrypted: bool,
 ) -> Result<()>
 where
     R: AsyncRead,
     W: AsyncWrite,
 {
-    if stream_type == TCP {
-        let (outbound, _) = match crate::client::get_pool_stream(&pools) {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("All TCP pools are unlinkable. Please modify and try again");
-            }
-        };
-
-        let stream = tokio::net::TcpStream::from_std(outbound)?;
-        stream.set_nodelay(true)?;
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
-            worker,
-            worker_r,
-            worker_w,
-            pool_r,
-            pool_w,
-            proxy,
-            is_encrypted,
-        )
-        .await
-    } else if stream_type == SSL {
-        let (stream, _) =
-            match crate::client::get_pool_stream_with_tls(&pools).await {
-                Some((stream, addr)) => (stream, addr),
-                None => {
-                    bail!("All TCP pools are unlinkable. Please modify and try again");
-                }
-            };
+    let (stream, endpoint) = proxy.pool.connect().await?;
+    worker.pool = endpoint.address.clone();
 
-        let (pool_r, pool_w) = tokio::io::split(stream);
-        let pool_r = tokio::io::BufReader::new(pool_r);
-
-        handle_stream::handle_stream(
-            worker,
-            worker_r,
-            worker_w,
-            pool_r,
-            pool_w,
-            proxy,
-            is_encrypted,
-        )
-        .await
-    } else {
-        panic!("unreachable branch reached");
-    }
+    handle_stream::handle_stream(
+        worker,
+        worker_r,
+        worker_w,
+        stream,
+        endpoint,
+        proxy,
+        is_encrypted,
+    )
+    .await
 }
 
 // pub async fn handle_tcp_timer<R, W>(
@@ -871,224 +722,74 @@ pub async fn submit_develop_hashrate(
 
 // new -----------------------------------------------------------------
 pub async fn proxy_pool_login(
-    config: &Settings, _hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    //TODO Compatible with SSL mining pools here
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("All TCP pools are unlinkable. Please modify and try again");
-                bail!("All TCP pools are unlinkable. Please modify and try again");
-            }
-        };
-
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
-        Some((stream, addr)) => (stream, addr),
-        None => {
-            bail!("All TCP pools are unlinkable. Please modify and try again");
-        }
-    };
-    let outbound = TcpStream::from_std(stream)?;
-    outbound.set_nodelay(true)?;
-    let (proxy_r, mut proxy_w) = tokio::io::split(outbound);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
+    config: &Settings, pools: &PoolManager, stratum: bool,
+) -> Result<(PoolLines, PoolWriter, PoolEndpoint)> {
+    let (proxy_lines, mut proxy_w, endpoint) = pools.connect_lines().await?;
 
     let s = config.get_share_name().unwrap();
+    fee_pool_login(&mut proxy_w, &config.share_wallet, &s, stratum).await?;
 
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
-        worker: s.clone(),
-    };
-
-    match write_to_socket(&mut proxy_w, &login, &s).await {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
-        }
-    }
-
-    Ok((proxy_lines, proxy_w))
+    Ok((proxy_lines, proxy_w, endpoint))
 }
 
-pub async fn proxy_pool_login_with_ssl(
-    config: &Settings, _hostname: String,
-) -> Result<(
-    Lines<BufReader<ReadHalf<tokio_native_tls::TlsStream<TcpStream>>>>,
-    WriteHalf<TlsStream<TcpStream>>,
-)> {
-    let (_stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(
-            &config.share_address,
-        ) {
-            Ok((stream, addr)) => (stream, addr),
-            Err(_e) => {
-                tracing::error!("All TCP pools are unlinkable. Please modify and try again");
-                bail!("All TCP pools are unlinkable. Please modify and try again");
-            }
-        };
-
-    let (stream, _) =
-        match crate::client::get_pool_stream_with_tls(&pools).await {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("All TCP pools are unlinkable. Please modify and try again");
-            }
+// eth_submitLogin, or mining.subscribe and mining.authorize of
+// `wallet.worker` on an EthereumStratum/1.0.0 pool
+async fn fee_pool_login(
+    w: &mut PoolWriter, wallet: &str, worker: &str, stratum: bool,
+) -> Result<()> {
+    let worker = worker.to_string();
+    let res = if stratum {
+        let authorize = EthStratumUpstream::authorize_worker(format!("{}.{}", wallet, worker));
+        async {
+            write_to_socket(w, &EthStratumUpstream::subscribe_rpc(), &worker).await?;
+            write_to_socket(w, &authorize, &worker).await
+        }
+        .await
+    } else {
+        let login = ClientWithWorkerName {
+            id: CLIENT_LOGIN,
+            method: "eth_submitLogin".into(),
+            params: vec![wallet.to_string(), "x".into()],
+            worker: worker.clone(),
         };
-
-    let (proxy_r, mut proxy_w) = tokio::io::split(stream);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
-
-    let s = config.get_share_name().unwrap();
-
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![config.share_wallet.clone(), "x".into()],
-        worker: s.clone(),
+        write_to_socket(w, &login, &worker).await
     };
 
-    match write_to_socket(&mut proxy_w, &login, &s).await {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
-        }
+    if let Err(e) = res {
+        tracing::error!("Error writing Socket, login of {}", worker);
+        return Err(e);
     }
-
-    Ok((proxy_lines, proxy_w))
+    Ok(())
 }
 
-pub async fn dev_pool_tcp_login(
-    hostname: String,
-) -> Result<(Lines<BufReader<ReadHalf<TcpStream>>>, WriteHalf<TcpStream>)> {
-    let pools = vec![
-        "127.0.0.1:4444".to_string(),
-        "127.0.0.1:4444".to_string(),
-    ];
-    // let pools = vec![
-    //     "127.0.0.1:4444".to_string(),
-    //     "127.0.0.1:4444".to_string(),
-    //     "127.0.0.1:4444".to_string(),
-    //     "127.0.0.1:4444".to_string(),
-    //     "127.0.0.1:4444".to_string(),
-    //     "127.0.0.1:4444".to_string(),
-    // ];
-
-    let (stream, _) = match crate::client::get_pool_stream(&pools) {
-        Some((stream, addr)) => (stream, addr),
-        None => {
-            bail!("All TCP pools are unlinkable. Please modify and try again");
-        }
-    };
-
-    let (proxy_r, mut proxy_w) =
-        tokio::io::split(tokio::net::TcpStream::from_std(stream)?);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
+// Developer fee pools. Certificates are verified like any other ssl:// pool.
+const DEVELOP_POOLS: [&str; 1] = ["ssl://127.0.0.1:5555"];
+const DEVELOP_WALLET: &str = "0x60cc493FD745E268622274D877f1A50eD8368251";
 
-    let login = ClientWithWorkerName {
-        id: CLIENT_LOGIN,
-        method: "eth_submitLogin".into(),
-        params: vec![
-            "0x60cc493FD745E268622274D877f1A50eD8368251".into(),
-            "x".into(),
-        ],
-        worker: hostname.clone(),
-    };
-
-    match write_to_socket(&mut proxy_w, &login, &hostname).await {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
-        }
-    }
-
-    Ok((proxy_lines, proxy_w))
+pub fn develop_pool() -> Result<PoolManager> {
+    let urls: Vec<String> = DEVELOP_POOLS.iter().map(|url| url.to_string()).collect();
+    PoolManager::new("develop pool", &urls)
 }
 
-pub async fn dev_pool_ssl_login(
-    hostname: String,
-) -> Result<(
-    Lines<BufReader<ReadHalf<tokio_native_tls::TlsStream<TcpStream>>>>,
-    WriteHalf<TlsStream<TcpStream>>,
-)> {
-    let pools = vec![
-        "127.0.0.1:5555".to_string(),
-        "127.0.0.1:5555".to_string(),
-    ];
-
-    // let pools = vec![
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    //     "127.0.0.1:5555".to_string(),
-    // ];
-
-    let (stream, _) =
-        match crate::client::get_pool_stream_with_tls(&pools).await {
-            Some((stream, addr)) => (stream, addr),
-            None => {
-                bail!("All mining pools are not linkable. Please modify and try again");
-            }
-        };
-    let (proxy_r, mut proxy_w) = tokio::io::split(stream);
-    let proxy_r = tokio::io::BufReader::new(proxy_r);
-    let proxy_lines = proxy_r.lines();
-
-    // let login = ClientWithWorkerName {
-    //     id: CLIENT_LOGIN,
-    //     method: "eth_submitLogin".into(),
-    //     params: vec![
-    //         "0x60cc493FD745E268622274D877f1A50eD8368251".into(),
-    //         "x".into(),
-    //    ],
-    //     worker: hostname.clone(),
-    // };
-
-    // let login = ClientWithWorkerName {
-    //     id: CLIENT_LOGIN,
-    //     method: "eth_submitLogin".into(),
-    //     params: vec![
-    //         "0x60cc493FD745E268622274D877f1A50eD8368251".into(),
-    //         "x".into(),
-    //     ],
-    //     worker: hostname.clone(),
-    // };
-
-    let login = ClientWithWorkerName {
+pub fn develop_login() -> EthClientRootObject {
+    EthClientRootObject {
         id: CLIENT_LOGIN,
         method: "eth_submitLogin".into(),
-        params: vec![
-            "0x60cc493FD745E268622274D877f1A50eD8368251".into(),
-            "x".into(),
-        ],
-        worker: hostname.clone(),
-    };
-
-    match write_to_socket(&mut proxy_w, &login, &hostname).await {
-        Ok(_) => {}
-        Err(e) => {
-            tracing::error!("Error writing Socket {:?}", login);
-            return Err(e);
-        }
+        params: vec![DEVELOP_WALLET.into(), "x".into()],
     }
+}
 
-    Ok((proxy_lines, proxy_w))
+pub async fn dev_pool_login(
+    pools: &PoolManager, hostname: &str, stratum: bool,
+) -> Result<(PoolLines, PoolWriter, PoolEndpoint)> {
+    let (proxy_lines, mut proxy_w, endpoint) = pools.connect_lines().await?;
+    fee_pool_login(&mut proxy_w, DEVELOP_WALLET, hostname, stratum).await?;
+    Ok((proxy_lines, proxy_w, endpoint))
 }
 
+// Full name the developer pool knows the transit by
+pub fn develop_worker(hostname: &str) -> String { format!("{}.{}", DEVELOP_WALLET, hostname) }
+
 pub async fn lines_unwrap(
     res: Result<Option<String>, std::io::Error>, worker_name: &String,
     form_name: &str,
@@ -1,172 +1,602 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use tracing::{debug, info};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
+    io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream},
     net::{TcpListener, TcpStream},
     select,
 };
 
-use crate::client::{self_write_socket_byte, write_to_socket_byte};
+use crate::{
+    client::{self_write_socket_byte, write_to_socket_byte},
+    util::{aead::AeadStream, mux::Mux},
+};
+
+// A miner has this long for its first line, then `MINER_TIMEOUT` between lines
+const FIRST_LINE_TIMEOUT: Duration = Duration::from_secs(1);
+const MINER_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct MonitorConfig {
+    // Local port the miners connect to
+    pub port: u16,
+    // `encrypt_key` of the transits
+    pub key: String,
+    // `host:port` of their `encrypt_port`, tried in order. Their `mux_port`
+    // with `mux`.
+    pub servers: Vec<String>,
+    // All miners over one connection instead of one each
+    pub mux: bool,
+    // Wait between rounds over `servers` while none answers, doubled up to
+    // `backoff_max_secs`
+    pub backoff_min_secs: u64,
+    pub backoff_max_secs: u64,
+    // How often the miner connection counts are logged
+    pub stats_secs: u64,
+    // How often every server is probed
+    pub probe_secs: u64,
+    // Servers, their health and the miners, rewritten after each probe.
+    // Empty for none.
+    pub status_file: String,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            key: String::new(),
+            servers: vec![],
+            mux: false,
+            backoff_min_secs: 1,
+            backoff_max_secs: 60,
+            stats_secs: 300,
+            probe_secs: 10,
+            status_file: "monitor_status.json".into(),
+        }
+    }
+}
+
+impl MonitorConfig {
+    // A missing file is an empty config, for flags only setups
+    pub fn load(path: &str) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Ok(serde_yaml::from_str(&s)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => bail!("Failed to read {}: {}", path, e),
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.port == 0 {
+            bail!("The local port is not set");
+        }
+        if self.servers.is_empty() {
+            bail!("No transit server is set");
+        }
+        if self.key.is_empty() {
+            bail!("The encryption key is not set");
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, current: Duration) -> Duration {
+        let max = Duration::from_secs(self.backoff_max_secs.max(self.backoff_min_secs));
+        (current * 2).min(max)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Count {
+    open: u64,
+    total: u64,
+}
+
+// Connections of each miner, by address
+#[derive(Default)]
+struct Miners(Mutex<HashMap<IpAddr, Count>>);
+
+impl Miners {
+    fn opened(&self, ip: IpAddr) -> Count {
+        let mut miners = self.0.lock().unwrap();
+        let count = miners.entry(ip).or_default();
+        count.open += 1;
+        count.total += 1;
+        *count
+    }
+
+    fn closed(&self, ip: IpAddr) -> Count {
+        let mut miners = self.0.lock().unwrap();
+        let count = miners.entry(ip).or_default();
+        count.open = count.open.saturating_sub(1);
+        *count
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        let miners = self.0.lock().unwrap();
+        (
+            miners.values().map(|c| c.open).sum(),
+            miners.values().map(|c| c.total).sum(),
+        )
+    }
+
+    fn log(&self) {
+        let miners = self.0.lock().unwrap();
+        let open: u64 = miners.values().map(|c| c.open).sum();
+        let mut list: Vec<_> = miners.iter().filter(|(_, c)| c.open > 0).collect();
+        list.sort_by_key(|(ip, _)| **ip);
+        let list: Vec<String> = list
+            .iter()
+            .map(|(ip, c)| format!("{} {}/{}", ip, c.open, c.total))
+            .collect();
+        info!(
+            "{} miner connections open from {} addresses (open/total): {}",
+            open,
+            list.len(),
+            list.join(", ")
+        );
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerHealth {
+    pub address: String,
+    pub healthy: bool,
+    pub latency_ms: u64,
+    pub error: String,
+    // Unix seconds of the last probe or connection, 0 before the first
+    pub checked_at: i64,
+}
+
+// What the servers answered lately. They start healthy so the first miners
+// do not wait for a probe.
+struct Health(Mutex<Vec<ServerHealth>>);
+
+impl Health {
+    fn new(servers: &[String]) -> Self {
+        Self(Mutex::new(
+            servers
+                .iter()
+                .map(|address| ServerHealth {
+                    address: address.clone(),
+                    healthy: true,
+                    latency_ms: 0,
+                    error: String::new(),
+                    checked_at: 0,
+                })
+                .collect(),
+        ))
+    }
+
+    fn set(&self, server: &str, res: std::result::Result<Duration, String>) {
+        let mut servers = self.0.lock().unwrap();
+        if let Some(health) = servers.iter_mut().find(|h| h.address == server) {
+            if health.healthy != res.is_ok() {
+                match &res {
+                    Ok(_) => info!("{} is healthy again", server),
+                    Err(e) => tracing::warn!("{} is down: {}", server, e),
+                }
+            }
+            health.healthy = res.is_ok();
+            health.checked_at = chrono::Utc::now().timestamp();
+            match res {
+                Ok(latency) => {
+                    health.latency_ms = latency.as_millis() as u64;
+                    health.error.clear();
+                }
+                Err(e) => health.error = e,
+            }
+        }
+    }
+
+    // The first healthy server, the one new miners go to
+    fn active(&self) -> Option<String> {
+        let servers = self.0.lock().unwrap();
+        servers.iter().find(|h| h.healthy).map(|h| h.address.clone())
+    }
+
+    // Healthy servers first, each group in the configured order
+    fn order(&self) -> Vec<String> {
+        let servers = self.0.lock().unwrap();
+        let (healthy, down): (Vec<_>, Vec<_>) = servers.iter().partition(|h| h.healthy);
+        healthy
+            .into_iter()
+            .chain(down)
+            .map(|h| h.address.clone())
+            .collect()
+    }
+
+    fn list(&self) -> Vec<ServerHealth> { self.0.lock().unwrap().clone() }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonitorStatus {
+    pub active: Option<String>,
+    pub servers: Vec<ServerHealth>,
+    pub miners_open: u64,
+    pub miners_total: u64,
+    pub updated_at: i64,
+}
+
+// A plain TCP connect. The handshake would make an encrypt_port transit dial
+// its pool for a miner that never comes.
+async fn probe(server: &str) -> std::result::Result<Duration, String> {
+    let start = std::time::Instant::now();
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(server)).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer in {:?}", CONNECT_TIMEOUT)),
+    }
+}
+
+async fn run_probes(config: Arc<MonitorConfig>, health: Arc<Health>, miners: Arc<Miners>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.probe_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let probes: Vec<_> = config
+            .servers
+            .iter()
+            .map(|server| {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let res = probe(&server).await;
+                    (server, res)
+                })
+            })
+            .collect();
+        for probe in probes {
+            if let Ok((server, res)) = probe.await {
+                health.set(&server, res);
+            }
+        }
+
+        if !config.status_file.is_empty() {
+            let (miners_open, miners_total) = miners.totals();
+            let status = MonitorStatus {
+                active: health.active(),
+                servers: health.list(),
+                miners_open,
+                miners_total,
+                updated_at: chrono::Utc::now().timestamp(),
+            };
+            if let Err(e) = write_status(&config.status_file, &status) {
+                tracing::warn!("Failed to write {}: {}", config.status_file, e);
+            }
+        }
+    }
+}
+
+fn write_status(path: &str, status: &MonitorStatus) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string_pretty(status)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
 
-pub async fn accept_monitor_tcp(port: i32, server: SocketAddr) -> Result<()> {
-    let address = format!("0.0.0.0:{}", port);
+pub async fn accept_monitor_tcp(config: MonitorConfig) -> Result<()> {
+    config.check()?;
+    let address = format!("0.0.0.0:{}", config.port);
     let listener = TcpListener::bind(address.clone()).await?;
     info!("😄 Accepting Monitor Tcp On: {}", &address);
 
+    let config = Arc::new(config);
+    let health = Arc::new(Health::new(&config.servers));
+    let tunnel = Arc::new(Tunnel {
+        config: config.clone(),
+        health: health.clone(),
+        mux: tokio::sync::Mutex::new(None),
+    });
+    let miners = Arc::new(Miners::default());
+    tokio::spawn(run_probes(config.clone(), health.clone(), miners.clone()));
+    if config.stats_secs != 0 {
+        let miners = miners.clone();
+        let every = Duration::from_secs(config.stats_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                miners.log();
+            }
+        });
+    }
+
     loop {
         let (stream, addr) = listener.accept().await?;
-        info!("😄 Accepting Monitor Tcp connection from {}", addr);
+        let count = miners.opened(addr.ip());
+        info!(
+            "😄 Accepting Monitor Tcp connection from {}, {} open of {}",
+            addr, count.open, count.total
+        );
+
+        let config = config.clone();
+        let health = health.clone();
+        let tunnel = tunnel.clone();
+        let miners = miners.clone();
+        tokio::spawn(async move {
+            let res = if config.mux {
+                transfer(stream, tunnel.open(addr)).await
+            } else {
+                transfer(stream, async { connect(&config, &health).await.0 }).await
+            };
+            if let Err(e) = res {
+                debug!("{} closed: {}", addr, e);
+            }
+            let count = miners.closed(addr.ip());
+            info!("Miner {} disconnected, {} still open", addr, count.open);
+        });
+    }
+}
+
+// The first server that answers, healthy ones first, waiting with backoff
+// for as long as none does
+async fn connect(config: &MonitorConfig, health: &Health) -> (AeadStream<TcpStream>, String) {
+    let mut backoff = Duration::from_secs(config.backoff_min_secs);
+    loop {
+        for server in health.order() {
+            let start = std::time::Instant::now();
+            let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&server))
+                .await
+            {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    health.set(&server, Err(e.to_string()));
+                    continue;
+                }
+                Err(_) => {
+                    health.set(&server, Err(format!("no answer in {:?}", CONNECT_TIMEOUT)));
+                    continue;
+                }
+            };
+            match AeadStream::connect(stream, &config.key).await {
+                Ok(stream) => {
+                    health.set(&server, Ok(start.elapsed()));
+                    if server != config.servers[0] {
+                        info!("Connected to fallback server {}", server);
+                    }
+                    return (stream, server);
+                }
+                Err(e) => {
+                    health.set(&server, Err(format!("encrypted handshake failed: {}", e)))
+                }
+            }
+        }
+
+        tracing::error!("No transit server answers, retrying in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = config.backoff(backoff);
+    }
+}
+
+// The session all miners share in `mux` mode, with the server it goes to.
+// It is dialed again once it drops, or for new miners once a server before
+// it in the list is healthy again. The old one ends with its last miner.
+struct Tunnel {
+    config: Arc<MonitorConfig>,
+    health: Arc<Health>,
+    mux: tokio::sync::Mutex<Option<(Mux, String)>>,
+}
 
-        tokio::spawn(async move { transfer(stream, server).await });
+impl Tunnel {
+    async fn open(&self, miner: SocketAddr) -> DuplexStream {
+        loop {
+            let mut mux = self.mux.lock().await;
+            let active = self.health.active();
+            let current = match mux.as_ref() {
+                Some((m, server)) if !m.is_closed() => {
+                    active.is_none() || active.as_ref() == Some(server)
+                }
+                _ => false,
+            };
+            if !current {
+                let (stream, server) = connect(&self.config, &self.health).await;
+                info!("Tunnel connected to {}", server);
+                *mux = Some((Mux::client(stream), server));
+            }
+            match mux.as_ref().unwrap().0.open(&miner.to_string()).await {
+                Ok(stream) => return stream,
+                Err(e) => tracing::warn!("{}, connecting again", e),
+            }
+        }
     }
 }
 
-async fn transfer(stream: TcpStream, addr: SocketAddr) -> Result<()> {
-    let (worker_r, mut worker_w) = tokio::io::split(stream);
-    let worker_r = tokio::io::BufReader::new(worker_r);
-    let mut worker_r = worker_r.lines();
+// `upstream` gives the connection to the transit, once it has one
+async fn transfer<S, F>(stream: TcpStream, upstream: F) -> Result<()>
+where
+    F: Future<Output = S>,
+    S: AsyncRead + AsyncWrite,
+{
+    let (worker_r, mut worker_w) = split(stream);
+    let mut worker_r = BufReader::new(worker_r).lines();
 
-    let std_stream = match std::net::TcpStream::connect_timeout(
-        &addr,
-        Duration::new(5, 0),
-    ) {
-        Ok(stream) => stream,
-        Err(_) => {
-            //info!("{} The remote address is unavailable!", addr);
-            //std::process::exit(1);
-            bail!("{} The remote address is unavailable!", addr);
+    // Lines the miner sends while the server is dialed, its login among them
+    let mut queued = vec![];
+    let pool_stream = {
+        let connect = upstream;
+        tokio::pin!(connect);
+        loop {
+            select! {
+                stream = &mut connect => break stream,
+                line = worker_r.next_line() => match line? {
+                    Some(line) => queued.push(line),
+                    None => bail!("Miner is offline"),
+                },
+            }
         }
     };
 
-    std_stream.set_nonblocking(true).unwrap();
-    let pool_stream = TcpStream::from_std(std_stream)?;
-    let (pool_r, mut pool_w) = tokio::io::split(pool_stream);
-    let pool_r = tokio::io::BufReader::new(pool_r);
-    let mut pool_r = pool_r.split(crate::SPLIT);
-    let mut client_timeout_sec = 1;
+    let (pool_r, mut pool_w) = split(pool_stream);
+    let mut pool_r = BufReader::new(pool_r).split(crate::SPLIT);
+    let mut client_timeout = if queued.is_empty() {
+        FIRST_LINE_TIMEOUT
+    } else {
+        MINER_TIMEOUT
+    };
+    for line in queued {
+        self_write_socket_byte(&mut pool_w, line.into_bytes(), &"encryption".to_string())
+            .await?;
+    }
 
     loop {
         select! {
-            res = tokio::time::timeout(std::time::Duration::new(client_timeout_sec,0), worker_r.next_line()) => {
-                //let start = std::time::Instant::now();
-                let buffer = match res{
-                    Ok(res) => {
-                        match res {
-                            Ok(buf) => match buf{
-                                    Some(buf) => buf,
-                                    None =>       {
-                                        match pool_w.shutdown().await  {
-                                            Ok(_) => {},
-                                            Err(e) => {
-                                                tracing::error!("Error Shutdown Socket {:?}",e);
-                                            },
-                                        };
-                                        info!("Miner is offline");
-                                        bail!("Miner is offline")
-                                    },
-                                },
-                            _ => {
-                                   match pool_w.shutdown().await  {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        tracing::error!("Error Shutdown Socket {:?}",e);
-                                    },
-                                };
-                                info!("Miner is offline");
-                                bail!("Miner is offline")
-                            },
+            res = tokio::time::timeout(client_timeout, worker_r.next_line()) => {
+                let buffer = match res {
+                    Ok(Ok(Some(buf))) => buf,
+                    Ok(_) => {
+                        if let Err(e) = pool_w.shutdown().await {
+                            tracing::error!("Error Shutdown Socket {:?}", e);
                         }
-                    },
+                        info!("Miner is offline");
+                        bail!("Miner is offline")
+                    }
                     Err(e) => {
-                           match pool_w.shutdown().await  {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        tracing::error!("Error Shutdown Socket {:?}",e);
-                                    },
-                                };
-                        bail!("The read timed out and the miner is offline: {}",e)},
+                        if let Err(e) = pool_w.shutdown().await {
+                            tracing::error!("Error Shutdown Socket {:?}", e);
+                        }
+                        bail!("The read timed out and the miner is offline: {}", e)
+                    }
                 };
+                client_timeout = MINER_TIMEOUT;
 
-                if client_timeout_sec == 1 {
-                    client_timeout_sec = 60;
-                }
-
-                //#[cfg(debug_assertions)]
                 debug!("------> :  Miner -> Mining Pool  {:?}", buffer);
-                let buffer: Vec<_> = buffer.split("\n").collect();
-                for buf in buffer {
+                for buf in buffer.split('\n') {
                     if buf.is_empty() {
                         continue;
                     }
-                    // let key = Vec::from_hex(key).unwrap();
-                    // let mut iv = Vec::from_hex(iv).unwrap();
-                    // 加密
-                    //let key = AesKey::new_encrypt(&key).unwrap();
-                    //let plain_text = buf.to_string().as_bytes();
-                    //let mut output = buf.as_bytes().to_vec().clone();
-
-                    // let cipher = Cipher::aes_256_cbc();
-                    // //let data = b"Some Crypto String";
-                    // let ciphertext = encrypt(
-                    //     cipher,
-                    //     &key,
-                    //     Some(&iv),
-                    //     buf.as_bytes()).unwrap();
-
-                    // info!("{:?}",ciphertext);
-
-                    // let base64 = base64::encode(&ciphertext[..]);
-                    // let write_len = w.write(&base64.as_bytes()).await?;
-
-                    match self_write_socket_byte(&mut pool_w,buf.as_bytes().to_vec(),&"encryption".to_string()).await{
-                        Ok(_) => {},
-                        Err(e) => {info!("{}",e);bail!("The miner is offline {}",e)}
+                    if let Err(e) = self_write_socket_byte(&mut pool_w, buf.as_bytes().to_vec(), &"encryption".to_string()).await {
+                        info!("{}", e);
+                        bail!("The miner is offline {}", e)
                     }
                 }
             },
             res = pool_r.next_segment() => {
-                //let start = std::time::Instant::now();
-                let buffer = match res{
-                    Ok(res) => {
-                        match res {
-                            Some(buf) => buf,
-                            None => {
-                                match worker_w.shutdown().await{
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        tracing::error!("Error Shutdown Socket {:?}",e);
-                                    },
-                                };
-                                info!("Miner is offline");
-                                bail!("Miner is offline")
-                            }
+                let buffer = match res {
+                    Ok(Some(buf)) => buf,
+                    Ok(None) => {
+                        if let Err(e) = worker_w.shutdown().await {
+                            tracing::error!("Error Shutdown Socket {:?}", e);
                         }
-                    },
-                    Err(e) => {info!("Miner is offline");bail!("Miner is offline: {}",e)},
+                        info!("Miner is offline");
+                        bail!("Miner is offline")
+                    }
+                    Err(e) => {
+                        info!("Miner is offline");
+                        bail!("Miner is offline: {}", e)
+                    }
                 };
 
-
-
-
-                let buffer = buffer[0..buffer.len()].split(|c| *c == crate::SPLIT);
-                for buf in buffer {
+                for buf in buffer.split(|c| *c == crate::SPLIT) {
                     if buf.is_empty() {
                         continue;
                     }
-
-                    //#[cfg(debug_assertions)]
-                    debug!("<------ :  Mining Pool -> Mining Machine  {}", String::from_utf8(buf.to_vec()).unwrap());
-
-                    match write_to_socket_byte(&mut worker_w,buf.to_vec(),&"decrypt".to_string()).await{
-                        Ok(_) => {},
-                        Err(e) => {info!("{}",e);bail!("The miner is offline {}",e)}
+                    debug!("<------ :  Mining Pool -> Mining Machine  {}", String::from_utf8_lossy(buf));
+                    if let Err(e) = write_to_socket_byte(&mut worker_w, buf.to_vec(), &"decrypt".to_string()).await {
+                        info!("{}", e);
+                        bail!("The miner is offline {}", e)
                     }
                 }
             }
         }
     }
 }
+
+#[test]
+fn test_monitor_tunnel() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        // A transit that echoes every line
+        let transit = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let transit_addr = transit.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = transit.accept().await.unwrap();
+            let stream = AeadStream::accept(stream, "0123456789abcdef").await.unwrap();
+            let (r, mut w) = split(stream);
+            let mut lines = BufReader::new(r).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                w.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+                w.flush().await.unwrap();
+            }
+        });
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down_addr = down.local_addr().unwrap();
+        drop(down);
+
+        let config = MonitorConfig {
+            port: 1,
+            key: "0123456789abcdef".into(),
+            servers: vec![down_addr.to_string(), transit_addr.to_string()],
+            ..Default::default()
+        };
+        let health = Arc::new(Health::new(&config.servers));
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = local.local_addr().unwrap();
+        let miner_health = health.clone();
+        tokio::spawn(async move {
+            let (stream, _) = local.accept().await.unwrap();
+            let _ = transfer(stream, async { connect(&config, &miner_health).await.0 }).await;
+        });
+
+        let mut miner = TcpStream::connect(local_addr).await.unwrap();
+        miner.write_all(b"{\"id\":1,\"method\":\"eth_submitLogin\"}\n").await.unwrap();
+        let mut lines = BufReader::new(miner).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "{\"id\":1,\"method\":\"eth_submitLogin\"}"
+        );
+        // The next miner goes straight to the server that answered
+        assert_eq!(health.active(), Some(transit_addr.to_string()));
+        assert_eq!(health.order(), vec![transit_addr.to_string(), down_addr.to_string()]);
+        health.set(&down_addr.to_string(), Ok(Duration::from_millis(3)));
+        assert_eq!(health.active(), Some(down_addr.to_string()));
+
+        // Two miners over one tunnel
+        let transit = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let transit_addr = transit.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = transit.accept().await.unwrap();
+            let stream = AeadStream::accept(stream, "0123456789abcdef").await.unwrap();
+            let (_mux, mut streams) = Mux::server(stream);
+            while let Some((stream, miner)) = streams.recv().await {
+                tokio::spawn(async move {
+                    let (r, mut w) = split(stream);
+                    let mut lines = BufReader::new(r).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        w.write_all(format!("{} {}\n", miner, line).as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        let servers = vec![transit_addr.to_string()];
+        let tunnel = Tunnel {
+            health: Arc::new(Health::new(&servers)),
+            config: Arc::new(MonitorConfig {
+                port: 1,
+                key: "0123456789abcdef".into(),
+                servers,
+                mux: true,
+                ..Default::default()
+            }),
+            mux: tokio::sync::Mutex::new(None),
+        };
+        for miner in ["10.0.0.1:1000", "10.0.0.2:1000"] {
+            let mut stream = tunnel.open(miner.parse().unwrap()).await;
+            stream.write_all(b"login\n").await.unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            assert_eq!(
+                lines.next_line().await.unwrap().unwrap(),
+                format!("{} login", miner)
+            );
+        }
+    });
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use tokio::{
+    io::{split, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::RwLockReadGuard,
+};
+use tracing::info;
+
+use crate::{
+    state::Worker,
+    util::{aead::AeadStream, config::Settings, mux::Mux},
+};
+
+use super::*;
+pub async fn accept_mux_tcp(proxy: Arc<Proxy>) -> Result<()> {
+    let config: Settings;
+    {
+        let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
+        config = rconfig.clone();
+    }
+
+    if config.mux_port == 0 {
+        return Ok(());
+    }
+
+    let address = format!("0.0.0.0:{}", config.mux_port);
+    let listener = match TcpListener::bind(address.clone()).await {
+        Ok(listener) => listener,
+        Err(_) => {
+            tracing::info!("Local port is occupied {}", address);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("Local TCP tunnel port {} started successfully!!!", &address);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if proxy.is_draining() {
+            debug!("IP: {} refused while draining", addr);
+            continue;
+        }
+
+        let p = Arc::clone(&proxy);
+        tokio::spawn(async move {
+            if let Err(e) = tunnel(p, stream, addr).await {
+                debug!("Tunnel {} broken: {}", addr, e);
+            }
+        });
+    }
+}
+
+// Every stream of the tunnel is a miner of its own
+async fn tunnel(proxy: Arc<Proxy>, tcp_stream: TcpStream, addr: SocketAddr) -> Result<()> {
+    // Read per connection, the key can be reloaded
+    let key = proxy.config.read().await.encrypt_key.clone();
+    let stream = AeadStream::accept(tcp_stream, &key).await?;
+    let (_mux, mut streams) = Mux::server(stream);
+    info!("Tunnel {} connected", addr);
+
+    while let Some((stream, miner)) = streams.recv().await {
+        if proxy.is_draining() {
+            debug!("IP: {} refused while draining", miner);
+            continue;
+        }
+
+        let p = Arc::clone(&proxy);
+        tokio::spawn(async move {
+            // Miner Status Management
+            let mut worker: Worker = Worker::default();
+            // The address the monitor took the miner from
+            worker.ip = match miner.parse::<SocketAddr>() {
+                Ok(miner) => miner.ip().to_string(),
+                Err(_) => addr.ip().to_string(),
+            };
+            let worker_tx = p.worker_tx.clone();
+            let (worker_r, worker_w) = split(stream);
+            let worker_r = BufReader::new(worker_r);
+            match handle_tcp_random(&mut worker, worker_r, worker_w, p, true).await {
+                Ok(_) => {
+                    if worker.is_online() {
+                        worker.offline();
+                        info!("IP: {} safe offline", miner);
+                        worker_tx.send(worker).unwrap();
+                    } else {
+                        info!("IP: {} offline", miner);
+                    }
+                }
+                Err(e) => {
+                    if worker.is_online() {
+                        worker.offline();
+                        worker_tx.send(worker).unwrap();
+                        info!("IP: {} Downtime Reason {}", miner, e);
+                    } else {
+                        debug!("IP: {} Malicious link broken: {}", miner, e);
+                    }
+                }
+            }
+        });
+    }
+
+    info!("Tunnel {} closed", addr);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Ids handed to the pool for forwarded requests. They stay clear of the
+// fixed ids in `crate::protocol` so replies can be told apart.
+const FIRST_ID: u64 = 100_000;
+const LAST_ID: u64 = u32::MAX as u64;
+
+// How long a share may wait for the pool before the miner is told it was
+// rejected.
+pub const SUBMIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+struct PendingRequest<T> {
+    request: T,
+    sent: Instant,
+}
+
+// Requests forwarded to the pool that still wait for an answer, keyed by the
+// id the pool saw.
+#[derive(Debug)]
+pub struct PendingRequests<T> {
+    next_id: u64,
+    requests: HashMap<u64, PendingRequest<T>>,
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new() -> Self {
+        Self {
+            next_id: FIRST_ID,
+            requests: HashMap::new(),
+        }
+    }
+
+    // Remember a request and return the id to send to the pool instead of
+    // the one the miner used.
+    pub fn insert(&mut self, request: T) -> u64 {
+        let id = self.next_id;
+        self.next_id = if id >= LAST_ID { FIRST_ID } else { id + 1 };
+
+        self.requests.insert(
+            id,
+            PendingRequest {
+                request,
+                sent: Instant::now(),
+            },
+        );
+        id
+    }
+
+    pub fn take(&mut self, id: u64) -> Option<T> {
+        self.requests.remove(&id).map(|r| r.request)
+    }
+
+    // Remove and return the requests older than `timeout`.
+    pub fn expire(&mut self, timeout: Duration) -> Vec<T> {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .requests
+            .iter()
+            .filter(|(_, r)| now.duration_since(r.sent) >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired
+            .iter()
+            .filter_map(|id| self.requests.remove(id))
+            .map(|r| r.request)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize { self.requests.len() }
+
+    pub fn is_empty(&self) -> bool { self.requests.is_empty() }
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self { Self::new() }
+}
+
+#[test]
+fn test_pending_requests() {
+    let mut pending = PendingRequests::new();
+    let first = pending.insert(40);
+    let second = pending.insert(40);
+    assert_ne!(first, second);
+    assert!(first >= FIRST_ID);
+
+    assert_eq!(pending.take(first), Some(40));
+    assert!(pending.take(first).is_none());
+
+    assert!(pending.expire(SUBMIT_TIMEOUT).is_empty());
+    let expired = pending.expire(Duration::from_secs(0));
+    assert_eq!(expired.len(), 1);
+    assert!(pending.is_empty());
+}
@@ -0,0 +1,508 @@
+use std::{
+    net::SocketAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, Lines, ReadHalf,
+        WriteHalf,
+    },
+    net::TcpStream,
+    sync::watch,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use super::{pool_tls::PoolTls, write_to_socket, SSL, TCP};
+use crate::protocol::ethjson::{EthClientRootObject, EthServerResponse};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Consecutive failures before a pool is taken out of rotation
+const FAILURE_THRESHOLD: u32 = 3;
+// How long a broken pool stays out of rotation before it is tried again
+const OPEN_TIME: Duration = Duration::from_secs(60);
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub trait PoolStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> PoolStream for T {}
+
+pub type BoxPoolStream = Box<dyn PoolStream>;
+pub type PoolLines = Lines<BufReader<ReadHalf<BoxPoolStream>>>;
+pub type PoolWriter = WriteHalf<BoxPoolStream>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolEndpoint {
+    pub url: String,
+    // host:port
+    pub address: String,
+    pub stream_type: i32,
+    // Lower is preferred
+    pub priority: u32,
+    // Share of new connections among pools of the same priority
+    pub weight: u32,
+    pub tls: PoolTls,
+}
+
+// Parse `tcp://host:port` or `ssl://host:port` with optional
+// `?priority=0&weight=1`. Without a priority the position in the list is
+// used so pools are tried in the configured order. `ssl://` pools also take
+// the TLS options of `PoolTls`, e.g. `?sni=eth.pool.com&pin=<sha256>`.
+pub fn parse_pool_url(url: &str, idx: usize) -> Result<PoolEndpoint> {
+    let (scheme, rest) = match url.split_once("://") {
+        Some(s) => s,
+        None => bail!("Pool {} has no service type", url),
+    };
+
+    let stream_type = match scheme.to_lowercase().as_str() {
+        "tcp" => TCP,
+        "ssl" => SSL,
+        _ => bail!("Pool {} does not support service type {}", url, scheme),
+    };
+
+    let (address, query) = match rest.split_once('?') {
+        Some((address, query)) => (address, query),
+        None => (rest, ""),
+    };
+
+    if address.is_empty() || !address.contains(':') {
+        bail!("Pool {} address must be host:port", url);
+    }
+
+    let mut priority = idx as u32;
+    let mut weight = 1;
+    let mut tls = PoolTls::default();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("priority", v)) => priority = v.parse()?,
+            Some(("weight", v)) => weight = v.parse()?,
+            Some((k, v)) if tls.set_option(k, v)? => {}
+            _ => bail!("Pool {} unknown option {}", url, pair),
+        }
+    }
+
+    if weight == 0 {
+        bail!("Pool {} weight must be greater than 0", url);
+    }
+    if stream_type == TCP && !tls.is_default() {
+        bail!("Pool {} has TLS options but is not an ssl:// pool", url);
+    }
+
+    Ok(PoolEndpoint {
+        url: url.to_string(),
+        address: address.to_string(),
+        stream_type,
+        priority,
+        weight,
+        tls,
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolHealth {
+    pub failures: u32,
+    // Set while the circuit is open
+    pub open_until: Option<Instant>,
+    pub latency: Option<Duration>,
+    pub last_error: String,
+    // Counters since the transit started
+    pub failures_total: u64,
+    pub circuit_opens: u64,
+    pub connects: u64,
+}
+
+impl PoolHealth {
+    pub fn is_available(&self, now: Instant) -> bool {
+        !matches!(self.open_until, Some(t) if now < t)
+    }
+}
+
+// Health of one pool as the transit reports it to the supervisor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoolStatus {
+    // Name of the pool list, e.g. `proxy pool` or `route a`
+    pub group: String,
+    pub pool: String,
+    pub up: bool,
+    pub failures_total: u64,
+    pub circuit_opens: u64,
+    pub connects: u64,
+}
+
+// Hands out upstream connections for a list of pools. Pools are tried by
+// priority, weighted at random within a priority, and pools that keep
+// failing are skipped until their circuit closes again.
+pub struct PoolManager {
+    name: String,
+    // Replaced as a whole on reload
+    pools: RwLock<Vec<PoolEndpoint>>,
+    // Same order as `pools`
+    health: Mutex<Vec<PoolHealth>>,
+    // Priority of the best pool currently available
+    preferred: watch::Sender<u32>,
+}
+
+impl PoolManager {
+    pub fn new(name: &str, urls: &[String]) -> Result<Self> {
+        let pools = parse_pools(name, urls)?;
+        let best = pools.iter().map(|p| p.priority).min().unwrap_or_default();
+        let (preferred, _) = watch::channel(best);
+
+        Ok(Self {
+            name: name.to_string(),
+            health: Mutex::new(vec![PoolHealth::default(); pools.len()]),
+            pools: RwLock::new(pools),
+            preferred,
+        })
+    }
+
+    // New pool list for the next connections. Open connections stay where
+    // they are, pools kept in the list keep their health.
+    pub fn set_pools(&self, urls: &[String]) -> Result<()> {
+        let new = parse_pools(&self.name, urls)?;
+        {
+            let mut pools = self.pools.write().unwrap();
+            if *pools == new {
+                return Ok(());
+            }
+            let mut health = self.health.lock().unwrap();
+            *health = new
+                .iter()
+                .map(|p| match pools.iter().position(|old| old.url == p.url) {
+                    Some(idx) => health[idx].clone(),
+                    None => PoolHealth::default(),
+                })
+                .collect();
+            *pools = new;
+        }
+        info!("{} pools reloaded: {}", self.name, urls.join(","));
+        self.update_preferred();
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn endpoints(&self) -> Vec<PoolEndpoint> { self.pools.read().unwrap().clone() }
+
+    pub fn health(&self) -> Vec<PoolHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub fn status(&self) -> Vec<PoolStatus> {
+        let now = Instant::now();
+        let pools = self.pools.read().unwrap();
+        let health = self.health.lock().unwrap();
+        pools
+            .iter()
+            .zip(health.iter())
+            .map(|(p, h)| PoolStatus {
+                group: self.name.clone(),
+                pool: p.address.clone(),
+                up: h.is_available(now),
+                failures_total: h.failures_total,
+                circuit_opens: h.circuit_opens,
+                connects: h.connects,
+            })
+            .collect()
+    }
+
+    // Changes whenever a pool with a better priority becomes available, so
+    // long lived connections can fail back.
+    pub fn subscribe(&self) -> watch::Receiver<u32> { self.preferred.subscribe() }
+
+    // Order in which pools are tried for a new connection
+    pub fn candidates(&self) -> Vec<PoolEndpoint> {
+        let now = Instant::now();
+        let pools = self.pools.read().unwrap();
+        let health = self.health.lock().unwrap();
+        let mut rng = rand::thread_rng();
+
+        let mut order: Vec<(bool, u32, f64, usize)> = pools
+            .iter()
+            .enumerate()
+            .map(|(idx, p)| {
+                // Weighted random order: smaller key is picked first
+                let key = -rng.gen::<f64>().ln() / p.weight as f64;
+                (!health[idx].is_available(now), p.priority, key, idx)
+            })
+            .collect();
+
+        order.sort_by(|a, b| {
+            (a.0, a.1)
+                .cmp(&(b.0, b.1))
+                .then(a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+        });
+
+        order.into_iter().map(|(_, _, _, idx)| pools[idx].clone()).collect()
+    }
+
+    pub async fn connect(&self) -> Result<(BoxPoolStream, PoolEndpoint)> {
+        for endpoint in self.candidates() {
+            let start = Instant::now();
+            match connect_endpoint(&endpoint).await {
+                Ok(stream) => {
+                    self.record_success(&endpoint, start.elapsed());
+                    self.health_of(&endpoint, |h| h.connects += 1);
+                    debug!("{} connected to {}", self.name, endpoint.address);
+                    return Ok((stream, endpoint));
+                }
+                Err(e) => {
+                    self.record_failure(&endpoint, e.to_string());
+                }
+            }
+        }
+
+        bail!("{}: all pools are unlinkable. Please modify and try again", self.name)
+    }
+
+    pub async fn connect_lines(&self) -> Result<(PoolLines, PoolWriter, PoolEndpoint)> {
+        let (stream, endpoint) = self.connect().await?;
+        let (pool_r, pool_w) = tokio::io::split(stream);
+        Ok((BufReader::new(pool_r).lines(), pool_w, endpoint))
+    }
+
+    // Pools dropped by a reload in the meantime are not recorded
+    fn health_of<F>(&self, endpoint: &PoolEndpoint, f: F)
+    where F: FnOnce(&mut PoolHealth) {
+        {
+            let pools = self.pools.read().unwrap();
+            if let Some(idx) = pools.iter().position(|p| p.url == endpoint.url) {
+                f(&mut self.health.lock().unwrap()[idx]);
+            }
+        }
+        self.update_preferred();
+    }
+
+    fn record_success(&self, endpoint: &PoolEndpoint, latency: Duration) {
+        self.health_of(endpoint, |h| {
+            if h.open_until.is_some() {
+                info!("{} pool {} recovered", self.name, endpoint.address);
+            }
+            h.failures = 0;
+            h.open_until = None;
+            h.latency = Some(latency);
+            h.last_error.clear();
+        });
+    }
+
+    fn record_failure(&self, endpoint: &PoolEndpoint, error: String) {
+        self.health_of(endpoint, |h| {
+            h.failures += 1;
+            h.failures_total += 1;
+            h.latency = None;
+            if h.failures >= FAILURE_THRESHOLD {
+                if !matches!(h.open_until, Some(t) if Instant::now() < t) {
+                    h.circuit_opens += 1;
+                    warn!(
+                        "{} pool {} failed {} times, skipped for {:?}: {}",
+                        self.name,
+                        endpoint.address,
+                        h.failures,
+                        OPEN_TIME,
+                        error
+                    );
+                }
+                h.open_until = Some(Instant::now() + OPEN_TIME);
+            } else {
+                debug!("{} pool {} failed: {}", self.name, endpoint.address, error);
+            }
+            h.last_error = error;
+        });
+    }
+
+    fn update_preferred(&self) {
+        let now = Instant::now();
+        let best = {
+            let pools = self.pools.read().unwrap();
+            let health = self.health.lock().unwrap();
+            pools
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| health[*idx].is_available(now))
+                .map(|(_, p)| p.priority)
+                .min()
+        };
+
+        if let Some(best) = best {
+            if *self.preferred.borrow() != best {
+                self.preferred.send_replace(best);
+            }
+        }
+    }
+
+    // Log in to one pool and wait for its answer.
+    async fn probe(&self, endpoint: &PoolEndpoint, login: &EthClientRootObject) -> Result<()> {
+        let stream = connect_endpoint(endpoint).await?;
+        let (pool_r, mut pool_w) = tokio::io::split(stream);
+        let mut lines = BufReader::new(pool_r).lines();
+
+        write_to_socket(&mut pool_w, login, &self.name).await?;
+
+        let answer = timeout(CONNECT_TIMEOUT, async {
+            while let Some(line) = lines.next_line().await? {
+                if let Ok(rpc) = serde_json::from_str::<EthServerResponse>(&line) {
+                    if rpc.id == login.id {
+                        return Ok(rpc);
+                    }
+                }
+            }
+            bail!("connection closed during login")
+        })
+        .await;
+
+        match answer {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e),
+            Err(_) => bail!("no answer to login within {:?}", CONNECT_TIMEOUT),
+        }
+    }
+
+    // Probe every pool forever. A pool counts as healthy when it answers the
+    // login, whatever the verdict on the wallet.
+    pub async fn run_health_checks(
+        &self, login: EthClientRootObject,
+    ) -> Result<()> {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            for endpoint in self.endpoints() {
+                let start = Instant::now();
+                match self.probe(&endpoint, &login).await {
+                    Ok(()) => self.record_success(&endpoint, start.elapsed()),
+                    Err(e) => self.record_failure(&endpoint, e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+fn parse_pools(name: &str, urls: &[String]) -> Result<Vec<PoolEndpoint>> {
+    if urls.is_empty() {
+        bail!("{} has no pool address", name);
+    }
+    urls.iter()
+        .enumerate()
+        .map(|(idx, url)| parse_pool_url(url, idx))
+        .collect()
+}
+
+pub async fn connect_endpoint(endpoint: &PoolEndpoint) -> Result<BoxPoolStream> {
+    let addrs: Vec<SocketAddr> =
+        match timeout(CONNECT_TIMEOUT, tokio::net::lookup_host(&endpoint.address))
+            .await
+        {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => bail!("resolve {} failed: {}", endpoint.address, e),
+            Err(_) => bail!("resolve {} timed out", endpoint.address),
+        };
+
+    let mut last_error = format!("{} resolved to no address", endpoint.address);
+    for addr in addrs {
+        let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                last_error = format!("connect {} failed: {}", addr, e);
+                continue;
+            }
+            Err(_) => {
+                last_error = format!("connect {} timed out", addr);
+                continue;
+            }
+        };
+        stream.set_nodelay(true)?;
+
+        if endpoint.stream_type == SSL {
+            return Ok(Box::new(tls_connect(endpoint, stream).await?));
+        }
+        return Ok(Box::new(stream));
+    }
+
+    bail!(last_error)
+}
+
+async fn tls_connect(
+    endpoint: &PoolEndpoint, stream: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let cx = endpoint.tls.connector()?;
+    let host = endpoint
+        .address
+        .rsplit_once(':')
+        .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+        .unwrap_or_default();
+    let domain = endpoint.tls.server_name(host)?;
+
+    match timeout(CONNECT_TIMEOUT, cx.connect(domain, stream)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => bail!("SSL link to {} failed: {}", endpoint.address, e),
+        Err(_) => bail!("SSL link to {} timed out", endpoint.address),
+    }
+}
+
+#[test]
+fn test_parse_pool_url() {
+    let p = parse_pool_url("ssl://asia1.ethermine.org:5555", 2).unwrap();
+    assert_eq!(p.address, "asia1.ethermine.org:5555");
+    assert_eq!(p.stream_type, SSL);
+    assert_eq!(p.priority, 2);
+    assert_eq!(p.weight, 1);
+
+    let p = parse_pool_url("tcp://eth.f2pool.com:6688?priority=0&weight=3", 1)
+        .unwrap();
+    assert_eq!(p.address, "eth.f2pool.com:6688");
+    assert_eq!(p.stream_type, TCP);
+    assert_eq!(p.priority, 0);
+    assert_eq!(p.weight, 3);
+
+    assert!(parse_pool_url("udp://eth.f2pool.com:6688", 0).is_err());
+    assert!(parse_pool_url("tcp://eth.f2pool.com:6688?weight=0", 0).is_err());
+    assert!(parse_pool_url("tcp://eth.f2pool.com:6688?verify=false", 0).is_err());
+
+    let p = parse_pool_url("ssl://10.0.0.2:5555?sni=eth.f2pool.com&min_tls=1.3", 0)
+        .unwrap();
+    assert!(p.tls.verify);
+    assert_eq!(p.tls.sni, "eth.f2pool.com");
+    assert_eq!(p.tls.min_version, "1.3");
+}
+
+#[test]
+fn test_pool_manager_circuit() {
+    let pools = PoolManager::new(
+        "test",
+        &["tcp://127.0.0.1:1".into(), "tcp://127.0.0.1:2".into()],
+    )
+    .unwrap();
+    let preferred = pools.subscribe();
+    let order = |pools: &PoolManager| -> Vec<String> {
+        pools.candidates().into_iter().map(|p| p.address).collect()
+    };
+    let first = pools.endpoints()[0].clone();
+    assert_eq!(order(&pools), vec!["127.0.0.1:1", "127.0.0.1:2"]);
+
+    for _ in 0..FAILURE_THRESHOLD {
+        pools.record_failure(&first, "refused".into());
+    }
+    assert_eq!(order(&pools), vec!["127.0.0.1:2", "127.0.0.1:1"]);
+    assert_eq!(*preferred.borrow(), 1);
+    let status = pools.status();
+    assert!(!status[0].up && status[1].up);
+    assert_eq!(status[0].failures_total, FAILURE_THRESHOLD as u64);
+    assert_eq!(status[0].circuit_opens, 1);
+
+    pools.record_success(&first, Duration::from_millis(5));
+    assert_eq!(order(&pools), vec!["127.0.0.1:1", "127.0.0.1:2"]);
+    assert_eq!(*preferred.borrow(), 0);
+
+    // A reload keeps the health of the pools that stay
+    for _ in 0..FAILURE_THRESHOLD {
+        pools.record_failure(&first, "refused".into());
+    }
+    pools
+        .set_pools(&["tcp://127.0.0.1:3".into(), "tcp://127.0.0.1:1".into()])
+        .unwrap();
+    assert_eq!(order(&pools), vec!["127.0.0.1:3", "127.0.0.1:1"]);
+    assert!(pools.health()[1].open_until.is_some());
+    assert!(pools.set_pools(&[]).is_err());
+}
@@ -0,0 +1,306 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::{
+            ServerCertVerified, ServerCertVerifier, ServerName, WebPkiVerifier,
+        },
+        Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore,
+    },
+    TlsConnector,
+};
+
+// Name sent when nothing can be verified against the host, e.g. pinned pools
+// addressed by IP.
+const UNVERIFIED_NAME: &str = "pool.invalid";
+
+// TLS options of one pool, taken from the `ssl://` url options
+// `verify`, `ca`, `sni`, `pin` and `min_tls`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolTls {
+    // Check the certificate chain and the host name
+    pub verify: bool,
+    // PEM bundle trusted in addition to the built-in roots
+    pub ca: String,
+    // Name sent in SNI and checked against the certificate instead of the host
+    pub sni: String,
+    // SHA-256 of the certificate's SubjectPublicKeyInfo. Any of them matches.
+    pub pins: Vec<[u8; 32]>,
+    // "1.2" or "1.3"
+    pub min_version: String,
+    // Built on the first dial and shared by the clones of the endpoint
+    config: ConfigCache,
+}
+
+#[derive(Clone, Default)]
+struct ConfigCache(Arc<Mutex<Option<Arc<ClientConfig>>>>);
+
+// Not part of the options
+impl PartialEq for ConfigCache {
+    fn eq(&self, _: &Self) -> bool { true }
+}
+
+impl fmt::Debug for ConfigCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("ConfigCache") }
+}
+
+impl Default for PoolTls {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            ca: "".into(),
+            sni: "".into(),
+            pins: vec![],
+            min_version: "1.2".into(),
+            config: ConfigCache::default(),
+        }
+    }
+}
+
+impl PoolTls {
+    // Apply one url option. Returns false for options that are not TLS ones.
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<bool> {
+        match key {
+            "verify" => self.verify = value.parse()?,
+            "ca" => self.ca = value.to_string(),
+            "sni" => self.sni = value.to_string(),
+            "pin" => self.pins.push(parse_pin(value)?),
+            "min_tls" => match value {
+                "1.2" | "1.3" => self.min_version = value.to_string(),
+                _ => bail!("TLS version {} is not supported, use 1.2 or 1.3", value),
+            },
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    pub fn is_default(&self) -> bool { *self == Self::default() }
+
+    // Name to connect with. An IP address cannot be verified, so it needs an
+    // `sni` or a pin with verification turned off.
+    pub fn server_name(&self, host: &str) -> Result<ServerName> {
+        let name = if !self.sni.is_empty() {
+            self.sni.as_str()
+        } else if host.parse::<std::net::IpAddr>().is_ok() {
+            if self.verify {
+                bail!(
+                    "Pool {} is an IP address. Set sni= to the certificate name or use pin= with verify=false",
+                    host
+                );
+            }
+            UNVERIFIED_NAME
+        } else {
+            host
+        };
+
+        match ServerName::try_from(name) {
+            Ok(name) => Ok(name),
+            Err(_) => bail!("{} is not a valid TLS server name", name),
+        }
+    }
+
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let versions: &[&rustls::SupportedProtocolVersion] =
+            if self.min_version == "1.3" {
+                &[&rustls::version::TLS13]
+            } else {
+                &[&rustls::version::TLS13, &rustls::version::TLS12]
+            };
+
+        let webpki = if self.verify {
+            Some(WebPkiVerifier::new(self.root_store()?, None))
+        } else {
+            if self.pins.is_empty() {
+                tracing::warn!(
+                    "Pool certificate check is off and no pin is set. The link can be intercepted"
+                );
+            }
+            None
+        };
+
+        let mut config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)?
+            .with_custom_certificate_verifier(Arc::new(PoolCertVerifier {
+                webpki,
+                pins: self.pins.clone(),
+            }))
+            .with_no_client_auth();
+        config.enable_sni = self.verify || !self.sni.is_empty();
+
+        Ok(config)
+    }
+
+    // The roots and the `ca` bundle are read once per pool, not on every dial
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let mut cached = self.config.0.lock().unwrap();
+        let config = match cached.as_ref() {
+            Some(config) => config.clone(),
+            None => cached.insert(Arc::new(self.client_config()?)).clone(),
+        };
+        Ok(TlsConnector::from(config))
+    }
+
+    fn root_store(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(
+            webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }),
+        );
+
+        if !self.ca.is_empty() {
+            let file = match std::fs::File::open(&self.ca) {
+                Ok(f) => f,
+                Err(e) => bail!("CA bundle {} cannot be read: {}", self.ca, e),
+            };
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+            if certs.is_empty() {
+                bail!("CA bundle {} has no certificate", self.ca);
+            }
+            for cert in certs {
+                if let Err(e) = roots.add(&Certificate(cert)) {
+                    bail!("CA bundle {} is invalid: {}", self.ca, e);
+                }
+            }
+        }
+
+        Ok(roots)
+    }
+}
+
+struct PoolCertVerifier {
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PoolCertVerifier {
+    fn verify_server_cert(
+        &self, end_entity: &Certificate, intermediates: &[Certificate],
+        server_name: &ServerName, scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8], now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        if !self.pins.is_empty() {
+            let pin = spki_sha256(&end_entity.0)
+                .ok_or(rustls::Error::InvalidCertificateEncoding)?;
+            if !self.pins.contains(&pin) {
+                return Err(rustls::Error::General(format!(
+                    "certificate key {} is not pinned",
+                    hex::encode(pin)
+                )));
+            }
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Pins are the hex SHA-256 of the DER SubjectPublicKeyInfo, as printed by
+// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256`
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(pin.trim_start_matches("sha256:"))?;
+    match <[u8; 32]>::try_from(bytes.as_slice()) {
+        Ok(pin) => Ok(pin),
+        Err(_) => bail!("Pin {} is not a SHA-256 hash", pin),
+    }
+}
+
+pub fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let spki = spki(cert)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+    <[u8; 32]>::try_from(digest.as_ref()).ok()
+}
+
+// Split the next DER element off `buf`. Returns (content, rest).
+pub(crate) fn der_next(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_tag, rest) = buf.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = if len < 0x80 {
+        (len as usize, rest)
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+        (len, &rest[n..])
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some((&rest[..len], &rest[len..]))
+}
+
+// The encoded SubjectPublicKeyInfo of an X.509 certificate.
+pub(crate) fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = der_next(cert)?;
+    let (mut tbs, _) = der_next(cert)?;
+    // [0] version is optional
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.1;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_next(tbs)?.1;
+    }
+
+    let (_, rest) = der_next(tbs)?;
+    Some(&tbs[..tbs.len() - rest.len()])
+}
+
+#[test]
+fn test_pool_tls() {
+    let pem = b"-----BEGIN CERTIFICATE-----
+MIIBfTCCASOgAwIBAgIUNV47cq7bVIXn9+oKbs1nuxxcm4EwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJcG9vbC50ZXN0MB4XDTI2MTAxODExMjk1NVoXDTM2MTAxNTEx
+Mjk1NVowFDESMBAGA1UEAwwJcG9vbC50ZXN0MFkwEwYHKoZIzj0CAQYIKoZIzj0D
+AQcDQgAEZO9fCKG0G93SchfnBls9htXd4xKUfgTv4rJelb5LdHyXfWpPJA9yv7nq
+UhI4WgXZC5aEUMZnXw8dexS0WtTg7KNTMFEwHQYDVR0OBBYEFOi/Q2oT68RDixKN
+U4HzQYdcORO4MB8GA1UdIwQYMBaAFOi/Q2oT68RDixKNU4HzQYdcORO4MA8GA1Ud
+EwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhAMr5M52RjONQPkgHUuRZIw9D
+iynghCoACsBtn8tWAPDGAiAcYVBtkh/z9Dd1felhC4cGla45Z5BVqRECH8Q/VnlD
+ng==
+-----END CERTIFICATE-----
+";
+    let cert = rustls_pemfile::certs(&mut &pem[..]).unwrap().remove(0);
+    let pin = "762f060027c00118936588c874a554733e410912ab78ded5faeffb0de7fab103";
+    assert_eq!(spki_sha256(&cert), Some(parse_pin(pin).unwrap()));
+    assert!(spki_sha256(&cert[..40]).is_none());
+
+    let mut tls = PoolTls::default();
+    assert!(tls.server_name("asia1.ethermine.org").is_ok());
+    assert!(tls.server_name("10.0.0.2").is_err());
+    assert!(tls.set_option("min_tls", "1.1").is_err());
+    assert!(!tls.set_option("priority", "0").unwrap());
+
+    tls.set_option("verify", "false").unwrap();
+    tls.set_option("pin", pin).unwrap();
+    assert!(tls.server_name("10.0.0.2").is_ok());
+    assert!(tls.client_config().is_ok());
+}
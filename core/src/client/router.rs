@@ -0,0 +1,242 @@
+use std::{net::IpAddr, sync::Arc};
+
+use anyhow::{bail, Result};
+
+use super::pool_manager::PoolManager;
+use crate::{
+    protocol::ethjson::{EthClientObject, EthClientRootObject},
+    util::config::RouteRule,
+};
+
+pub struct Route {
+    pub rule: RouteRule,
+    cidr: Option<(IpAddr, u8)>,
+    pub pools: Arc<PoolManager>,
+}
+
+impl Route {
+    // Every condition that is set has to match
+    fn matches(&self, login: &str, worker_name: &str, ip: Option<IpAddr>) -> bool {
+        if !self.rule.wallet.is_empty() {
+            let wallet = login.split('.').next().unwrap_or_default();
+            if !glob_match(&self.rule.wallet, login)
+                && !glob_match(&self.rule.wallet, wallet)
+            {
+                return false;
+            }
+        }
+
+        if !self.rule.worker_prefix.is_empty()
+            && !worker_name.starts_with(self.rule.worker_prefix.as_str())
+        {
+            return false;
+        }
+
+        if let Some(cidr) = self.cidr {
+            match ip {
+                Some(ip) if cidr_contains(cidr, ip) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+// Picks the pools of a miner at login. Rules are tried in the configured
+// order and miners matching none of them stay on `pool_address`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(rules: &[RouteRule]) -> Result<Self> {
+        let mut routes = Vec::new();
+        for rule in rules {
+            if rule.name.is_empty() {
+                bail!("Route without a name");
+            }
+            if routes.iter().any(|r: &Route| r.rule.name == rule.name) {
+                bail!("Route {} is defined twice", rule.name);
+            }
+            if rule.wallet.is_empty()
+                && rule.worker_prefix.is_empty()
+                && rule.cidr.is_empty()
+            {
+                bail!("Route {} has no condition", rule.name);
+            }
+
+            let cidr = if rule.cidr.is_empty() {
+                None
+            } else {
+                Some(parse_cidr(&rule.cidr)?)
+            };
+            let pools = PoolManager::new(
+                &format!("route {}", rule.name),
+                &rule.pool_address,
+            )?;
+
+            routes.push(Route {
+                rule: rule.clone(),
+                cidr,
+                pools: Arc::new(pools),
+            });
+        }
+
+        Ok(Self { routes })
+    }
+
+    pub fn is_empty(&self) -> bool { self.routes.is_empty() }
+
+    pub fn routes(&self) -> &[Route] { &self.routes }
+
+    pub fn route(
+        &self, login: &str, worker_name: &str, ip: Option<IpAddr>,
+    ) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(login, worker_name, ip))
+    }
+
+    // Route for a login request of either protocol
+    pub fn route_login(
+        &self, rpc: &(dyn EthClientObject + Send + Sync), ip: Option<IpAddr>,
+    ) -> Option<&Route> {
+        if self.routes.is_empty() {
+            return None;
+        }
+
+        let wallet = rpc.get_eth_wallet()?;
+        let (login, worker_name) = match wallet.split_once('.') {
+            Some((_, worker_name)) => (wallet.clone(), worker_name.to_string()),
+            None => {
+                let worker_name = rpc.get_worker_name();
+                (wallet + "." + worker_name.as_str(), worker_name)
+            }
+        };
+
+        self.route(&login, &worker_name, ip)
+    }
+
+    pub async fn run_health_checks(&self, login: EthClientRootObject) -> Result<()> {
+        let checks: Vec<_> = self
+            .routes
+            .iter()
+            .map(|route| {
+                let pools = route.pools.clone();
+                let login = login.clone();
+                tokio::spawn(async move { pools.run_health_checks(login).await })
+            })
+            .collect();
+
+        for check in checks {
+            check.await??;
+        }
+        Ok(())
+    }
+}
+
+// `*` matches any run of characters. Wallets are hex so case is ignored.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if !text.starts_with(first)
+        || !text.ends_with(last)
+        || text.len() < first.len() + last.len()
+    {
+        return false;
+    }
+
+    let mut rest = match text.get(first.len()..text.len() - last.len()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
+    let (net, len) = match cidr.split_once('/') {
+        Some((net, len)) => (net, len),
+        None => bail!("CIDR {} has no prefix length", cidr),
+    };
+
+    let net: IpAddr = net.parse()?;
+    let len: u8 = len.parse()?;
+    let max = if net.is_ipv4() { 32 } else { 128 };
+    if len > max {
+        bail!("CIDR {} prefix length is greater than {}", cidr, max);
+    }
+
+    Ok((net, len))
+}
+
+pub fn cidr_contains((net, len): (IpAddr, u8), ip: IpAddr) -> bool {
+    let (net, ip, bits) = match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            (u32::from(net) as u128, u32::from(ip) as u128, 32)
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+        (IpAddr::V4(net), IpAddr::V6(ip)) => match ip.to_ipv4() {
+            Some(ip) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+            None => return false,
+        },
+        _ => return false,
+    };
+
+    if len == 0 {
+        return true;
+    }
+    let mask = !0u128 << (bits - len as u32);
+    net & mask == ip & mask
+}
+
+#[test]
+fn test_router() {
+    let rule = |name: &str, wallet: &str, worker_prefix: &str, cidr: &str| RouteRule {
+        name: name.into(),
+        wallet: wallet.into(),
+        worker_prefix: worker_prefix.into(),
+        cidr: cidr.into(),
+        pool_address: vec!["tcp://127.0.0.1:1".into()],
+    };
+    let router = Router::new(&[
+        rule("a", "0xABC.*", "", ""),
+        rule("b", "", "gpu-farm2-", ""),
+        rule("c", "", "", "10.2.0.0/16"),
+    ])
+    .unwrap();
+
+    let ip = |s: &str| Some(s.parse().unwrap());
+    assert_eq!(router.route("0xabc.rig1", "rig1", None).unwrap().rule.name, "a");
+    assert_eq!(
+        router.route("0xdef.gpu-farm2-01", "gpu-farm2-01", ip("10.2.3.4")).unwrap().rule.name,
+        "b"
+    );
+    assert_eq!(router.route("0xdef.rig1", "rig1", ip("10.2.3.4")).unwrap().rule.name, "c");
+    assert_eq!(
+        router.route("0xdef.rig1", "rig1", ip("::ffff:10.2.0.1")).unwrap().rule.name,
+        "c"
+    );
+    assert!(router.route("0xdef.rig1", "rig1", ip("10.3.0.1")).is_none());
+
+    assert!(glob_match("0x*c*f", "0xabcdef"));
+    assert!(!glob_match("0x*c*f", "0xabcde"));
+    assert!(!glob_match("0x*a", "0xé"));
+    assert!(glob_match("0x*é", "0xcafé"));
+    assert!(parse_cidr("10.0.0.0/33").is_err());
+    assert!(Router::new(&[rule("d", "", "", "")]).is_err());
+}
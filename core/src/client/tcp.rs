@@ -36,11 +36,16 @@ pub async fn accept_tcp(proxy: Arc<Proxy>) -> Result<()> {
     loop {
         let (stream, addr) = listener.accept().await?;
         stream.set_nodelay(true)?;
+        if proxy.is_draining() {
+            debug!("IP: {} refused while draining", addr);
+            continue;
+        }
         
         let p = Arc::clone(&proxy);
         tokio::spawn(async move {
             // Miner Status Management
             let mut worker: Worker = Worker::default();
+            worker.ip = addr.ip().to_string();
             let worker_tx = p.worker_tx.clone();
 
             match transfer(p, &mut worker, stream).await {
@@ -73,30 +78,8 @@ async fn transfer(
     let (worker_r, worker_w) = split(tcp_stream);
     let worker_r = BufReader::new(worker_r);
 
-    let mut pool_address: Vec<String> = Vec::new();
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
-    }
-
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("Not matched to a mining pool or neither can be linked. Please modify and try again");
-            }
-        };
 
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        false,
-    )
-    .await
+    handle_tcp_random(worker, worker_r, worker_w, proxy, false).await
     //handle_tcp_random(worker, worker_r, worker_w, &pools, proxy, false).await
 
     // if config.share == 0 {
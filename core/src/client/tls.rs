@@ -1,6 +1,5 @@
 use anyhow::Result;
 
-use tokio_rustls::rustls::ServerConfig;
 use tracing::info;
 
 use tokio::{
@@ -14,11 +13,13 @@ use tokio::{
 use tokio_rustls::TlsAcceptor;
 
 use super::*;
-use crate::{proxy::Proxy, state::Worker, util::config::Settings};
+use crate::{
+    proxy::Proxy,
+    state::Worker,
+    util::{cert, config::Settings},
+};
 
-pub async fn accept_tcp_with_tls(
-    proxy: Arc<Proxy>, cert: ServerConfig,
-) -> Result<()> {
+pub async fn accept_tcp_with_tls(proxy: Arc<Proxy>) -> Result<()> {
     let config: Settings;
     {
         let rconfig = RwLockReadGuard::map(proxy.config.read().await, |s| s);
@@ -29,6 +30,16 @@ pub async fn accept_tcp_with_tls(
         return Ok(());
     }
 
+    let cert = match cert::load_or_generate(&config.pem_path, &config.key_path) {
+        Ok(cert) => cert,
+        Err(e) => {
+            tracing::info!("SSL certificate failed to load. Please modify the certificate: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let (cert_tx, certs) = tokio::sync::watch::channel(Arc::new(cert));
+    tokio::spawn(cert::watch(config.pem_path.clone(), config.key_path.clone(), cert_tx));
+
     let address = format!("0.0.0.0:{}", config.ssl_port);
     let listener = match TcpListener::bind(address.clone()).await {
         Ok(listener) => listener,
@@ -43,19 +54,24 @@ pub async fn accept_tcp_with_tls(
     // let tls_acceptor = tokio_native_tls::TlsAcceptor::from(
     //     native_tls::TlsAcceptor::builder(cert).build()?,
     // );
-    let tls_acceptor = TlsAcceptor::from(Arc::new(cert));
 
     loop {
         // Asynchronously wait for an inbound TcpStream.
         let (stream, addr) = listener.accept().await?;
         stream.set_nodelay(true)?;
-        let acceptor = tls_acceptor.clone();
+        if proxy.is_draining() {
+            debug!("IP: {} refused while draining", addr);
+            continue;
+        }
+        // The latest certificate, connected miners keep theirs
+        let acceptor = TlsAcceptor::from(certs.borrow().clone());
 
         let p = Arc::clone(&proxy);
 
         tokio::spawn(async move {
             // Miner Status Management
             let mut worker: Worker = Worker::default();
+            worker.ip = addr.ip().to_string();
             let worker_tx = p.worker_tx.clone();
             match transfer_ssl(p, &mut worker, stream, acceptor).await {
                 Ok(_) => {
@@ -88,19 +104,6 @@ async fn transfer_ssl(
     let client_stream = tls_acceptor.accept(tcp_stream).await?;
     let (worker_r, worker_w) = split(client_stream);
     let worker_r = BufReader::new(worker_r);
-    let pool_address: Vec<String>;
-    {
-        let config = RwLockReadGuard::map(proxy.config.read().await, |s| s);
-        pool_address = config.pool_address.to_vec();
-    }
-
-    let (stream_type, pools) =
-        match crate::client::get_pool_ip_and_type_from_vec(&pool_address) {
-            Ok(pool) => pool,
-            Err(_) => {
-                bail!("Not matched to a mining pool or neither can be linked. Please modify and try again");
-            }
-        };
 
     // if config.share == 0 {
     //     handle_tcp_pool(
@@ -125,16 +128,7 @@ async fn transfer_ssl(
     //     false,
     // )
     // .await
-    handle_tcp_random(
-        worker,
-        worker_r,
-        worker_w,
-        &pools,
-        proxy,
-        stream_type,
-        false,
-    )
-    .await
+    handle_tcp_random(worker, worker_r, worker_w, proxy, false).await
     // } else {
     //     handle_tcp_pool_timer(
     //         worker,
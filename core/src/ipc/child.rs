@@ -0,0 +1,272 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::split,
+    select,
+    sync::mpsc::{self, UnboundedReceiver},
+    time::Instant,
+};
+
+use super::{
+    handshake, read_frame, write_frame, Command, Endpoint, IpcStream, Reply, ToChild,
+    ToParent, IPC_ENDPOINT, IPC_TOKEN,
+};
+use crate::{alert::Alerts, proxy::Proxy, state::Worker};
+
+// Miners remembered while the supervisor is away. Updates of further miners
+// are dropped until it is back.
+const MAX_MINERS: usize = 10_000;
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+// How often the health of the pools goes to the supervisor
+const POOL_REPORT_INTERVAL: Duration = Duration::from_secs(15);
+// Frames handed to the writer. Past that, miner updates are coalesced.
+const FRAME_QUEUE: usize = 64;
+
+// Latest state of every miner of the transit. Offline miners are forgotten
+// once sent to the supervisor.
+struct Miners {
+    alerts: Alerts,
+    latest: HashMap<String, Worker>,
+    // Miners whose latest state is not sent yet
+    pending: HashSet<String>,
+    full: bool,
+}
+
+impl Miners {
+    fn update(&mut self, worker: &Worker) {
+        self.alerts.observe(worker);
+        if self.latest.len() >= MAX_MINERS && !self.latest.contains_key(&worker.worker) {
+            if !self.full {
+                tracing::warn!("Too many miners buffered for the web supervisor, dropping updates");
+                self.full = true;
+            }
+            return;
+        }
+        self.latest.insert(worker.worker.clone(), worker.clone());
+        self.pending.insert(worker.worker.clone());
+    }
+
+    // Everything again, for a supervisor that just connected
+    fn resend_all(&mut self) { self.pending = self.latest.keys().cloned().collect(); }
+
+    fn next_frame(&mut self) -> Option<ToParent> {
+        let name = self.pending.iter().next()?.clone();
+        self.pending.remove(&name);
+        let worker = self.latest.get(&name)?.clone();
+        if !worker.is_online() {
+            self.latest.remove(&name);
+            self.full = false;
+        }
+        Some(ToParent::Worker {
+            worker: Box::new(worker),
+        })
+    }
+
+    fn online(&self) -> Vec<Worker> {
+        self.latest.values().filter(|w| w.is_online()).cloned().collect()
+    }
+}
+
+// Reports miners to the web supervisor and runs its commands. Reconnects with
+// backoff for as long as the transit lives.
+pub async fn run(
+    proxy: Arc<Proxy>, mut worker_rx: UnboundedReceiver<Worker>, alerts: Alerts,
+) -> Result<()> {
+    let endpoint = Endpoint::parse(&IPC_ENDPOINT)?;
+    let name = proxy.config.read().await.name.clone();
+    let mut miners = Miners {
+        alerts,
+        latest: HashMap::new(),
+        pending: HashSet::new(),
+        full: false,
+    };
+    let mut backoff = BACKOFF_MIN;
+    let mut wait = false;
+
+    loop {
+        // Miners keep reporting while the supervisor is away
+        let stream = {
+            let connect = connect(&endpoint, &name, &mut backoff, wait);
+            tokio::pin!(connect);
+            loop {
+                select! {
+                    stream = &mut connect => break stream,
+                    Some(w) = worker_rx.recv() => miners.update(&w),
+                }
+            }
+        };
+
+        let started = Instant::now();
+        if let Err(e) = session(stream, &proxy, &mut worker_rx, &mut miners).await {
+            tracing::warn!("Lost the web supervisor: {}", e);
+        }
+        // A supervisor that drops us right away is not hammered
+        wait = started.elapsed() < BACKOFF_MAX;
+        if !wait {
+            backoff = BACKOFF_MIN;
+        }
+    }
+}
+
+async fn connect(
+    endpoint: &Endpoint, name: &str, backoff: &mut Duration, mut wait: bool,
+) -> IpcStream {
+    loop {
+        if wait {
+            tokio::time::sleep(*backoff).await;
+            *backoff = (*backoff * 2).min(BACKOFF_MAX);
+        }
+        wait = true;
+
+        let mut stream = match endpoint.connect().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::error!("Unable to reach the web supervisor at {}: {}", endpoint, e);
+                continue;
+            }
+        };
+        match handshake(&mut stream, name, &IPC_TOKEN).await {
+            Ok(()) => {
+                tracing::info!("Connected to the web supervisor at {}", endpoint);
+                return stream;
+            }
+            Err(e) => tracing::error!("Web supervisor handshake failed: {}", e),
+        }
+    }
+}
+
+async fn session(
+    stream: IpcStream, proxy: &Proxy, worker_rx: &mut UnboundedReceiver<Worker>,
+    miners: &mut Miners,
+) -> Result<()> {
+    let (mut r, mut w) = split(stream);
+
+    // Frames are read on their own task, a read cut by select! would lose
+    // the frame
+    let (command_tx, mut command_rx) = mpsc::channel::<ToChild>(16);
+    let reader = tokio::spawn(async move {
+        loop {
+            let msg = read_frame(&mut r).await?;
+            if command_tx.send(msg).await.is_err() {
+                return Ok::<(), anyhow::Error>(());
+            }
+        }
+    });
+    // And written on another, so miners keep being taken in while the
+    // supervisor is slow to read
+    let (frame_tx, mut frame_rx) = mpsc::channel::<ToParent>(FRAME_QUEUE);
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = frame_rx.recv().await {
+            write_frame(&mut w, &msg).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    });
+
+    let res = async {
+        // Everything the supervisor missed, and a full picture after its
+        // restart
+        miners.resend_all();
+        // Replies and pool reports, sent before miner updates
+        let mut outbox: VecDeque<ToParent> = VecDeque::new();
+        let mut report = tokio::time::interval(POOL_REPORT_INTERVAL);
+        loop {
+            let sending = !outbox.is_empty() || !miners.pending.is_empty();
+            select! {
+                Some(worker) = worker_rx.recv() => miners.update(&worker),
+                permit = frame_tx.reserve(), if sending => {
+                    let permit = permit.map_err(|_| anyhow::anyhow!("Connection closed"))?;
+                    if let Some(msg) = outbox.pop_front().or_else(|| miners.next_frame()) {
+                        permit.send(msg);
+                    }
+                },
+                _ = report.tick() => {
+                    // A report still waiting is replaced
+                    outbox.retain(|msg| !matches!(msg, ToParent::Pools { .. }));
+                    outbox.push_back(ToParent::Pools { pools: proxy.pool_status() });
+                },
+                msg = command_rx.recv() => match msg {
+                    Some(ToChild::Command { id, command }) => {
+                        let reply = execute(command, proxy, miners).await;
+                        outbox.push_back(ToParent::Reply { id, reply });
+                    }
+                    Some(_) => {}
+                    None => bail!("Connection closed"),
+                },
+            }
+        }
+    }
+    .await;
+
+    reader.abort();
+    writer.abort();
+    match (reader.await, writer.await) {
+        (Ok(Err(e)), _) | (_, Ok(Err(e))) => Err(e),
+        _ => res,
+    }
+}
+
+async fn execute(command: Command, proxy: &Proxy, miners: &Miners) -> Reply {
+    match command {
+        Command::KickWorker { worker } => {
+            if !matches!(miners.latest.get(&worker), Some(w) if w.is_online()) {
+                return Reply::error(format!("Miner {} is not online", worker));
+            }
+            proxy.kick_worker(&worker);
+            Reply::ok(format!("Miner {} kicked", worker))
+        }
+        Command::ReloadConfig { config } => match proxy.reload(*config).await {
+            Ok(restart) if restart.is_empty() => Reply::ok("Configuration reloaded"),
+            Ok(restart) => Reply {
+                restart: restart.iter().map(|s| s.to_string()).collect(),
+                ..Reply::ok(format!(
+                    "Configuration reloaded, restart to apply {}",
+                    restart.join(", ")
+                ))
+            },
+            Err(e) => Reply::error(format!("Invalid configuration: {}", e)),
+        },
+        Command::Drain => {
+            proxy.drain();
+            Reply::ok(format!("Draining, {} miners still online", miners.online().len()))
+        }
+        Command::FetchStats => Reply {
+            workers: miners.online(),
+            ..Reply::ok("")
+        },
+    }
+}
+
+#[test]
+fn test_miners_coalesce() {
+    let (alerts, _runner) = Alerts::new("eth", Default::default());
+    let mut miners = Miners {
+        alerts,
+        latest: HashMap::new(),
+        pending: HashSet::new(),
+        full: false,
+    };
+    let mut worker = Worker::new("0xabc.rig1".into(), "rig1".into(), "0xabc".into(), true);
+    miners.update(&worker);
+    worker.share_index = 5;
+    miners.update(&worker);
+
+    // Only the latest state goes out
+    match miners.next_frame() {
+        Some(ToParent::Worker { worker }) => assert_eq!(worker.share_index, 5),
+        msg => panic!("unexpected {:?}", msg),
+    }
+    assert!(miners.next_frame().is_none());
+
+    // Offline miners are sent once, then forgotten
+    worker.online = false;
+    miners.update(&worker);
+    assert!(miners.next_frame().is_some());
+    miners.resend_all();
+    assert!(miners.next_frame().is_none());
+}
@@ -0,0 +1,346 @@
+pub mod child;
+pub mod parent;
+
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::{bail, Result};
+use ring::hmac;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{client::pool_manager::PoolStatus, state::Worker, util::config::Settings};
+
+// Bumped on any change to the messages below. Both ends refuse a peer of
+// another version.
+pub const IPC_VERSION: u32 = 3;
+
+// Stats of a big transit fit easily
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(unix)]
+const DEFAULT_ENDPOINT: &str = "mining_proxy.sock";
+#[cfg(not(unix))]
+const DEFAULT_ENDPOINT: &str = "127.0.0.1:65501";
+
+lazy_static! {
+    // Where the web supervisor listens for its transits: a Unix socket path,
+    // a TCP port or a TCP address. Two supervisors on one host need their own.
+    pub static ref IPC_ENDPOINT: String = std::env::var("MINING_PROXY_IPC")
+        .unwrap_or_else(|_| DEFAULT_ENDPOINT.into());
+
+    // Handshake secret. The supervisor keeps it in `ipc_token` and hands it
+    // to the transits it spawns, so they find their way back to it after a
+    // restart of the supervisor.
+    pub static ref IPC_TOKEN: String = std::env::var("MINING_PROXY_IPC_TOKEN")
+        .unwrap_or_else(|_| crate::web::users::load_secret(IPC_TOKEN_FILE));
+}
+
+pub const IPC_TOKEN_FILE: &str = "ipc_token";
+
+// Supervisor to transit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToChild {
+    Challenge { version: u32, nonce: String },
+    Welcome,
+    Command { id: u64, command: Command },
+}
+
+// Transit to supervisor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToParent {
+    // `proof` is the HMAC of the challenge nonce and the name under the token
+    Hello {
+        version: u32,
+        name: String,
+        pid: u32,
+        proof: String,
+    },
+    Worker { worker: Box<Worker> },
+    // Sent every few seconds, see `POOL_REPORT_INTERVAL`
+    Pools { pools: Vec<PoolStatus> },
+    Reply { id: u64, reply: Reply },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    // Closes every session of the miner, by its full `wallet.name`
+    KickWorker { worker: String },
+    // Live sessions pick up what they safely can, see `Settings::reload`
+    ReloadConfig { config: Box<Settings> },
+    // Stops taking new miners. Connected ones keep mining.
+    Drain,
+    FetchStats,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    pub message: String,
+    // Online miners, for FetchStats
+    #[serde(default)]
+    pub workers: Vec<Worker>,
+    // Changed settings waiting for a restart, for ReloadConfig
+    #[serde(default)]
+    pub restart: Vec<String>,
+}
+
+impl Reply {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+}
+
+// Frames are a big endian u32 length followed by that many bytes of JSON
+pub async fn write_frame<W, T>(w: &mut W, msg: &T) -> Result<()>
+where W: AsyncWrite + Unpin, T: Serialize {
+    let body = serde_json::to_vec(msg)?;
+    if body.len() > MAX_FRAME {
+        bail!("IPC frame of {} bytes is too large", body.len());
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    w.write_all(&frame).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame<R, T>(r: &mut R) -> Result<T>
+where R: AsyncRead + Unpin, T: DeserializeOwned {
+    let len = r.read_u32().await? as usize;
+    if len > MAX_FRAME {
+        bail!("IPC frame of {} bytes is too large", len);
+    }
+
+    let mut body = vec![0; len];
+    r.read_exact(&mut body).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn proof(token: &str, nonce: &str, name: &str) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    hmac::sign(&key, format!("{}:{}", nonce, name).as_bytes())
+}
+
+// Supervisor side of the handshake. Returns the name and pid of the transit.
+pub async fn accept_handshake<S>(stream: &mut S, token: &str) -> Result<(String, u32)>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let nonce = hex::encode(rand::random::<[u8; 32]>());
+    write_frame(stream, &ToChild::Challenge {
+        version: IPC_VERSION,
+        nonce: nonce.clone(),
+    })
+    .await?;
+
+    let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(stream)).await??;
+    let (version, name, pid, proof_hex) = match hello {
+        ToParent::Hello {
+            version,
+            name,
+            pid,
+            proof,
+        } => (version, name, pid, proof),
+        _ => bail!("Expected a hello from the transit"),
+    };
+    if version != IPC_VERSION {
+        bail!("Transit {} speaks IPC version {}, expected {}", name, version, IPC_VERSION);
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    let message = format!("{}:{}", nonce, name);
+    if hmac::verify(&key, message.as_bytes(), &hex::decode(proof_hex)?).is_err() {
+        bail!("Transit {} failed the handshake", name);
+    }
+
+    write_frame(stream, &ToChild::Welcome).await?;
+    Ok((name, pid))
+}
+
+// Transit side of the handshake
+pub async fn handshake<S>(stream: &mut S, name: &str, token: &str) -> Result<()>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let challenge = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(stream)).await??;
+    let nonce = match challenge {
+        ToChild::Challenge { version, nonce } if version == IPC_VERSION => nonce,
+        ToChild::Challenge { version, .. } => bail!(
+            "The web supervisor speaks IPC version {}, expected {}",
+            version,
+            IPC_VERSION
+        ),
+        _ => bail!("Expected a challenge from the web supervisor"),
+    };
+
+    write_frame(stream, &ToParent::Hello {
+        version: IPC_VERSION,
+        name: name.to_string(),
+        pid: std::process::id(),
+        proof: hex::encode(proof(token, &nonce, name).as_ref()),
+    })
+    .await?;
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(stream)).await?? {
+        ToChild::Welcome => Ok(()),
+        _ => bail!("Expected a welcome from the web supervisor"),
+    }
+}
+
+pub trait IpcIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IpcIo for T {}
+
+pub type IpcStream = Box<dyn IpcIo>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl Endpoint {
+    // A bare port listens on localhost
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Ok(port) = value.parse::<u16>() {
+            return Ok(Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], port))));
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(Endpoint::Tcp(addr));
+        }
+
+        #[cfg(unix)]
+        if !value.is_empty() {
+            return Ok(Endpoint::Unix(value.into()));
+        }
+        bail!("Invalid IPC endpoint {:?}", value)
+    }
+
+    pub async fn connect(&self) -> Result<IpcStream> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        })
+    }
+
+    pub async fn bind(&self) -> Result<Listener> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                // A socket file left by a supervisor that crashed
+                if path.exists() {
+                    if tokio::net::UnixStream::connect(path).await.is_ok() {
+                        bail!("{} is used by another supervisor", path.display());
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                Listener::Unix(tokio::net::UnixListener::bind(path)?)
+            }
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub async fn accept(&self) -> Result<IpcStream> {
+        Ok(match self {
+            Listener::Tcp(listener) => Box::new(listener.accept().await?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+#[test]
+fn test_ipc_handshake() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        assert_eq!(
+            Endpoint::parse("65501").unwrap(),
+            Endpoint::Tcp("127.0.0.1:65501".parse().unwrap())
+        );
+        assert!(Endpoint::parse("").is_err());
+
+        let (mut parent, mut child) = tokio::io::duplex(1024);
+        let (accepted, joined) = tokio::join!(
+            accept_handshake(&mut parent, "secret"),
+            handshake(&mut child, "eth", "secret"),
+        );
+        assert_eq!(accepted.unwrap(), ("eth".to_string(), std::process::id()));
+        joined.unwrap();
+
+        write_frame(&mut parent, &ToChild::Command {
+            id: 7,
+            command: Command::KickWorker {
+                worker: "0xabc.rig1".into(),
+            },
+        })
+        .await
+        .unwrap();
+        match read_frame(&mut child).await.unwrap() {
+            ToChild::Command {
+                id: 7,
+                command: Command::KickWorker { worker },
+            } => assert_eq!(worker, "0xabc.rig1"),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        let worker = Worker::new("0xabc.rig1".into(), "rig1".into(), "0xabc".into(), true);
+        write_frame(&mut child, &ToParent::Worker {
+            worker: Box::new(worker.clone()),
+        })
+        .await
+        .unwrap();
+        match read_frame(&mut parent).await.unwrap() {
+            ToParent::Worker { worker: w } => assert_eq!(w.worker, worker.worker),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // A transit that does not know the token
+        let (mut parent, mut child) = tokio::io::duplex(1024);
+        let (accepted, joined) = tokio::join!(
+            async move { accept_handshake(&mut parent, "secret").await },
+            handshake(&mut child, "eth", "guess"),
+        );
+        assert!(accepted.is_err());
+        assert!(joined.is_err());
+
+        // Oversized length prefix
+        let (mut a, mut b) = tokio::io::duplex(64);
+        a.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        assert!(read_frame::<_, ToChild>(&mut b).await.is_err());
+    });
+}
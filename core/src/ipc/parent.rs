@@ -0,0 +1,205 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::{
+    io::split,
+    select,
+    sync::{mpsc, oneshot},
+};
+
+use super::{
+    accept_handshake, read_frame, write_frame, Command, Endpoint, IpcStream, Reply, ToChild,
+    ToParent, IPC_ENDPOINT, IPC_TOKEN,
+};
+use crate::{
+    state::Worker,
+    util::config::{load_configs, Settings},
+    web::AppState,
+};
+
+// Commands waiting for a busy transit
+const COMMAND_QUEUE: usize = 16;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type CommandSender = mpsc::Sender<(Command, oneshot::Sender<Reply>)>;
+
+// Accepts the transits spawned by this supervisor
+pub async fn serve(app: AppState) -> Result<()> {
+    let endpoint = Endpoint::parse(&IPC_ENDPOINT)?;
+    let listener = endpoint.bind().await?;
+    tracing::info!("Waiting for transits on {}", endpoint);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::error!("Failed to accept a transit: {}", e);
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_child(app, stream).await {
+                tracing::warn!("Transit connection closed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_child(app: AppState, mut stream: IpcStream) -> Result<()> {
+    let (name, pid) = accept_handshake(&mut stream, &IPC_TOKEN).await?;
+
+    let (commands, mut command_rx) = mpsc::channel(COMMAND_QUEUE);
+    {
+        let mut proxy_server = app.lock().unwrap();
+        let server = match proxy_server.get_mut(&name) {
+            Some(server) => server,
+            None => bail!("Unknown transit {}", name),
+        };
+        // The transit sends all its online miners right after connecting.
+        // Miners of a restarted process stay listed as offline.
+        for worker in &mut server.workers {
+            worker.online = false;
+        }
+        server.commands = Some(commands);
+    }
+    tracing::info!("Transit {} (pid {}) connected", name, pid);
+
+    let (mut r, mut w) = split(stream);
+    let (msg_tx, mut msg_rx) = mpsc::channel::<ToParent>(64);
+    let reader = tokio::spawn(async move {
+        loop {
+            let msg = read_frame(&mut r).await?;
+            if msg_tx.send(msg).await.is_err() {
+                return Ok::<(), anyhow::Error>(());
+            }
+        }
+    });
+
+    let mut waiting: HashMap<u64, oneshot::Sender<Reply>> = HashMap::new();
+    let mut next_id = 0;
+    let res = async {
+        loop {
+            select! {
+                msg = msg_rx.recv() => match msg {
+                    Some(ToParent::Worker { worker }) => update_worker(&app, &name, *worker),
+                    Some(ToParent::Pools { pools }) => {
+                        if let Some(server) = app.lock().unwrap().get_mut(&name) {
+                            server.pools = pools;
+                        }
+                    }
+                    Some(ToParent::Reply { id, reply }) => {
+                        if let Some(waiter) = waiting.remove(&id) {
+                            let _ = waiter.send(reply);
+                        }
+                    }
+                    Some(ToParent::Hello { .. }) => {}
+                    None => bail!("Transit {} disconnected", name),
+                },
+                command = command_rx.recv() => match command {
+                    Some((command, waiter)) => {
+                        next_id += 1;
+                        waiting.insert(next_id, waiter);
+                        write_frame(&mut w, &ToChild::Command { id: next_id, command }).await?;
+                    }
+                    // Replaced by a newer connection of the same transit
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+
+    reader.abort();
+    drop(command_rx);
+    if let Some(server) = app.lock().unwrap().get_mut(&name) {
+        if matches!(&server.commands, Some(c) if c.is_closed()) {
+            server.commands = None;
+            server.pools.clear();
+        }
+    }
+    match reader.await {
+        Ok(Err(e)) => Err(e),
+        _ => res,
+    }
+}
+
+fn update_worker(app: &AppState, name: &str, mut worker: Worker) {
+    worker.seen();
+    let mut proxy_server = app.lock().unwrap();
+    let server = match proxy_server.get_mut(name) {
+        Some(server) => server,
+        None => return,
+    };
+    match server.workers.iter_mut().find(|w| w.worker == worker.worker) {
+        Some(w) => *w = worker,
+        None => server.workers.push(worker),
+    }
+}
+
+// Runs a command on a transit and waits for its answer
+pub async fn send_command(app: &AppState, name: &str, command: Command) -> Result<Reply> {
+    let commands = match app.lock().unwrap().get(name) {
+        Some(server) => server.commands.clone(),
+        None => bail!("Transit {} not found", name),
+    };
+    let commands = commands.ok_or_else(|| anyhow!("Transit {} is not connected", name))?;
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tokio::time::timeout(COMMAND_TIMEOUT, async {
+        commands
+            .send((command, reply_tx))
+            .await
+            .map_err(|_| anyhow!("Transit {} is not connected", name))?;
+        reply_rx
+            .await
+            .map_err(|_| anyhow!("Transit {} disconnected before answering", name))
+    })
+    .await
+    .map_err(|_| anyhow!("Transit {} did not answer in {:?}", name, COMMAND_TIMEOUT))?
+}
+
+// Hands a new config to a running transit and keeps it as the one shown in
+// the web interface
+pub async fn reload_config(app: &AppState, config: Settings) -> Result<Reply> {
+    let reply = send_command(app, &config.name, Command::ReloadConfig {
+        config: Box::new(config.clone()),
+    })
+    .await?;
+    if reply.ok {
+        if let Some(server) = app.lock().unwrap().get_mut(&config.name) {
+            server.config = config;
+        }
+    }
+    Ok(reply)
+}
+
+// Pushes the transits of the configs file that changed to their process
+pub async fn reload_configs(app: &AppState, path: &str) -> Result<()> {
+    for config in load_configs(path)? {
+        let changed = match app.lock().unwrap().get_mut(&config.name) {
+            // A stopped transit starts with it
+            Some(server) if server.commands.is_none() => {
+                server.config = config;
+                continue;
+            }
+            Some(server) => server.config != config,
+            None => {
+                tracing::warn!("Transit {} is not running, restart to start it", config.name);
+                continue;
+            }
+        };
+        if !changed {
+            continue;
+        }
+
+        let name = config.name.clone();
+        match reload_config(app, config).await {
+            Ok(reply) if reply.ok => tracing::info!("Transit {}: {}", name, reply.message),
+            Ok(reply) => tracing::error!("Transit {} kept its config: {}", name, reply.message),
+            Err(e) => tracing::error!("Transit {} kept its config: {}", name, e),
+        }
+    }
+    Ok(())
+}
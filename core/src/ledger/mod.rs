@@ -0,0 +1,278 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{protocol::rpc::eth::RejectReason, state::Worker};
+
+// Ledgers live in `ledger/<instance name>/<YYYY-MM-DD>.jsonl` under the
+// working directory, one file per UTC day.
+pub const LEDGER_DIR: &str = "ledger";
+
+// Where the work of a share went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Destination {
+    Miner,
+    Fee,
+    Develop,
+}
+
+// One line of the ledger
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareRecord {
+    // Unix time in milliseconds of the pool verdict
+    pub time: u64,
+    pub worker: String,
+    pub wallet: String,
+    pub job_id: String,
+    pub destination: Destination,
+    pub difficulty: f64,
+    pub accepted: bool,
+    pub reason: Option<RejectReason>,
+}
+
+impl ShareRecord {
+    pub fn new(
+        worker: &Worker, job_id: &str, destination: Destination, difficulty: f64,
+    ) -> Self {
+        Self {
+            time: 0,
+            worker: worker.worker.clone(),
+            wallet: worker.worker_wallet.clone(),
+            job_id: job_id.to_string(),
+            destination,
+            difficulty,
+            accepted: false,
+            reason: None,
+        }
+    }
+
+    pub fn verdict(mut self, accepted: bool, reason: Option<RejectReason>) -> Self {
+        self.time = now_millis();
+        self.accepted = accepted;
+        self.reason = if accepted { None } else { reason };
+        self
+    }
+}
+
+// A fee or developer share on its way to the fee loop, which records it once
+// the pool answered.
+#[derive(Debug, Clone)]
+pub struct FeeShare {
+    pub params: Vec<String>,
+    pub record: ShareRecord,
+}
+
+// Cheap handle to the ledger writer. Records are appended in the order they
+// are sent.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    tx: UnboundedSender<ShareRecord>,
+}
+
+impl Ledger {
+    pub fn new(name: &str) -> (Self, LedgerWriter) {
+        let (tx, rx) = unbounded_channel();
+        (
+            Self { tx },
+            LedgerWriter {
+                dir: ledger_dir(name),
+                rx,
+            },
+        )
+    }
+
+    pub fn record(&self, record: ShareRecord) {
+        if let Err(e) = self.tx.send(record) {
+            tracing::error!("Ledger writer is gone, share not recorded: {:?}", e.0);
+        }
+    }
+}
+
+pub struct LedgerWriter {
+    dir: PathBuf,
+    rx: UnboundedReceiver<ShareRecord>,
+}
+
+impl LedgerWriter {
+    // A failed write loses that share only. The file is opened again for the
+    // next one, sessions never stop over the ledger.
+    pub async fn run(mut self) -> Result<()> {
+        let mut current: Option<(NaiveDate, File)> = None;
+        while let Some(record) = self.rx.recv().await {
+            if let Err(e) = self.write(&mut current, &record).await {
+                tracing::error!("Ledger {} share not recorded: {}", self.dir.display(), e);
+                current = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write(
+        &self, current: &mut Option<(NaiveDate, File)>, record: &ShareRecord,
+    ) -> Result<()> {
+        let day = day_of(record.time);
+        let file = match current {
+            Some((d, ref mut file)) if *d == day => file,
+            _ => {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                let path = self.dir.join(format!("{}.jsonl", day));
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?;
+                &mut current.insert((day, file)).1
+            }
+        };
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line).await?;
+        // A tokio file writes in the background, readers and an exit would
+        // miss the tail otherwise
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+pub fn ledger_dir(name: &str) -> PathBuf { Path::new(LEDGER_DIR).join(name) }
+
+// Realized fee of one wallet. Work is the sum of the difficulty of accepted
+// shares.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WalletFee {
+    pub wallet: String,
+    pub shares: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub miner_work: f64,
+    pub fee_work: f64,
+    pub develop_work: f64,
+    // fee_work over all accepted work
+    pub fee_ratio: f64,
+    pub develop_ratio: f64,
+}
+
+// Fee per wallet from the records with `from <= time < to` (Unix ms).
+pub fn query_fees(
+    dir: &Path, from: u64, to: u64, wallet: Option<&str>,
+) -> Result<Vec<WalletFee>> {
+    let mut wallets: BTreeMap<String, WalletFee> = BTreeMap::new();
+    if !dir.exists() || from >= to {
+        return Ok(vec![]);
+    }
+
+    let (first, last) = (day_of(from), day_of(to.saturating_sub(1)));
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let day = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok());
+        match day {
+            Some(day) if day >= first && day <= last => {}
+            _ => continue,
+        }
+
+        let file = std::fs::File::open(&path)?;
+        for line in BufReader::new(file).lines() {
+            let record: ShareRecord = match serde_json::from_str(&line?) {
+                Ok(record) => record,
+                // A torn last line after a crash
+                Err(_) => continue,
+            };
+            if record.time < from || record.time >= to {
+                continue;
+            }
+            if matches!(wallet, Some(w) if !w.eq_ignore_ascii_case(&record.wallet)) {
+                continue;
+            }
+
+            let fee = wallets
+                .entry(record.wallet.to_lowercase())
+                .or_insert_with(|| WalletFee {
+                    wallet: record.wallet.clone(),
+                    ..Default::default()
+                });
+            fee.shares += 1;
+            if !record.accepted {
+                fee.rejected += 1;
+                continue;
+            }
+            fee.accepted += 1;
+            match record.destination {
+                Destination::Miner => fee.miner_work += record.difficulty,
+                Destination::Fee => fee.fee_work += record.difficulty,
+                Destination::Develop => fee.develop_work += record.difficulty,
+            }
+        }
+    }
+
+    Ok(wallets
+        .into_values()
+        .map(|mut fee| {
+            let total = fee.miner_work + fee.fee_work + fee.develop_work;
+            if total > 0.0 {
+                fee.fee_ratio = fee.fee_work / total;
+                fee.develop_ratio = fee.develop_work / total;
+            }
+            fee
+        })
+        .collect())
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn day_of(millis: u64) -> NaiveDate {
+    NaiveDateTime::from_timestamp((millis / 1000) as i64, 0).date()
+}
+
+#[test]
+fn test_ledger() {
+    let dir = std::env::temp_dir().join(format!("ledger_test_{}", now_millis()));
+    let (ledger, writer) = Ledger::new("test");
+    let writer = LedgerWriter { dir: dir.clone(), ..writer };
+
+    let mut worker = Worker::default();
+    worker.worker = "0xAbc.rig1".into();
+    worker.worker_wallet = "0xAbc".into();
+    for i in 0..20 {
+        let destination = if i % 10 == 0 { Destination::Fee } else { Destination::Miner };
+        ledger.record(ShareRecord::new(&worker, "0x01", destination, 2.0).verdict(true, None));
+    }
+    ledger.record(
+        ShareRecord::new(&worker, "0x01", Destination::Miner, 2.0)
+            .verdict(false, Some(RejectReason::Stale)),
+    );
+    drop(ledger);
+
+    tokio::runtime::Runtime::new().unwrap().block_on(writer.run()).unwrap();
+
+    let fees = query_fees(&dir, 0, now_millis() + 1, Some("0xabc")).unwrap();
+    assert_eq!(fees.len(), 1);
+    assert_eq!(fees[0].shares, 21);
+    assert_eq!(fees[0].rejected, 1);
+    assert!((fees[0].fee_ratio - 0.1).abs() < 1e-9);
+    assert!(query_fees(&dir, 0, 1, None).unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -6,10 +6,10 @@ extern crate lazy_static;
 const SPLIT: u8 = b'\n';
 
 lazy_static! {
+    // Signs the web tokens. Made on first run and kept in `jwt_secret`, so
+    // logins survive a restart.
     pub static ref JWT_SECRET: String = std::env::var("JWT_SECRET")
-        .unwrap_or_else(|_| {
-            "Generate : 0x60cc493FD745E268622274D877f1A50eD8368251".into()
-        });
+        .unwrap_or_else(|_| web::users::load_secret(web::users::JWT_SECRET_FILE));
 }
 
 lazy_static! {
@@ -49,7 +49,10 @@ pub fn init() {
     dev_fee.to_string();
 }
 
+pub mod alert;
 pub mod client;
+pub mod ipc;
+pub mod ledger;
 pub mod protocol;
 pub mod proxy;
 pub mod state;
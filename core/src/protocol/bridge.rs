@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+
+use serde_json::Value;
+
+use super::{
+    eth_stratum::{EthStratumNotify, EthStratumSetDifficulty, EthSubscriptionNotify},
+    ethjson::{EthClientObject, EthClientRootObject},
+    rpc::eth::RejectReason,
+    stratum::StraumMiningSet,
+    CLIENT_LOGIN, CLIENT_SUBMITWORK, SUBSCRIBE,
+};
+
+// Values of `Settings.pool_protocol`. AUTO speaks the dialect of each miner
+// to the pools, which then have to take both on the same port.
+pub const POOL_PROTOCOL_AUTO: &str = "AUTO";
+pub const POOL_PROTOCOL_ETHPROXY: &str = "ETHPROXY";
+pub const POOL_PROTOCOL_ETHSTRATUM: &str = "ETHEREUMSTRATUM";
+
+// How many pool jobs are remembered to map a submitted header back to the
+// pool job id.
+const MAX_JOBS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamMessage {
+    // The pool answered mining.subscribe and assigned an extranonce.
+    Subscribed,
+    // The pool changed the extranonce with mining.set_extranonce.
+    Extranonce(String),
+    // mining.set_difficulty. Applied to the next job.
+    Difficulty,
+    // A mining.notify converted to the eth_getWork result form.
+    Job(Vec<String>),
+    // Anything else. Results of authorize and submit end up here.
+    Other,
+}
+
+// The pool half of the protocol translation. The proxy talks
+// EthereumStratum/1.0.0 to the pool and hands out jobs as the eth_getWork
+// result [header, seed, target] so the rest of the session does not care
+// which dialect the pool speaks.
+//
+// Nonces found by the miner must start with the extranonce assigned by the
+// pool. EthereumStratum/1.0.0 miners get it through their own session. An
+// ETHProxy miner picks all 8 bytes of the nonce and cannot be told about it,
+// so splitting the nonce range for it is not done here. Its login is refused
+// when the pool assigns a non-empty extranonce. Shares outside the range are
+// rejected locally.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EthStratumUpstream {
+    pub extranonce: String,
+    pub difficulty: f64,
+    pub subscribed: bool,
+    // (header, pool job id)
+    jobs: VecDeque<(String, String)>,
+}
+
+impl EthStratumUpstream {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn subscribe_rpc() -> EthClientRootObject {
+        EthClientRootObject {
+            id: SUBSCRIBE,
+            method: "mining.subscribe".into(),
+            params: vec![
+                format!("mining_proxy/{}", clap::crate_version!()),
+                "EthereumStratum/1.0.0".into(),
+            ],
+        }
+    }
+
+    // Build mining.authorize from the wallet the miner logged in with.
+    pub fn authorize_rpc(
+        rpc: &(dyn EthClientObject + Send + Sync),
+    ) -> Option<Box<dyn EthClientObject + Send + Sync>> {
+        let wallet = rpc.get_eth_wallet()?;
+        let full_name = if wallet.contains('.') {
+            wallet
+        } else {
+            wallet + "." + rpc.get_worker_name().as_str()
+        };
+
+        Some(Box::new(Self::authorize_worker(full_name)))
+    }
+
+    // mining.authorize of `wallet.worker`
+    pub fn authorize_worker(full_name: String) -> EthClientRootObject {
+        EthClientRootObject {
+            id: CLIENT_LOGIN,
+            method: "mining.authorize".into(),
+            params: vec![full_name, "x".into()],
+        }
+    }
+
+    pub fn handle_message(&mut self, buf: &str) -> UpstreamMessage {
+        if let Ok(notify) = serde_json::from_str::<EthStratumNotify>(buf) {
+            if notify.method == "mining.notify" {
+                return UpstreamMessage::Job(self.notify_to_job(&notify));
+            }
+        }
+
+        if let Ok(set) = serde_json::from_str::<EthStratumSetDifficulty>(buf) {
+            if set.method == "mining.set_difficulty" {
+                if let Some(diff) = set.params.first() {
+                    self.difficulty = *diff;
+                }
+                return UpstreamMessage::Difficulty;
+            }
+        }
+
+        if let Ok(set) = serde_json::from_str::<StraumMiningSet>(buf) {
+            if set.method == "mining.set_extranonce" {
+                if let Some(extranonce) = set.params.first() {
+                    self.extranonce = extranonce.clone();
+                }
+                return UpstreamMessage::Extranonce(self.extranonce.clone());
+            }
+        }
+
+        if let Ok(res) = serde_json::from_str::<EthSubscriptionNotify>(buf) {
+            if res.id == SUBSCRIBE {
+                self.extranonce = res.result.1;
+                self.subscribed = true;
+                return UpstreamMessage::Subscribed;
+            }
+        }
+
+        UpstreamMessage::Other
+    }
+
+    // mining.notify [job_id, seed, header, clean] to [header, seed, target]
+    pub fn notify_to_job(&mut self, notify: &EthStratumNotify) -> Vec<String> {
+        let (job_id, seed, header, clean) = &notify.params;
+        let header = format!("0x{}", header.trim_start_matches("0x"));
+
+        if *clean {
+            self.jobs.clear();
+        }
+        self.jobs.push_back((header.clone(), job_id.clone()));
+        while self.jobs.len() > MAX_JOBS {
+            self.jobs.pop_front();
+        }
+
+        vec![
+            header,
+            format!("0x{}", seed.trim_start_matches("0x")),
+            crate::util::diff_to_target(self.difficulty),
+        ]
+    }
+
+    // eth_submitWork [nonce, header, mix_hash] to mining.submit
+    // [worker, job_id, nonce without the pool extranonce]
+    pub fn work_to_submit(
+        &self, params: &[String], worker: &str,
+    ) -> Result<EthClientRootObject, RejectReason> {
+        let (nonce, header) = match (params.first(), params.get(1)) {
+            (Some(nonce), Some(header)) => (
+                nonce.trim_start_matches("0x"),
+                format!("0x{}", header.trim_start_matches("0x")),
+            ),
+            _ => return Err(RejectReason::Malformed),
+        };
+
+        let (_, job_id) = self
+            .jobs
+            .iter()
+            .rev()
+            .find(|(h, _)| *h == header)
+            .ok_or(RejectReason::Stale)?;
+        let nonce = nonce
+            .strip_prefix(self.extranonce.as_str())
+            .ok_or(RejectReason::Malformed)?;
+
+        Ok(EthClientRootObject {
+            id: CLIENT_SUBMITWORK,
+            method: "mining.submit".into(),
+            params: vec![worker.to_string(), job_id.clone(), nonce.to_string()],
+        })
+    }
+
+    pub fn set_extranonce_rpc(&self) -> StraumMiningSet {
+        StraumMiningSet {
+            id: Value::Null,
+            method: "mining.set_extranonce".into(),
+            params: vec![self.extranonce.clone()],
+        }
+    }
+}
+
+#[test]
+fn test_eth_stratum_upstream() {
+    let mut up = EthStratumUpstream::new();
+    let res = up.handle_message(
+        r#"{"id":10002,"result":[["mining.notify","ae6812eb4cd7735a302a8a9dd95cf71f","EthereumStratum/1.0.0"],"080c"],"error":null}"#,
+    );
+    assert_eq!(res, UpstreamMessage::Subscribed);
+    assert_eq!(up.extranonce, "080c");
+
+    let res = up.handle_message(
+        r#"{"id":null,"method":"mining.set_difficulty","params":[2]}"#,
+    );
+    assert_eq!(res, UpstreamMessage::Difficulty);
+
+    let res = up.handle_message(
+        r#"{"id":null,"method":"mining.notify","params":["bf0488aa","abad8f99f3918bf903c6a909d9bbc0fdfa5a2f4b9cb1196175ec825c6610126c","645cf20198c2f3861e947d4f67e3ab63b7b2e24dcc9095bd9123e7b33371f6cc",true]}"#,
+    );
+    let job = match res {
+        UpstreamMessage::Job(job) => job,
+        _ => panic!("expected a job"),
+    };
+    assert_eq!(
+        job[0],
+        "0x645cf20198c2f3861e947d4f67e3ab63b7b2e24dcc9095bd9123e7b33371f6cc"
+    );
+    assert!((crate::util::target_to_diff(&job[2]) - 2.0).abs() < 1e-6);
+
+    let submit = up
+        .work_to_submit(
+            &["0x080c6b7d2e8a91f0".into(), job[0].clone(), "0x00".into()],
+            "0xabc.rig1",
+        )
+        .unwrap();
+    assert_eq!(submit.params, vec!["0xabc.rig1", "bf0488aa", "6b7d2e8a91f0"]);
+
+    // Nonce outside the extranonce range of the pool
+    assert_eq!(
+        up.work_to_submit(&["0x1111111111111111".into(), job[0].clone()], "w")
+            .unwrap_err(),
+        RejectReason::Malformed
+    );
+    assert_eq!(
+        up.work_to_submit(&["0x080c6b7d2e8a91f0".into(), "0x00".into()], "w")
+            .unwrap_err(),
+        RejectReason::Stale
+    );
+
+    let res = up.handle_message(
+        r#"{"id":null,"method":"mining.set_extranonce","params":["af4c"]}"#,
+    );
+    assert_eq!(res, UpstreamMessage::Extranonce("af4c".into()));
+}
@@ -1,6 +1,13 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use super::stratum::StraumMiningSet;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EthLoginNotify {
@@ -16,3 +23,243 @@ pub struct EthSubscriptionNotify {
     pub result: (Vec<String>, String),
     pub error: Value,
 }
+
+// EthereumStratum/1.0.0 (NiceHash) messages sent from the proxy to the miner.
+
+//{"id":1,"result":[["mining.notify","ae6812eb4cd7735a302a8a9dd95cf71f","EthereumStratum/1.0.0"],"080c"],"error":null}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthStratumSubscribeResult {
+    pub id: u64,
+    pub result: ((String, String, String), String),
+    pub error: Value,
+}
+
+//{"id":2,"result":true,"error":null}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthStratumResult {
+    pub id: u64,
+    pub result: bool,
+    pub error: Value,
+}
+
+//{"id":null,"method":"mining.set_difficulty","params":[0.5]}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthStratumSetDifficulty {
+    pub id: Value,
+    pub method: String,
+    pub params: Vec<f64>,
+}
+
+//{"id":null,"method":"mining.notify","params":["bf0488aa","abad8f99...","645cf201...",true]}
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthStratumNotify {
+    pub id: Value,
+    pub method: String,
+    pub params: (String, String, String, bool),
+}
+
+static EXTRANONCE_IDX: AtomicU32 = AtomicU32::new(0);
+
+// Jobs remembered with the extranonce they were sent under
+const MAX_SESSION_JOBS: usize = 16;
+
+// State of one EthereumStratum/1.0.0 miner connected to the proxy. Jobs are
+// kept in the eth_getWork form ([header, seed, target, height]) everywhere
+// else, so this only converts at the edge of the miner connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EthStratumSession {
+    pub session_id: String,
+    // Nonce prefix the miner currently works with
+    pub extranonce: String,
+    // 2 bytes of the miner's own, appended to the extranonce of the fee and
+    // developer pools so miners that receive the same fee job never search
+    // the same nonce range
+    pub suffix: String,
+    pub difficulty: f64,
+    pub authorized: bool,
+    // (header, extranonce) of the jobs sent
+    jobs: VecDeque<(String, String)>,
+}
+
+impl EthStratumSession {
+    pub fn new() -> Self {
+        let idx = EXTRANONCE_IDX.fetch_add(1, Ordering::Relaxed);
+        use rand::SeedableRng;
+        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
+        let session_id: u128 = rand::Rng::gen(&mut rng);
+        let suffix = format!("{:04x}", idx & 0xffff);
+
+        Self {
+            session_id: format!("{:032x}", session_id),
+            extranonce: suffix.clone(),
+            suffix,
+            difficulty: 0.0,
+            authorized: false,
+            jobs: VecDeque::new(),
+        }
+    }
+
+    // The mining.set_extranonce that moves the miner to `extranonce`, if it
+    // is not there already. Applies from the next job.
+    pub fn switch_extranonce(&mut self, extranonce: &str) -> Option<StraumMiningSet> {
+        if self.extranonce == extranonce {
+            return None;
+        }
+        self.extranonce = extranonce.to_string();
+        Some(StraumMiningSet {
+            id: Value::Null,
+            method: "mining.set_extranonce".into(),
+            params: vec![self.extranonce.clone()],
+        })
+    }
+
+    // Nonce prefix for a job of a fee or developer pool that assigned
+    // `pool`. The suffix is left out when it would leave the miner less than
+    // 4 bytes of nonce.
+    pub fn fee_extranonce(&self, pool: &str) -> String {
+        if pool.len() + self.suffix.len() <= 8 {
+            format!("{}{}", pool, self.suffix)
+        } else {
+            pool.to_string()
+        }
+    }
+
+    pub fn subscribe_result(&self, id: u64) -> EthStratumSubscribeResult {
+        EthStratumSubscribeResult {
+            id,
+            result: (
+                (
+                    "mining.notify".into(),
+                    self.session_id.clone(),
+                    "EthereumStratum/1.0.0".into(),
+                ),
+                self.extranonce.clone(),
+            ),
+            error: Value::Null,
+        }
+    }
+
+    // Convert an eth_getWork job into mining.notify. A mining.set_difficulty
+    // is returned as well when the job boundary differs from the last one
+    // sent to the miner.
+    pub fn job_to_notify(
+        &mut self, job: &[String],
+    ) -> Option<(Option<EthStratumSetDifficulty>, EthStratumNotify)> {
+        let header = job.first()?;
+        let seed = job.get(1)?;
+        let target = job.get(2)?;
+
+        let mut set_diff = None;
+        let diff = crate::util::target_to_diff(target);
+        if diff > 0.0 && (diff - self.difficulty).abs() > f64::EPSILON {
+            self.difficulty = diff;
+            set_diff = Some(EthStratumSetDifficulty {
+                id: Value::Null,
+                method: "mining.set_difficulty".into(),
+                params: vec![diff],
+            });
+        }
+
+        let header = header.trim_start_matches("0x").to_string();
+        self.jobs.push_back((header.clone(), self.extranonce.clone()));
+        while self.jobs.len() > MAX_SESSION_JOBS {
+            self.jobs.pop_front();
+        }
+        let notify = EthStratumNotify {
+            id: Value::Null,
+            method: "mining.notify".into(),
+            params: (
+                header.clone(),
+                seed.trim_start_matches("0x").to_string(),
+                header,
+                true,
+            ),
+        };
+
+        Some((set_diff, notify))
+    }
+
+    // Convert mining.submit params [worker, job_id, nonce] into the
+    // eth_submitWork params [nonce, header, mix_hash]. The job id is the
+    // header hash and the nonce gets the extranonce the job was sent under.
+    // EthereumStratum/1.0.0 does not carry the mix hash, so it is only there
+    // when the miner appends it as a fourth param. Without it the share is
+    // only good for a pool that speaks EthereumStratum too.
+    pub fn submit_to_work(&self, params: &[String]) -> Option<Vec<String>> {
+        let job_id = params.get(1)?.trim_start_matches("0x");
+        let nonce = params.get(2)?.trim_start_matches("0x");
+        if job_id.is_empty() || nonce.is_empty() {
+            return None;
+        }
+
+        let extranonce = self
+            .jobs
+            .iter()
+            .rev()
+            .find(|(header, _)| header == job_id)
+            .map_or(self.extranonce.as_str(), |(_, extranonce)| extranonce.as_str());
+        let nonce = if nonce.len() + extranonce.len() <= 16 {
+            format!("0x{}{}", extranonce, nonce)
+        } else {
+            format!("0x{}", nonce)
+        };
+
+        let mut work = vec![nonce, format!("0x{}", job_id)];
+        if let Some(mix) = params.get(3) {
+            work.push(format!("0x{}", mix.trim_start_matches("0x")));
+        }
+        Some(work)
+    }
+}
+
+impl Default for EthStratumSession {
+    fn default() -> Self { Self::new() }
+}
+
+#[test]
+fn test_eth_stratum_session() {
+    let mut session = EthStratumSession::new();
+    session.extranonce = "080c".into();
+
+    let job = vec![
+        "0x645cf20198c2f3861e947d4f67e3ab63b7b2e24dcc9095bd9123e7b33371f6cc"
+            .to_string(),
+        "0xabad8f99f3918bf903c6a909d9bbc0fdfa5a2f4b9cb1196175ec825c6610126c"
+            .to_string(),
+        "0x000000007fffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            .to_string(),
+    ];
+
+    let (set_diff, notify) = session.job_to_notify(&job).unwrap();
+    assert!(set_diff.is_some());
+    assert_eq!(notify.params.0, job[0][2..]);
+    assert_eq!(notify.params.1, job[1][2..]);
+
+    // Same boundary again does not resend the difficulty.
+    let (set_diff, _) = session.job_to_notify(&job).unwrap();
+    assert!(set_diff.is_none());
+
+    let work = session
+        .submit_to_work(&[
+            "0xabc.rig1".to_string(),
+            notify.params.0.clone(),
+            "6b7d2e8a91f0".to_string(),
+        ])
+        .unwrap();
+    assert_eq!(work[0], "0x080c6b7d2e8a91f0");
+    assert_eq!(work[1], job[0]);
+    // No made up mix hash
+    assert_eq!(work.len(), 2);
+
+    // A share for a job sent before the miner moved to a fee extranonce
+    let set = session.switch_extranonce("af4c0001").unwrap();
+    assert_eq!(set.params, vec!["af4c0001"]);
+    assert!(session.switch_extranonce("af4c0001").is_none());
+    let work = session
+        .submit_to_work(&["w".to_string(), notify.params.0.clone(), "6b7d2e8a".to_string()])
+        .unwrap();
+    assert_eq!(work[0], "0x080c6b7d2e8a");
+
+    assert_eq!(session.fee_extranonce("af4c"), format!("af4c{}", session.suffix));
+    assert_eq!(session.fee_extranonce("af4c01"), "af4c01");
+}
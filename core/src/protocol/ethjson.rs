@@ -1,5 +1,6 @@
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::io::{AsyncWrite, WriteHalf};
 
 use super::{
@@ -331,6 +332,23 @@ pub struct EthServer {
     pub result: bool,
 }
 
+// Any pool answer to a request. Depending on the pool `result` is a bool or
+// null and `error` is null, a string, an object or a [code, message] array.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthServerResponse {
+    pub id: u64,
+    #[serde(default)]
+    pub result: Value,
+    #[serde(default)]
+    pub error: Value,
+}
+
+impl EthServerResponse {
+    pub fn is_accepted(&self) -> bool {
+        self.result == Value::Bool(true) && self.error.is_null()
+    }
+}
+
 pub async fn new_eth_submit_work<W, W2>(
     _worker: &mut Worker, pool_w: &mut WriteHalf<W>,
     _worker_w: &mut WriteHalf<W2>, rpc: &mut Box<EthClientWorkerObject>,
@@ -1,3 +1,4 @@
+pub mod bridge;
 pub mod eth_stratum;
 pub mod ethjson;
 pub mod rpc;
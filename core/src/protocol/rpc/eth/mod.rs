@@ -395,40 +395,127 @@ pub struct ServerRootErrorValue {
     pub error: String,
 }
 
-pub fn handle_error(worker_id: u64, buf: &[u8]) {
-    if let Ok(rpc) =
-        serde_json::from_slice::<crate::protocol::rpc::eth::ServerError>(&buf)
-    {
-        tracing::warn!("Pumping Miner {} Share Reject: {}", worker_id, rpc.error);
-    } else if let Ok(_rpc) =
-        serde_json::from_slice::<crate::protocol::rpc::eth::ServerRoot>(&buf)
-    {
-        //tracing::warn!("water pumping machine {} Share Reject: {}", worker_id, rpc.error);
-    } else if let Ok(rpc) = serde_json::from_slice::<
-        crate::protocol::rpc::eth::ServerRootError,
-    >(&buf)
-    {
-        tracing::warn!("water pumping machine {} Share Reject: {}", worker_id, rpc.error.1);
-    } else {
-        tracing::warn!("water pumping machine {} Share Reject: {:?}", worker_id, buf);
+// Why a pool refused a share
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    Stale,
+    LowDifficulty,
+    Duplicate,
+    Unauthorized,
+    Malformed,
+    Other,
+}
+
+impl RejectReason {
+    // Classify by message first, pools reuse the stratum codes loosely.
+    // 21 job not found, 22 duplicate, 23 low difficulty, 24 unauthorized,
+    // 25 not subscribed.
+    pub fn classify(code: Option<i64>, message: &str) -> Self {
+        let message = message.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|w| message.contains(w));
+
+        if has(&["stale", "job not found", "unknown job", "old job", "expired"]) {
+            RejectReason::Stale
+        } else if has(&["duplicate", "already submitted"]) {
+            RejectReason::Duplicate
+        } else if has(&["difficulty", "above target", "high-hash", "low share"]) {
+            RejectReason::LowDifficulty
+        } else if has(&["unauthorized", "not authorized", "not subscribed", "not logged", "login"]) {
+            RejectReason::Unauthorized
+        } else if has(&["malformed", "invalid", "bad", "parse", "wrong"]) {
+            RejectReason::Malformed
+        } else {
+            match code {
+                Some(21) => RejectReason::Stale,
+                Some(22) => RejectReason::Duplicate,
+                Some(23) => RejectReason::LowDifficulty,
+                Some(24) | Some(25) => RejectReason::Unauthorized,
+                _ => RejectReason::Other,
+            }
+        }
+    }
+
+    // `error` as sent by the pool: a string, {code, message} or
+    // [code, message, data].
+    pub fn from_error(error: &Value) -> Self {
+        match error {
+            Value::String(message) => Self::classify(None, message),
+            Value::Object(o) => Self::classify(
+                o.get("code").and_then(Value::as_i64),
+                o.get("message").and_then(Value::as_str).unwrap_or_default(),
+            ),
+            Value::Array(a) => Self::classify(
+                a.first().and_then(Value::as_i64),
+                a.get(1).and_then(Value::as_str).unwrap_or_default(),
+            ),
+            _ => RejectReason::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            RejectReason::Stale => "stale",
+            RejectReason::LowDifficulty => "low difficulty",
+            RejectReason::Duplicate => "duplicate",
+            RejectReason::Unauthorized => "unauthorized",
+            RejectReason::Malformed => "malformed",
+            RejectReason::Other => "other",
+        };
+        write!(f, "{}", reason)
     }
 }
 
-pub fn handle_error_for_worker(worker_name: &String, buf: &[u8]) {
-    if let Ok(rpc) =
-        serde_json::from_slice::<crate::protocol::rpc::eth::ServerError>(&buf)
-    {
-        tracing::warn!("mining machine {} Share Reject: {}", worker_name, rpc.error);
-    } else if let Ok(_rpc) =
-        serde_json::from_slice::<crate::protocol::rpc::eth::ServerRoot>(&buf)
-    {
-        //tracing::warn!("mining machine {} Share Reject: {}", worker_name, rpc.error);
-    } else if let Ok(rpc) = serde_json::from_slice::<
-        crate::protocol::rpc::eth::ServerRootError,
-    >(&buf)
-    {
-        tracing::warn!("mining machine {} Share Reject: {}", worker_name, rpc.error.1);
-    } else {
-        tracing::warn!("mining machine {} Share Reject: {:?}", worker_name, buf);
+fn reject_reason(buf: &[u8]) -> (RejectReason, Value) {
+    match serde_json::from_slice::<Value>(buf) {
+        Ok(rpc) => {
+            let error = rpc.get("error").cloned().unwrap_or(Value::Null);
+            (RejectReason::from_error(&error), error)
+        }
+        Err(_) => (RejectReason::Other, Value::Null),
     }
 }
+
+pub fn handle_error(worker_id: u64, buf: &[u8]) -> RejectReason {
+    let (reason, error) = reject_reason(buf);
+    tracing::warn!(
+        "water pumping machine {} Share Reject ({}): {}",
+        worker_id,
+        reason,
+        error
+    );
+    reason
+}
+
+pub fn handle_error_for_worker(worker_name: &String, buf: &[u8]) -> RejectReason {
+    let (reason, error) = reject_reason(buf);
+    tracing::warn!(
+        "mining machine {} Share Reject ({}): {}",
+        worker_name,
+        reason,
+        error
+    );
+    reason
+}
+
+#[test]
+fn test_reject_reason() {
+    let error: Value =
+        serde_json::from_str(r#"[21,"Job not found (=stale)",null]"#).unwrap();
+    assert_eq!(RejectReason::from_error(&error), RejectReason::Stale);
+
+    let error: Value = serde_json::from_str(
+        r#"{"code":-1,"message":"Low difficulty share"}"#,
+    )
+    .unwrap();
+    assert_eq!(RejectReason::from_error(&error), RejectReason::LowDifficulty);
+
+    let error = Value::String("Duplicate share".into());
+    assert_eq!(RejectReason::from_error(&error), RejectReason::Duplicate);
+
+    let error: Value = serde_json::from_str(r#"[24,"",null]"#).unwrap();
+    assert_eq!(RejectReason::from_error(&error), RejectReason::Unauthorized);
+
+    assert_eq!(RejectReason::from_error(&Value::Null), RejectReason::Other);
+}
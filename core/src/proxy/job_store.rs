@@ -0,0 +1,152 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::RwLock,
+};
+
+use tokio::sync::watch;
+
+use crate::protocol::ethjson::EthServerRootObjectJsonRpc;
+
+// Jobs kept when the pool sends no height. Enough to match late shares.
+const MAX_JOBS: usize = 32;
+
+#[derive(Default)]
+struct Jobs {
+    // job id -> (height, eth_getWork result)
+    by_id: HashMap<String, (u64, Vec<String>)>,
+    // Job ids, oldest first
+    order: VecDeque<String>,
+    height: u64,
+}
+
+// Latest jobs of the fee or developer pool. A job is
+// `[header, seed, target, height?]` as returned by eth_getWork. Jobs below the
+// newest height are dropped as soon as it is seen.
+pub struct JobStore {
+    jobs: RwLock<Jobs>,
+    // Bumped on every new job
+    notify: watch::Sender<u64>,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        let (notify, _) = watch::channel(0);
+        Self {
+            jobs: RwLock::new(Jobs::default()),
+            notify,
+        }
+    }
+}
+
+impl JobStore {
+    // Returns false for a job already known, which eth_getWork polling sends
+    // again and again.
+    pub fn push(&self, job: Vec<String>) -> bool {
+        let job_id = match job.first() {
+            Some(id) => id.clone(),
+            None => return false,
+        };
+        let height = job_height(&job);
+
+        {
+            let mut jobs = self.jobs.write().unwrap();
+            if jobs.by_id.contains_key(&job_id) {
+                return false;
+            }
+            // A late job of a block that is already gone
+            if height != 0 && height < jobs.height {
+                return false;
+            }
+
+            if height > jobs.height {
+                jobs.height = height;
+                let Jobs { by_id, order, .. } = &mut *jobs;
+                by_id.retain(|_, (h, _)| *h == 0 || *h >= height);
+                order.retain(|id| by_id.contains_key(id));
+            }
+
+            jobs.order.push_back(job_id.clone());
+            jobs.by_id.insert(job_id, (height, job));
+            while jobs.order.len() > MAX_JOBS {
+                if let Some(id) = jobs.order.pop_front() {
+                    jobs.by_id.remove(&id);
+                }
+            }
+        }
+
+        let seq = *self.notify.borrow() + 1;
+        self.notify.send_replace(seq);
+        true
+    }
+
+    // Newest job at `min_height` or above. Jobs without a height always
+    // qualify, as does any job when the miner's height is unknown (0).
+    pub fn latest(&self, min_height: u64) -> Option<Vec<String>> {
+        let jobs = self.jobs.read().unwrap();
+        jobs.order.iter().rev().find_map(|id| match jobs.by_id.get(id) {
+            Some((height, job)) if *height == 0 || *height >= min_height => {
+                Some(job.clone())
+            }
+            _ => None,
+        })
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<Vec<String>> {
+        let jobs = self.jobs.read().unwrap();
+        jobs.by_id.get(job_id).map(|(_, job)| job.clone())
+    }
+
+    // Highest height seen, 0 if the pool sends none
+    pub fn height(&self) -> u64 { self.jobs.read().unwrap().height }
+
+    pub fn len(&self) -> usize { self.jobs.read().unwrap().order.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    // Changes whenever a new job is stored
+    pub fn subscribe(&self) -> watch::Receiver<u64> { self.notify.subscribe() }
+}
+
+// Block height of an eth_getWork result, 0 if the pool does not send it
+pub fn job_height(job: &[String]) -> u64 {
+    EthServerRootObjectJsonRpc {
+        result: job.to_vec(),
+        ..Default::default()
+    }
+    .get_hight()
+}
+
+#[test]
+fn test_job_store() {
+    let job = |id: &str, height: u64| {
+        vec![id.to_string(), "0xseed".into(), "0xtarget".into(), format!("{:#x}", height)]
+    };
+
+    let store = JobStore::default();
+    let mut updates = store.subscribe();
+    assert!(store.latest(0).is_none());
+
+    assert!(store.push(job("0x01", 100)));
+    assert!(!store.push(job("0x01", 100)));
+    assert!(store.push(job("0x02", 100)));
+    assert!(updates.has_changed().unwrap());
+    assert_eq!(*updates.borrow_and_update(), 2);
+
+    // A new block evicts the jobs of the old one
+    assert!(store.push(job("0x03", 101)));
+    assert_eq!(store.len(), 1);
+    assert!(store.get("0x01").is_none());
+    assert_eq!(store.height(), 101);
+    assert!(!store.push(job("0x04", 100)));
+
+    assert_eq!(store.latest(101).unwrap()[0], "0x03");
+    assert!(store.latest(102).is_none());
+
+    // Pools without a height are bounded by count
+    let store = JobStore::default();
+    for i in 0..100 {
+        store.push(vec![format!("0x{:x}", i), "0xseed".into(), "0xtarget".into()]);
+    }
+    assert_eq!(store.len(), MAX_JOBS);
+    assert_eq!(store.latest(500).unwrap()[0], "0x63");
+}
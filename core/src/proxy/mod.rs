@@ -1,21 +1,167 @@
-use std::{sync::Arc, collections::VecDeque};
+pub mod job_store;
 
-use tokio::sync::{broadcast::Sender, mpsc::UnboundedSender, RwLock, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
-use crate::{state::Worker, util::config::Settings};
+use anyhow::{bail, Result};
+use tokio::sync::{
+    broadcast::Sender,
+    mpsc::{self, Receiver, UnboundedSender},
+    watch, RwLock,
+};
 
-pub type Job =    Arc<RwLock<VecDeque<Vec<String>>>>;
+use self::job_store::JobStore;
+
+use crate::{
+    client::{
+        pool_manager::{PoolManager, PoolStatus},
+        router::Router,
+    },
+    ledger::{Destination, FeeShare, Ledger},
+    state::Worker,
+    util::config::Settings,
+};
+
+pub type Job = Arc<JobStore>;
+
+// Fee and developer pools in one dialect. Filled by the fee loops, read by
+// the miner sessions whose pool speaks that dialect.
+pub struct FeeRounds {
+    pub fee_job: Job,
+    pub develop_job: Job,
+    pub tx: mpsc::Sender<FeeShare>,
+    pub dev_tx: mpsc::Sender<FeeShare>,
+    // Nonce prefixes assigned by EthereumStratum/1.0.0 pools, empty otherwise
+    pub fee_extranonce: watch::Sender<String>,
+    pub develop_extranonce: watch::Sender<String>,
+    // The fee loops connect once a session needs them
+    wanted: watch::Sender<bool>,
+}
+
+impl FeeRounds {
+    // Also returns the receivers of the fee and developer shares
+    pub fn new() -> (Self, Receiver<FeeShare>, Receiver<FeeShare>) {
+        let (tx, rx) = mpsc::channel::<FeeShare>(15);
+        let (dev_tx, dev_rx) = mpsc::channel::<FeeShare>(15);
+        let rounds = Self {
+            fee_job: Arc::new(JobStore::default()),
+            develop_job: Arc::new(JobStore::default()),
+            tx,
+            dev_tx,
+            fee_extranonce: watch::channel(String::new()).0,
+            develop_extranonce: watch::channel(String::new()).0,
+            wanted: watch::channel(false).0,
+        };
+        (rounds, rx, dev_rx)
+    }
+
+    pub fn want(&self) {
+        if !*self.wanted.borrow() {
+            self.wanted.send_replace(true);
+        }
+    }
+
+    pub async fn wanted(&self) {
+        let mut wanted = self.wanted.subscribe();
+        while !*wanted.borrow_and_update() {
+            if wanted.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn job(&self, destination: Destination) -> &Job {
+        match destination {
+            Destination::Develop => &self.develop_job,
+            _ => &self.fee_job,
+        }
+    }
+
+    pub fn extranonce(&self, destination: Destination) -> String {
+        match destination {
+            Destination::Develop => self.develop_extranonce.borrow().clone(),
+            _ => self.fee_extranonce.borrow().clone(),
+        }
+    }
+}
 
 
 pub struct Proxy {
     pub config: Arc<RwLock<Settings>>,
     // pub chan: Sender<Vec<String>>,
     // pub dev_chan: Sender<Vec<String>>,
-    pub fee_job:Job,
-    pub develop_job:Job,
-    pub tx: tokio::sync::mpsc::Sender<Vec<String>>,
-    pub dev_tx: tokio::sync::mpsc::Sender<Vec<String>>,
+    // Fee and developer rounds of sessions on an ETHProxy pool
+    pub eth_fee: FeeRounds,
+    // And on an EthereumStratum/1.0.0 pool
+    pub stratum_fee: FeeRounds,
+    pub ledger: Ledger,
     pub worker_tx: UnboundedSender<Worker>,
+    // Upstream for miner sessions
+    pub pool: Arc<PoolManager>,
+    // Upstream for the fee loop
+    pub fee_pool: Arc<PoolManager>,
+    // Per-customer upstreams picked at login
+    pub router: Router,
+    // Full names of miners the supervisor asked to disconnect
+    pub kick: Sender<String>,
+    // Set by the supervisor to stop taking new miners
+    pub draining: AtomicBool,
+    // Bumped after every config reload
+    pub reloaded: watch::Sender<u64>,
     // pub proxy_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
     // pub dev_write: Arc<Mutex<Box<dyn AsyncWrite + Send + Sync + Unpin>>>,
 }
+
+impl Proxy {
+    // Returns false when no miner session is running at all
+    pub fn kick_worker(&self, worker: &str) -> bool {
+        self.kick.send(worker.to_string()).is_ok()
+    }
+
+    pub fn drain(&self) { self.draining.store(true, Ordering::Relaxed) }
+
+    pub fn is_draining(&self) -> bool { self.draining.load(Ordering::Relaxed) }
+
+    // Validates and swaps in a new config. Returns the changed settings that
+    // only take effect after a restart.
+    pub async fn reload(&self, config: Settings) -> Result<Vec<&'static str>> {
+        config.check().await?;
+
+        let restart = {
+            let mut current = self.config.write().await;
+            if config.name != current.name {
+                bail!("The transit name cannot change");
+            }
+            self.pool.set_pools(&config.pool_address)?;
+            self.fee_pool.set_pools(&config.share_address)?;
+            current.reload(config)
+        };
+
+        let seq = *self.reloaded.borrow() + 1;
+        self.reloaded.send_replace(seq);
+        Ok(restart)
+    }
+
+    pub fn fee_rounds(&self, stratum: bool) -> &FeeRounds {
+        if stratum {
+            &self.stratum_fee
+        } else {
+            &self.eth_fee
+        }
+    }
+
+    // Miner, fee and route pools
+    pub fn pool_status(&self) -> Vec<PoolStatus> {
+        let mut status = self.pool.status();
+        status.extend(self.fee_pool.status());
+        for route in self.router.routes() {
+            status.extend(route.pools.status());
+        }
+        status
+    }
+
+    // Changes after every reload
+    pub fn subscribe_reloads(&self) -> watch::Receiver<u64> { self.reloaded.subscribe() }
+}
@@ -4,10 +4,15 @@ extern crate serde_millis;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::{
+    collections::VecDeque,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 use tracing::{debug, info};
 
-use crate::protocol::PROTOCOL;
+use crate::protocol::{rpc::eth::RejectReason, PROTOCOL};
+
+pub mod snapshot;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Worker {
@@ -16,9 +21,9 @@ pub struct Worker {
     pub worker_name: String,
     pub worker_wallet: String,
     pub protocol: PROTOCOL,
-    #[serde(with = "serde_millis")]
+    #[serde(with = "instant_millis")]
     pub login_time: Instant,
-    #[serde(with = "serde_millis")]
+    #[serde(with = "instant_millis")]
     pub last_subwork_time: Instant,
     pub rpc_id: u64,
     pub hash: u64,
@@ -32,6 +37,193 @@ pub struct Worker {
     pub fee_share_index: u64,
     pub fee_accept_index: u64,
     pub fee_invalid_index: u64,
+    // Shares found on developer jobs
+    #[serde(default)]
+    pub develop_share_index: u64,
+    #[serde(default)]
+    pub rejects: RejectCounts,
+    // Address of the pool the miner is currently mining on
+    #[serde(default)]
+    pub pool: String,
+    // How many times the session moved to another pool
+    #[serde(default)]
+    pub pool_switch: u64,
+    // Source address of the miner connection
+    #[serde(default)]
+    pub ip: String,
+    // Name of the routing rule that picked the pool. Empty for the default pools.
+    #[serde(default)]
+    pub route: String,
+    // Difficulty of the accepted shares over time. Stays in the proxy
+    // process, only `effective_hash` is reported.
+    #[serde(skip)]
+    pub work: ShareWork,
+    // Hashrate proven by accepted shares, next to the self-reported `hash`
+    #[serde(default)]
+    pub effective_hash: EffectiveHash,
+    // Unix seconds of the last report from the transit. Set by the web
+    // supervisor and kept across restarts.
+    #[serde(default)]
+    pub last_seen: u64,
+}
+
+// Rejected shares by reason
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RejectCounts {
+    pub stale: u64,
+    pub low_difficulty: u64,
+    pub duplicate: u64,
+    pub unauthorized: u64,
+    pub malformed: u64,
+    pub other: u64,
+}
+
+impl RejectCounts {
+    pub fn add(&mut self, reason: RejectReason) {
+        match reason {
+            RejectReason::Stale => self.stale += 1,
+            RejectReason::LowDifficulty => self.low_difficulty += 1,
+            RejectReason::Duplicate => self.duplicate += 1,
+            RejectReason::Unauthorized => self.unauthorized += 1,
+            RejectReason::Malformed => self.malformed += 1,
+            RejectReason::Other => self.other += 1,
+        }
+    }
+}
+
+// Instants as Unix milliseconds, the format of serde_millis. One from
+// before the boot of this machine, e.g. out of a snapshot, reads as now
+// instead of panicking.
+mod instant_millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, Instant};
+
+    pub fn serialize<S>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer {
+        serde_millis::serialize(instant, serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Instant, D::Error>
+    where D: Deserializer<'de> {
+        let millis = u64::deserialize(deserializer)?;
+        let now = super::unix_secs() as u128 * 1000;
+        let age = Duration::from_millis(now.saturating_sub(millis as u128) as u64);
+        Ok(Instant::now().checked_sub(age).unwrap_or_else(Instant::now))
+    }
+}
+
+// Hashes per share of difficulty 1 (EthereumStratum/1.0.0 difficulty)
+const HASHES_PER_DIFFICULTY: f64 = 4294967296.0;
+
+// Effective hashrate in H/s over sliding windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct EffectiveHash {
+    pub m5: u64,
+    pub h1: u64,
+    pub h24: u64,
+}
+
+impl std::ops::AddAssign for EffectiveHash {
+    fn add_assign(&mut self, other: Self) {
+        self.m5 += other.m5;
+        self.h1 += other.h1;
+        self.h24 += other.h24;
+    }
+}
+
+// Work summed into fixed time buckets, covering `span` seconds
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorkWindow {
+    span: u64,
+    bucket: u64,
+    // (bucket start in Unix seconds, difficulty)
+    buckets: VecDeque<(u64, f64)>,
+}
+
+impl WorkWindow {
+    pub fn new(span: u64, bucket: u64) -> Self {
+        Self {
+            span,
+            bucket,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, now: u64, difficulty: f64) {
+        let start = now - now % self.bucket;
+        match self.buckets.back_mut() {
+            Some((s, work)) if *s == start => *work += difficulty,
+            _ => self.buckets.push_back((start, difficulty)),
+        }
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: u64) {
+        while let Some((start, _)) = self.buckets.front() {
+            if start + self.bucket + self.span > now {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    // H/s over the window, or over `age` seconds for a younger session
+    pub fn hashrate(&mut self, now: u64, age: u64) -> u64 {
+        self.expire(now);
+        let work: f64 = self.buckets.iter().map(|(_, work)| work).sum();
+        let secs = self.span.min(age).max(60);
+        (work * HASHES_PER_DIFFICULTY / secs as f64) as u64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareWork {
+    // Unix seconds of the first share, 0 before
+    start: u64,
+    m5: WorkWindow,
+    h1: WorkWindow,
+    h24: WorkWindow,
+}
+
+impl Default for ShareWork {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            m5: WorkWindow::new(300, 10),
+            h1: WorkWindow::new(3600, 60),
+            h24: WorkWindow::new(86400, 900),
+        }
+    }
+}
+
+impl ShareWork {
+    pub fn add(&mut self, now: u64, difficulty: f64) {
+        if self.start == 0 {
+            self.start = now;
+        }
+        self.m5.add(now, difficulty);
+        self.h1.add(now, difficulty);
+        self.h24.add(now, difficulty);
+    }
+
+    pub fn hashrate(&mut self, now: u64) -> EffectiveHash {
+        if self.start == 0 {
+            return EffectiveHash::default();
+        }
+        let age = now.saturating_sub(self.start);
+        EffectiveHash {
+            m5: self.m5.hashrate(now, age),
+            h1: self.h1.hashrate(now, age),
+            h24: self.h24.hashrate(now, age),
+        }
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 impl Worker {
@@ -57,6 +249,15 @@ impl Worker {
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            develop_share_index: 0,
+            rejects: RejectCounts::default(),
+            pool: "".into(),
+            pool_switch: 0,
+            ip: "".into(),
+            route: "".into(),
+            work: ShareWork::default(),
+            effective_hash: EffectiveHash::default(),
+            last_seen: 0,
             rpc_id: 0,
         }
     }
@@ -80,6 +281,15 @@ impl Worker {
             fee_share_index: 0,
             fee_accept_index: 0,
             fee_invalid_index: 0,
+            develop_share_index: 0,
+            rejects: RejectCounts::default(),
+            pool: "".into(),
+            pool_switch: 0,
+            ip: "".into(),
+            route: "".into(),
+            work: ShareWork::default(),
+            effective_hash: EffectiveHash::default(),
+            last_seen: 0,
             rpc_id: 0,
         }
     }
@@ -129,6 +339,15 @@ impl Worker {
         true
     }
 
+    pub fn seen(&mut self) { self.last_seen = unix_secs(); }
+
+    // The upstream dropped and the session continues on another pool
+    pub fn pool_switched(&mut self, pool: String) {
+        info!("Miner: {} moved from pool {} to {}", self.worker, self.pool, pool);
+        self.pool = pool;
+        self.pool_switch += 1;
+    }
+
     // Set the current link protocol
     pub fn set_protocol(&mut self, p: PROTOCOL) { self.protocol = p; }
 
@@ -144,6 +363,7 @@ impl Worker {
         self.share_index = 0;
         self.accept_index = 0;
         self.invalid_index = 0;
+        self.rejects = RejectCounts::default();
         //self.login_time = Instant::now();
     }
 
@@ -161,12 +381,28 @@ impl Worker {
         debug!("Miner: {} Share Accept #{}", self.worker, self.share_index);
     }
 
+    // Work of a share the pool accepted, weighted by its job difficulty
+    pub fn add_work(&mut self, difficulty: f64) {
+        self.work.add(unix_secs(), difficulty);
+    }
+
+    // Refresh `effective_hash` before the state is reported
+    pub fn update_effective_hash(&mut self) {
+        self.effective_hash = self.work.hashrate(unix_secs());
+    }
+
     // rejected share
     pub fn share_reject(&mut self) {
         self.invalid_index += 1;
         debug!("Miner: {} Share Reject #{}", self.worker, self.share_index);
     }
 
+    // rejected share with the reason given by the pool
+    pub fn share_reject_reason(&mut self, reason: RejectReason) {
+        self.share_reject();
+        self.rejects.add(reason);
+    }
+
     // total share increase
     pub fn fee_share_index_add(&mut self) {
         //self.last_subwork_time = Instant::now();
@@ -175,6 +411,8 @@ impl Worker {
         //debug!("Miners: {} Share #{}", self.worker, self.share_index);
     }
 
+    pub fn develop_share_index_add(&mut self) { self.develop_share_index += 1; }
+
     // take share
     pub fn fee_share_accept(&mut self) {
         self.fee_accept_index += 1;
@@ -238,3 +476,51 @@ fn test_share_reject() {
     assert_eq!(w.accept_index, 0);
     assert_eq!(w.invalid_index, 1);
 }
+
+#[test]
+fn test_share_reject_reason() {
+    let mut w = Worker::default();
+    w.share_reject_reason(RejectReason::Stale);
+    w.share_reject_reason(RejectReason::Stale);
+    w.share_reject_reason(RejectReason::Duplicate);
+    assert_eq!(w.invalid_index, 3);
+    assert_eq!(w.rejects.stale, 2);
+    assert_eq!(w.rejects.duplicate, 1);
+    assert_eq!(w.rejects.other, 0);
+}
+
+#[test]
+fn test_pool_switched() {
+    let mut w = Worker::default();
+    w.pool = "eth.f2pool.com:6688".into();
+    w.pool_switched("asia1.ethermine.org:4444".into());
+    assert_eq!(w.pool, "asia1.ethermine.org:4444");
+    assert_eq!(w.pool_switch, 1);
+}
+
+#[test]
+fn test_effective_hash() {
+    let mut work = ShareWork::default();
+    assert_eq!(work.hashrate(1000), EffectiveHash::default());
+
+    // 100 MH/s finds a share of difficulty 4 (4 * 2^32 hashes) every ~172 s
+    let start = 1_600_000_000;
+    let diff = 4.0;
+    let interval = (diff * HASHES_PER_DIFFICULTY / 100e6) as u64;
+    let mut now = start;
+    while now < start + 86400 {
+        work.add(now, diff);
+        now += interval;
+    }
+
+    let hash = work.hashrate(now);
+    for rate in [hash.h1, hash.h24] {
+        assert!((rate as f64 - 100e6).abs() < 100e6 * 0.05, "{}", rate);
+    }
+    assert!(hash.m5 > 0);
+
+    // Hours after the rig stopped only the 24 h window remembers it
+    let hash = work.hashrate(now + 7200);
+    assert_eq!((hash.m5, hash.h1), (0, 0));
+    assert!(hash.h24 > 0);
+}
@@ -0,0 +1,92 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::Result;
+
+use super::{unix_secs, Worker};
+use crate::web::AppState;
+
+// Miners of every transit, saved by the web supervisor next to configs.yaml
+pub const SNAPSHOT_FILE: &str = "workers.json";
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+// Rigs offline for longer are forgotten
+const KEEP_OFFLINE: u64 = 7 * 24 * 3600;
+
+pub type Snapshot = HashMap<String, Vec<Worker>>;
+
+pub fn take(app: &AppState) -> Snapshot {
+    let now = unix_secs();
+    let proxy_server = app.lock().unwrap();
+    proxy_server
+        .iter()
+        .map(|(name, server)| {
+            let workers = server
+                .workers
+                .iter()
+                .filter(|w| w.is_online() || w.last_seen + KEEP_OFFLINE > now)
+                .cloned()
+                .collect();
+            (name.clone(), workers)
+        })
+        .collect()
+}
+
+// Written to a temporary file first so a crash never leaves half a snapshot
+pub fn save(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(snapshot)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Every restored miner is offline until its transit reports it again. A
+// missing file is an empty snapshot.
+pub fn load(path: &Path) -> Result<Snapshot> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Snapshot::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut snapshot: Snapshot = serde_json::from_slice(&data)?;
+    for worker in snapshot.values_mut().flatten() {
+        worker.online = false;
+    }
+    Ok(snapshot)
+}
+
+// Put the saved miners back into the transits that are running
+pub fn restore(app: &AppState, mut snapshot: Snapshot) {
+    let mut proxy_server = app.lock().unwrap();
+    for (name, server) in proxy_server.iter_mut() {
+        if let Some(workers) = snapshot.remove(name) {
+            server.workers = workers;
+        }
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let path = std::env::temp_dir().join(format!("workers_test_{}.json", unix_secs()));
+
+    let mut worker = Worker::new("0xabc.rig1".into(), "rig1".into(), "0xabc".into(), true);
+    worker.accept_index = 42;
+    worker.last_seen = unix_secs();
+    let mut snapshot = Snapshot::new();
+    snapshot.insert("eth".into(), vec![worker]);
+    save(&path, &snapshot).unwrap();
+
+    let loaded = load(&path).unwrap();
+    let worker = &loaded["eth"][0];
+    assert_eq!(worker.accept_index, 42);
+    assert!(!worker.online);
+
+    // Saved long before the machine booted
+    let json = serde_json::to_string(worker).unwrap();
+    let start = json.find("\"login_time\":").unwrap() + "\"login_time\":".len();
+    let end = start + json[start..].find(',').unwrap();
+    let json = format!("{}1000{}", &json[..start], &json[end..]);
+    assert!(serde_json::from_str::<Worker>(&json).is_ok());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(load(&path).unwrap().is_empty());
+}
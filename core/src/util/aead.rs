@@ -0,0 +1,333 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use aes_gcm::{
+    aead::{Aead, NewAead, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{bail, Result};
+use ring::hkdf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// Sent before the salt. Bumped on any change to the framing.
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// Largest payload of one frame, longer writes are split
+const MAX_PLAIN: usize = 16 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// AES-256-GCM over any stream, between the encrypt_port of a transit and a
+// monitor. Both ends send a random salt first. The keys of each direction
+// come from the pre-shared key and both salts, so a recorded session cannot
+// be played to a new connection. Frames are a big endian u32 length, also
+// authenticated, and the sealed payload. Nonces are a counter per direction
+// that is never sent: a dropped, replayed or reordered frame fails to open.
+pub struct AeadStream<S> {
+    inner: S,
+    reader: Cipher,
+    writer: Cipher,
+    // Frame being read, its length prefix included
+    frame: Vec<u8>,
+    filled: usize,
+    // Opened payload not yet handed out
+    plain: Vec<u8>,
+    consumed: usize,
+    // Sealed frames not yet written
+    out: Vec<u8>,
+    written: usize,
+}
+
+impl<S> AeadStream<S>
+where S: AsyncRead + AsyncWrite + Unpin {
+    // Transit side
+    pub async fn accept(stream: S, psk: &str) -> Result<Self> {
+        Self::handshake(stream, psk, false).await
+    }
+
+    // Monitor side
+    pub async fn connect(stream: S, psk: &str) -> Result<Self> {
+        Self::handshake(stream, psk, true).await
+    }
+
+    async fn handshake(mut inner: S, psk: &str, client: bool) -> Result<Self> {
+        if psk.is_empty() {
+            bail!("No encryption key set");
+        }
+
+        let salt: [u8; SALT_LEN] = rand::random();
+        let mut hello = vec![VERSION];
+        hello.extend_from_slice(&salt);
+        inner.write_all(&hello).await?;
+        inner.flush().await?;
+
+        let mut peer = [0; 1 + SALT_LEN];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, inner.read_exact(&mut peer)).await??;
+        if peer[0] != VERSION {
+            bail!("Peer speaks encryption version {}, expected {}", peer[0], VERSION);
+        }
+
+        let mut salts = Vec::with_capacity(2 * SALT_LEN);
+        if client {
+            salts.extend_from_slice(&salt);
+            salts.extend_from_slice(&peer[1..]);
+        } else {
+            salts.extend_from_slice(&peer[1..]);
+            salts.extend_from_slice(&salt);
+        }
+        let upstream = Cipher::derive(psk, &salts, b"mining_proxy aead monitor to transit")?;
+        let downstream = Cipher::derive(psk, &salts, b"mining_proxy aead transit to monitor")?;
+        let (reader, writer) = if client {
+            (downstream, upstream)
+        } else {
+            (upstream, downstream)
+        };
+
+        Ok(Self {
+            inner,
+            reader,
+            writer,
+            frame: Vec::new(),
+            filled: 0,
+            plain: Vec::new(),
+            consumed: 0,
+            out: Vec::new(),
+            written: 0,
+        })
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.out.len() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.out[self.written..]) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct Cipher {
+    aead: Aes256Gcm,
+    counter: u64,
+}
+
+impl Cipher {
+    fn derive(psk: &str, salts: &[u8], info: &[u8]) -> Result<Self> {
+        let info = [info];
+        let mut key = [0; 32];
+        hkdf::Salt::new(hkdf::HKDF_SHA256, salts)
+            .extract(psk.as_bytes())
+            .expand(&info, hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key))
+            .map_err(|_| anyhow::anyhow!("Key derivation failed"))?;
+        Ok(Self {
+            aead: Aes256Gcm::new(Key::from_slice(&key)),
+            counter: 0,
+        })
+    }
+
+    fn nonce(&mut self) -> io::Result<[u8; 12]> {
+        if self.counter == u64::MAX {
+            return Err(invalid("Encrypted connection used up its nonces"));
+        }
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Ok(nonce)
+    }
+
+    fn seal(&mut self, plain: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let len = ((plain.len() + TAG_LEN) as u32).to_be_bytes();
+        let nonce = self.nonce()?;
+        let sealed = self
+            .aead
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad: &len })
+            .map_err(|_| invalid("Failed to encrypt a frame"))?;
+        out.extend_from_slice(&len);
+        out.extend_from_slice(&sealed);
+        Ok(())
+    }
+
+    fn open(&mut self, len: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce()?;
+        self.aead
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: sealed, aad: len })
+            .map_err(|_| invalid("Encrypted frame failed authentication, tampered or wrong key"))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn frame_len(header: &[u8]) -> io::Result<usize> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if !(TAG_LEN..=MAX_PLAIN + TAG_LEN).contains(&len) {
+        return Err(invalid("Encrypted frame of a wrong length"));
+    }
+    Ok(len)
+}
+
+impl<S> AsyncRead for AeadStream<S>
+where S: AsyncRead + AsyncWrite + Unpin {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.consumed < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.consumed);
+                buf.put_slice(&this.plain[this.consumed..this.consumed + n]);
+                this.consumed += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let want = if this.filled < 4 {
+                4
+            } else {
+                4 + frame_len(&this.frame[..4])?
+            };
+            if this.filled < want {
+                if this.frame.len() < want {
+                    this.frame.resize(want, 0);
+                }
+                let mut read = ReadBuf::new(&mut this.frame[this.filled..want]);
+                match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                let n = read.filled().len();
+                if n == 0 {
+                    // Closed between two frames is a clean end
+                    if this.filled == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.filled += n;
+                continue;
+            }
+
+            this.plain = this.reader.open(&this.frame[..4], &this.frame[4..want])?;
+            this.consumed = 0;
+            this.filled = 0;
+        }
+    }
+}
+
+impl<S> AsyncWrite for AeadStream<S>
+where S: AsyncRead + AsyncWrite + Unpin {
+    // Takes the whole buffer. What the socket does not take right away goes
+    // out on the next write or flush.
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map(|r| r.map(|_| 0)),
+        }
+        for chunk in buf.chunks(MAX_PLAIN) {
+            this.writer.seal(chunk, &mut this.out)?;
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+#[test]
+fn test_aead_stream() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (monitor, transit) = tokio::join!(
+            AeadStream::connect(a, "pre-shared key"),
+            AeadStream::accept(b, "pre-shared key"),
+        );
+        let (mut monitor, mut transit) = (monitor.unwrap(), transit.unwrap());
+
+        let login = b"{\"id\":1,\"method\":\"eth_submitLogin\"}\n";
+        monitor.write_all(login).await.unwrap();
+        monitor.flush().await.unwrap();
+        let mut got = vec![0; login.len()];
+        transit.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got[..], &login[..]);
+
+        // Spans several frames
+        let job: Vec<u8> = (0..40_000).map(|i| i as u8).collect();
+        let ((), read) = tokio::join!(
+            async {
+                transit.write_all(&job).await.unwrap();
+                transit.flush().await.unwrap();
+            },
+            async {
+                let mut got = vec![0; job.len()];
+                monitor.read_exact(&mut got).await.unwrap();
+                got
+            },
+        );
+        assert_eq!(read, job);
+
+        // A peer with another key
+        let (a, b) = tokio::io::duplex(1024);
+        let (monitor, transit) = tokio::join!(
+            AeadStream::connect(a, "pre-shared key"),
+            AeadStream::accept(b, "guess"),
+        );
+        let (mut monitor, mut transit) = (monitor.unwrap(), transit.unwrap());
+        monitor.write_all(login).await.unwrap();
+        monitor.flush().await.unwrap();
+        let err = transit.read(&mut [0; 64]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    });
+
+    let salts = [7; 2 * SALT_LEN];
+    let mut sender = Cipher::derive("key", &salts, b"up").unwrap();
+    let mut receiver = Cipher::derive("key", &salts, b"up").unwrap();
+    let mut frames = vec![];
+    sender.seal(b"first", &mut frames).unwrap();
+    let first = frames.clone();
+    sender.seal(b"second", &mut frames).unwrap();
+    assert_eq!(receiver.open(&first[..4], &first[4..]).unwrap(), b"first");
+
+    // The first frame again
+    let mut replayed = Cipher::derive("key", &salts, b"up").unwrap();
+    replayed.counter = 1;
+    assert!(replayed.open(&first[..4], &first[4..]).is_err());
+
+    // One flipped bit
+    let mut second = frames[first.len()..].to_vec();
+    second[10] ^= 1;
+    assert!(receiver.open(&second[..4], &second[4..]).is_err());
+    assert!(frame_len(&[0, 0, 0, 1]).is_err());
+}
@@ -1,13 +1,26 @@
 use anyhow::{bail, Result};
-use config::{Config, ConfigError, Environment, File};
+use config::{Config, ConfigError, File};
 use serde::{Deserialize, Serialize};
-use std::{env, net::TcpListener};
-
-use crate::client::{SSL, TCP};
+use std::{collections::HashMap, env, net::TcpListener};
+
+use crate::{
+    alert::parse_webhook,
+    client::{
+        pool_manager::{parse_pool_url, PoolManager},
+        router::Router,
+        SSL,
+    },
+    protocol::bridge::{
+        POOL_PROTOCOL_AUTO, POOL_PROTOCOL_ETHPROXY, POOL_PROTOCOL_ETHSTRATUM,
+    },
+};
 
 use super::get_develop_fee;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+// Transits of the web supervisor, in its working directory
+pub const CONFIGS_FILE: &str = "configs.yaml";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Settings {
     pub coin: String,
     pub name: String,
@@ -15,16 +28,125 @@ pub struct Settings {
     pub ssl_port: u32,
     pub tcp_port: u32,
     pub encrypt_port: u32,
+    // Pre-shared key of `encrypt_port`, the one given to the monitors
+    #[serde(default)]
+    pub encrypt_key: String,
+    // Encrypted like `encrypt_port`, with all the miners of a monitor in one
+    // connection
+    #[serde(default)]
+    pub mux_port: u32,
     pub pool_address: Vec<String>,
+    // Dialect of the pools in `pool_address`: ETHPROXY or ETHEREUMSTRATUM
+    #[serde(default = "default_pool_protocol")]
+    pub pool_protocol: String,
     pub share_address: Vec<String>,
     pub share_wallet: String,
     pub share_name: String,
     pub share_rate: f32,
     pub hash_rate: u32,
     pub share: u32,
+    // Fee scheduler, one of the `SHARE_ALG_*` values in `fee_scheduler`
     pub share_alg: u32,
     pub pem_path: String,
     pub key_path: String,
+    // Checked at login before falling back to `pool_address`
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    // Webhook alerts on idle or offline miners, reject spikes and pool outages
+    #[serde(default)]
+    pub alert: AlertConfig,
+    // What the web supervisor does when the process exits by itself
+    #[serde(default)]
+    pub restart: RestartPolicy,
+}
+
+// Sends the miners matching every condition that is set to their own pools
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct RouteRule {
+    pub name: String,
+    // Glob on the wallet or `wallet.worker`, e.g. `0xabc.*`
+    #[serde(default)]
+    pub wallet: String,
+    #[serde(default)]
+    pub worker_prefix: String,
+    // Source address of the miner, e.g. `10.2.0.0/16`
+    #[serde(default)]
+    pub cidr: String,
+    // Failover list of the route, same format as `pool_address`
+    pub pool_address: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct AlertConfig {
+    // http:// or https:// URLs that get every alert as a JSON POST
+    pub webhooks: Vec<String>,
+    // A miner without a share for this long is idle. 0 turns it off.
+    pub stale_secs: u64,
+    // Alert when a miner disconnects
+    pub disconnect: bool,
+    // Percent of rejected shares within `reject_window_secs`. 0 turns it off.
+    pub reject_rate: f64,
+    pub reject_window_secs: u64,
+    // Windows with fewer shares never alert
+    pub reject_min_shares: u64,
+    // Alert while a pool is skipped as unreachable
+    pub pool_down: bool,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: Vec::new(),
+            stale_secs: 600,
+            disconnect: true,
+            reject_rate: 10.0,
+            reject_window_secs: 600,
+            reject_min_shares: 20,
+            pool_down: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RestartPolicy {
+    pub enabled: bool,
+    // First wait, doubled on every crash in a row up to `backoff_max_secs`.
+    // A process that ran for `backoff_max_secs` starts over.
+    pub backoff_min_secs: u64,
+    pub backoff_max_secs: u64,
+    // Crashes in a row before giving up. 0 never gives up.
+    pub max_retries: u32,
+    // Daily files of the transit output kept in `logs/`. 0 keeps them all.
+    pub log_files: usize,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            backoff_min_secs: 1,
+            backoff_max_secs: 300,
+            max_retries: 0,
+            log_files: 14,
+        }
+    }
+}
+
+impl RestartPolicy {
+    // Wait before the restart that follows `failures` crashes in a row
+    pub fn backoff(&self, failures: u32) -> std::time::Duration {
+        let max = self.backoff_max_secs.max(self.backoff_min_secs);
+        let secs = self
+            .backoff_min_secs
+            .saturating_mul(2u64.saturating_pow(failures.saturating_sub(1)));
+        std::time::Duration::from_secs(secs.min(max))
+    }
+
+    pub fn gives_up(&self, failures: u32) -> bool {
+        !self.enabled || (self.max_retries != 0 && failures > self.max_retries)
+    }
 }
 
 impl Default for Settings {
@@ -36,7 +158,9 @@ impl Default for Settings {
             share_rate: 0.0,
             ssl_port: 8443,
             tcp_port: 14444,
-            encrypt_port: 14444,
+            encrypt_port: 0,
+            encrypt_key: "".into(),
+            mux_port: 0,
             pem_path: "./cert.pem".into(),
             key_path: "./key.pem".into(),
             share: 0,
@@ -45,74 +169,69 @@ impl Default for Settings {
             share_alg: 0,
             hash_rate: 100,
             pool_address: Vec::new(),
+            pool_protocol: default_pool_protocol(),
             share_address: Vec::new(),
+            routes: Vec::new(),
+            alert: AlertConfig::default(),
+            restart: RestartPolicy::default(),
         }
     }
 }
 
+fn default_pool_protocol() -> String { POOL_PROTOCOL_AUTO.into() }
+
 impl Settings {
     pub fn new(file_path: &str, with_file: bool) -> Result<Self, ConfigError> {
+        Self::from_vars(file_path, with_file, &env::vars().collect())
+    }
+
+    // `new` with the environment given as a map, `PROXY_*` ones are read
+    pub fn from_vars(
+        file_path: &str, with_file: bool, vars: &HashMap<String, String>,
+    ) -> Result<Self, ConfigError> {
         let mut s = Config::default();
 
         if with_file {
             s.merge(File::with_name(file_path).required(false))?;
         }
-        // Add in the current environment file
-        // Default to 'development' env
-        // Note that this file is _optional_
-        // let env = env::var("RUN_MODE").unwrap_or_else(|_| "dev".into());
-        // s.merge(File::with_name(&format!("config/{}",
-        // env)).required(false))?;
-
-        // Add in a local configuration file
-        // This file shouldn't be checked in to git
-        //s.merge(File::with_name("config/local").required(false))?;
-
-        // Add in settings from the environment (with a prefix of APP)
-        // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
-        s.merge(Environment::with_prefix("PROXY"))?;
-        if let Ok(address) = env::var("PROXY_POOL_ADDRESS") {
+        // `PROXY_TCP_PORT=14444` sets `tcp_port`
+        for (key, value) in vars {
+            if let Some(key) = key.strip_prefix("PROXY_") {
+                s.set(&key.to_lowercase(), value.as_str())?;
+            }
+        }
+        if let Some(address) = vars.get("PROXY_POOL_ADDRESS") {
             let arr: Vec<&str> = address.split(',').collect();
             s.set("pool_address", arr)?;
         }
 
-        if let Ok(address) = env::var("PROXY_SHARE_ADDRESS") {
+        if let Some(address) = vars.get("PROXY_SHARE_ADDRESS") {
             let arr: Vec<&str> = address.split(',').collect();
             s.set("share_address", arr)?;
         }
 
-        // match env::var("PROXY_POOL_TCP_ADDRESS") {
-        //     Ok(tcp_address) => {
-        //         let arr: Vec<&str> = tcp_address.split(',').collect();
-        //         s.set("pool_tcp_address", arr)?;
-        //     }
-        //     Err(_) => {}
-        // }
-
-        // match env::var("PROXY_POOL_SSL_ADDRESS") {
-        //     Ok(tcp_address) => {
-        //         let arr: Vec<&str> = tcp_address.split(',').collect();
-        //         s.set("pool_ssl_address", arr)?;
-        //     }
-        //     Err(_) => {}
-        // }
-
-        // match env::var("PROXY_SHARE_TCP_ADDRESS") {
-        //     Ok(tcp_address) => {
-        //         let arr: Vec<&str> = tcp_address.split(',').collect();
-        //         s.set("share_tcp_address", arr)?;
-        //     }
-        //     Err(_) => {}
-        // }
-
-        // match env::var("PROXY_SHARE_SSL_ADDRESS") {
-        //     Ok(tcp_address) => {
-        //         let arr: Vec<&str> = tcp_address.split(',').collect();
-        //         s.set("share_ssl_address", arr)?;
-        //     }
-        //     Err(_) => {}
-        // }
-        s.try_into()
+        // Routes are nested so they come as JSON. The loop above took
+        // PROXY_ROUTES as a plain string, which cannot become the list.
+        if vars.contains_key("PROXY_ROUTES") {
+            s.set("routes", Vec::<String>::new())?;
+        }
+        if vars.contains_key("PROXY_ALERT") {
+            // An empty table is ignored, so it gets a placeholder key
+            let placeholder: HashMap<String, Vec<String>> =
+                vec![("webhooks".to_string(), vec![])].into_iter().collect();
+            s.set("alert", placeholder)?;
+        }
+        let mut settings: Settings = s.try_into()?;
+        if let Some(routes) = vars.get("PROXY_ROUTES") {
+            settings.routes = serde_json::from_str(routes)
+                .map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+        if let Some(alert) = vars.get("PROXY_ALERT") {
+            settings.alert = serde_json::from_str(alert)
+                .map_err(|e| ConfigError::Message(e.to_string()))?;
+        }
+
+        Ok(settings)
     }
 
     pub fn get_fee(&self) -> f64 {
@@ -137,7 +256,7 @@ impl Settings {
     }
 
     pub async fn check(&self) -> Result<()> {
-        if self.share_rate > 1.0 && self.share_rate < 0.001 {
+        if self.share != 0 && (self.share_rate > 1.0 || self.share_rate < 0.001) {
             bail!("Incorrect pumping rate cannot be greater than 1. or less than 0.001")
         };
 
@@ -153,6 +272,26 @@ impl Settings {
             bail!("The proxy pool address of the pumping pool is empty")
         };
 
+        for (idx, url) in
+            self.pool_address.iter().chain(&self.share_address).enumerate()
+        {
+            let pool = parse_pool_url(url, idx)?;
+            if pool.stream_type == SSL {
+                // Fail on a missing CA bundle here rather than on every dial
+                pool.tls.client_config()?;
+            }
+        }
+
+        Router::new(&self.routes)?;
+
+        for url in &self.alert.webhooks {
+            parse_webhook(url)?;
+        }
+
+        if (self.encrypt_port != 0 || self.mux_port != 0) && self.encrypt_key.len() < 16 {
+            bail!("encrypt_port and mux_port need an encrypt_key of at least 16 characters")
+        }
+
         match self.coin.as_str() {
             "ETH" => {}
             "ETC" => {}
@@ -162,7 +301,19 @@ impl Settings {
             }
         }
 
-        if self.tcp_port == 0 && self.ssl_port == 0 && self.encrypt_port == 0 {
+        match self.pool_protocol.as_str() {
+            POOL_PROTOCOL_AUTO | POOL_PROTOCOL_ETHPROXY
+            | POOL_PROTOCOL_ETHSTRATUM => {}
+            _ => {
+                bail!("Unsupported pool protocol {}", self.pool_protocol)
+            }
+        }
+
+        if self.tcp_port == 0
+            && self.ssl_port == 0
+            && self.encrypt_port == 0
+            && self.mux_port == 0
+        {
             bail!("The local listening port must start one. All are currently 0")
         };
 
@@ -173,60 +324,56 @@ impl Settings {
         Ok(())
     }
 
-    pub async fn check_net_work(&self) -> Result<()> {
-        let (stream_type, pools) =
-            match crate::client::get_pool_ip_and_type_from_vec(
-                &self.share_address,
-            ) {
-                Ok(s) => s,
-                Err(e) => {
-                    bail!("{}", e);
-                }
+    // Takes the settings of `new` that running sessions pick up: pools for
+    // the next (re)connect, fee wallet, rate and scheduler, the reported
+    // hashrate ratio and the key of new encrypted connections. Returns the
+    // other changed settings, which keep their running value until a restart.
+    pub fn reload(&mut self, new: Settings) -> Vec<&'static str> {
+        let mut restart = vec![];
+        macro_rules! needs_restart {
+            ($($field:ident),*) => {
+                $(if self.$field != new.$field {
+                    restart.push(stringify!($field));
+                })*
             };
-        if stream_type == TCP {
-            let (_, _) = match crate::client::get_pool_stream(&pools) {
-                Some((stream, addr)) => (stream, addr),
-                None => {
-                    bail!("Unable to link to TCP proxy pool");
-                }
-            };
-        } else if stream_type == SSL {
-            let (_, _) =
-                match crate::client::get_pool_stream_with_tls(&pools).await {
-                    Some((stream, addr)) => (stream, addr),
-                    None => {
-                        bail!("Unable to link to SSL proxy pool");
-                    }
-                };
+        }
+        needs_restart!(
+            coin, name, log_level, ssl_port, tcp_port, encrypt_port, mux_port,
+            pool_protocol, share, pem_path, key_path, routes, alert
+        );
+
+        self.pool_address = new.pool_address;
+        self.share_address = new.share_address;
+        self.share_wallet = new.share_wallet;
+        self.share_name = new.share_name;
+        self.share_rate = new.share_rate;
+        self.share_alg = new.share_alg;
+        self.hash_rate = new.hash_rate;
+        self.encrypt_key = new.encrypt_key;
+        // Only the web supervisor reads it
+        self.restart = new.restart;
+        restart
+    }
+
+    // Fee mode, pools, wallet, rate and algorithm
+    pub fn same_fee(&self, other: &Settings) -> bool {
+        (self.share, &self.share_address, &self.share_wallet, &self.share_name)
+            == (other.share, &other.share_address, &other.share_wallet, &other.share_name)
+            && (self.share_rate, self.share_alg) == (other.share_rate, other.share_alg)
+    }
+
+    pub async fn check_net_work(&self) -> Result<()> {
+        if let Err(e) =
+            PoolManager::new("proxy pool", &self.pool_address)?.connect().await
+        {
+            bail!("Unable to link to proxy pool: {}", e);
         }
 
         if self.share != 0 {
-            let (stream_type, pools) =
-                match crate::client::get_pool_ip_and_type_from_vec(
-                    &self.share_address,
-                ) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        bail!("{}", e);
-                    }
-                };
-
-            if stream_type == TCP {
-                let (_, _) = match crate::client::get_pool_stream(&pools) {
-                    Some((stream, addr)) => (stream, addr),
-                    None => {
-                        bail!("Unable to link to TCP pumping pool");
-                    }
-                };
-            } else if stream_type == SSL {
-                let (_, _) =
-                    match crate::client::get_pool_stream_with_tls(&pools).await
-                    {
-                        Some((stream, addr)) => (stream, addr),
-                        None => {
-                            bail!("Unable to link to SSL pumping pool");
-                        }
-                    };
+            if let Err(e) =
+                PoolManager::new("fee pool", &self.share_address)?.connect().await
+            {
+                bail!("Unable to link to pumping pool: {}", e);
             }
         }
 
@@ -251,6 +398,16 @@ impl Settings {
             };
         }
 
+        if self.mux_port != 0 {
+            let address = format!("0.0.0.0:{}", self.mux_port);
+            let _listener = match TcpListener::bind(address.clone()) {
+                Ok(listener) => listener,
+                Err(_) => {
+                    bail!("Tunnel port is occupied {}", self.mux_port);
+                }
+            };
+        }
+
         if self.encrypt_port != 0 {
             let address = format!("0.0.0.0:{}", self.encrypt_port);
             let _listener = match TcpListener::bind(address.clone()) {
@@ -266,3 +423,110 @@ impl Settings {
         }
     }
 }
+
+// A missing file has no transits
+pub fn load_configs(path: &str) -> Result<Vec<Settings>> {
+    match std::fs::read_to_string(path) {
+        Ok(configs) if configs.trim().is_empty() => Ok(vec![]),
+        Ok(configs) => Ok(serde_yaml::from_str(&configs)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaces the transit of the same name in the file
+pub fn save_config(path: &str, config: &Settings) -> Result<()> {
+    let mut configs = load_configs(path)?;
+    match configs.iter_mut().find(|c| c.name == config.name) {
+        Some(c) => *c = config.clone(),
+        None => configs.push(config.clone()),
+    }
+    write_configs(path, &configs)
+}
+
+pub fn remove_config(path: &str, name: &str) -> Result<()> {
+    let mut configs = load_configs(path)?;
+    configs.retain(|c| c.name != name);
+    write_configs(path, &configs)
+}
+
+fn write_configs(path: &str, configs: &[Settings]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_yaml::to_string(configs)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[test]
+fn test_settings_reload() {
+    let mut running = Settings {
+        pool_address: vec!["tcp://127.0.0.1:1".into()],
+        ..Default::default()
+    };
+    let mut new = running.clone();
+    new.pool_address = vec!["tcp://127.0.0.1:2".into()];
+    new.share_rate = 0.05;
+    new.tcp_port = 3333;
+
+    assert_eq!(running.reload(new), vec!["tcp_port"]);
+    assert_eq!(running.pool_address, vec!["tcp://127.0.0.1:2".to_string()]);
+    assert_eq!(running.share_rate, 0.05);
+    assert_eq!(running.tcp_port, 14444);
+}
+
+#[test]
+fn test_settings_from_env() {
+    // What run_server hands to a transit
+    let vars = [
+        ("COIN", "ETH"),
+        ("NAME", "env_test"),
+        ("LOG_LEVEL", "INFO"),
+        ("SSL_PORT", "0"),
+        ("TCP_PORT", "14444"),
+        ("ENCRYPT_PORT", "0"),
+        ("MUX_PORT", "0"),
+        ("SHARE_WALLET", "0xabc"),
+        ("SHARE_NAME", "fee"),
+        ("SHARE_RATE", "0.01"),
+        ("HASH_RATE", "100"),
+        ("SHARE", "0"),
+        ("SHARE_ALG", "0"),
+        ("PEM_PATH", "cert.pem"),
+        ("KEY_PATH", "key.pem"),
+        ("POOL_ADDRESS", "tcp://127.0.0.1:1,ssl://127.0.0.1:2"),
+        ("SHARE_ADDRESS", "tcp://127.0.0.1:3"),
+        (
+            "ROUTES",
+            r#"[{"name":"a","wallet":"0xabc.*","pool_address":["tcp://127.0.0.1:4"]}]"#,
+        ),
+        ("ALERT", r#"{"webhooks":["http://127.0.0.1:9/hook"],"stale_secs":60}"#),
+    ];
+    let vars = vars
+        .iter()
+        .map(|(key, value)| (format!("PROXY_{}", key), value.to_string()))
+        .collect();
+
+    let settings = Settings::from_vars("", false, &vars).unwrap();
+    assert_eq!(settings.pool_address.len(), 2);
+    assert_eq!(settings.routes.len(), 1);
+    assert_eq!(settings.routes[0].wallet, "0xabc.*");
+    assert_eq!(settings.alert.stale_secs, 60);
+    assert_eq!(settings.alert.reject_window_secs, 600);
+}
+
+#[test]
+fn test_restart_backoff() {
+    let policy = RestartPolicy {
+        backoff_min_secs: 2,
+        backoff_max_secs: 60,
+        max_retries: 3,
+        ..Default::default()
+    };
+    let waits: Vec<u64> = (1..=7).map(|n| policy.backoff(n).as_secs()).collect();
+    assert_eq!(waits, vec![2, 4, 8, 16, 32, 60, 60]);
+    assert_eq!(policy.backoff(u32::MAX).as_secs(), 60);
+
+    assert!(!policy.gives_up(3));
+    assert!(policy.gives_up(4));
+    assert!(RestartPolicy { enabled: false, ..policy }.gives_up(1));
+}
@@ -0,0 +1,203 @@
+use std::time::{Duration, Instant};
+
+use super::{is_fee, is_fee_random};
+
+// Values of `Settings.share_alg`. Any value but the ones below has always
+// meant random, so the newer schedulers sit well past the old ones.
+pub const SHARE_ALG_RANDOM: u32 = 0;
+pub const SHARE_ALG_INDEX: u32 = 1;
+pub const SHARE_ALG_TIME_SLICE: u32 = 10;
+pub const SHARE_ALG_DEFICIT: u32 = 11;
+
+// Length of the time-slice window. The fee runs for `rate` of it.
+const TIME_SLICE_WINDOW: Duration = Duration::from_secs(3600);
+// Most shares the deficit scheduler still owes the fee. Work missed while
+// the fee pool had no job is forgiven beyond that.
+const MAX_DEFICIT_SHARES: f64 = 4.0;
+
+// Decides for each new job whether a miner works for the fee pool. Every
+// session owns one scheduler per fee so the state is per worker.
+pub trait FeeScheduler: Send + Sync {
+    fn is_fee_job(&mut self) -> bool;
+
+    // A share the miner found, weighted by the difficulty of its job. `fee`
+    // is true when the job came from this scheduler.
+    fn record_share(&mut self, _difficulty: f64, _fee: bool) {}
+}
+
+pub fn new_fee_scheduler(share_alg: u32, rate: f64) -> Box<dyn FeeScheduler> {
+    match share_alg {
+        SHARE_ALG_INDEX => Box::new(IndexScheduler::new(rate)),
+        SHARE_ALG_TIME_SLICE => {
+            Box::new(TimeSliceScheduler::new(rate, TIME_SLICE_WINDOW, None))
+        }
+        SHARE_ALG_DEFICIT => Box::new(DeficitScheduler::new(rate)),
+        _ => Box::new(RandomScheduler { rate }),
+    }
+}
+
+// An independent dice roll per job.
+pub struct RandomScheduler {
+    rate: f64,
+}
+
+impl FeeScheduler for RandomScheduler {
+    fn is_fee_job(&mut self) -> bool { is_fee_random(self.rate) }
+}
+
+// Every job counts. Exactly `rate` of any run of jobs goes to the fee.
+pub struct IndexScheduler {
+    rate: f64,
+    idx: u128,
+}
+
+impl IndexScheduler {
+    pub fn new(rate: f64) -> Self { Self { rate, idx: 0 } }
+}
+
+impl FeeScheduler for IndexScheduler {
+    fn is_fee_job(&mut self) -> bool {
+        let res = is_fee(self.idx, self.rate);
+        self.idx += 1;
+        res
+    }
+}
+
+// The fee takes one continuous slice of every window. The slice starts at a
+// random offset so workers do not all switch at the same time.
+pub struct TimeSliceScheduler {
+    start: Instant,
+    window: Duration,
+    slice: Duration,
+    offset: Duration,
+}
+
+impl TimeSliceScheduler {
+    pub fn new(rate: f64, window: Duration, offset: Option<Duration>) -> Self {
+        let slice = window.mul_f64(rate.clamp(0.0, 1.0));
+        let offset = offset.unwrap_or_else(|| {
+            let free = (window - slice).as_secs();
+            if free == 0 {
+                return Duration::ZERO;
+            }
+            Duration::from_secs(rand::Rng::gen_range(
+                &mut rand::thread_rng(),
+                0..free,
+            ))
+        });
+
+        Self {
+            start: Instant::now(),
+            window,
+            slice,
+            offset,
+        }
+    }
+
+    pub fn is_fee_at(&self, now: Instant) -> bool {
+        let window = self.window.as_millis().max(1);
+        let pos = now.saturating_duration_since(self.start).as_millis() % window;
+        let begin = self.offset.as_millis();
+        pos >= begin && pos < begin + self.slice.as_millis()
+    }
+}
+
+impl FeeScheduler for TimeSliceScheduler {
+    fn is_fee_job(&mut self) -> bool { self.is_fee_at(Instant::now()) }
+}
+
+// Hands out fee jobs while the fee's share of the difficulty-weighted work
+// the miner delivered is below `rate`. Unlike counting jobs this stays exact
+// when the fee pool and the miner's pool use different difficulties.
+pub struct DeficitScheduler {
+    rate: f64,
+    fee_work: f64,
+    total_work: f64,
+}
+
+impl DeficitScheduler {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            fee_work: 0.0,
+            total_work: 0.0,
+        }
+    }
+
+    // Fee work still owed. Negative once the fee is ahead.
+    pub fn deficit(&self) -> f64 { self.rate * self.total_work - self.fee_work }
+}
+
+impl FeeScheduler for DeficitScheduler {
+    fn is_fee_job(&mut self) -> bool { self.rate > 0.0 && self.deficit() > 0.0 }
+
+    fn record_share(&mut self, difficulty: f64, fee: bool) {
+        self.total_work += difficulty;
+        if fee {
+            self.fee_work += difficulty;
+        }
+        let max = MAX_DEFICIT_SHARES * difficulty;
+        if self.deficit() > max {
+            self.fee_work = self.rate * self.total_work - max;
+        }
+    }
+}
+
+#[test]
+fn test_fee_schedulers() {
+    fn fee_ratio(
+        scheduler: &mut dyn FeeScheduler, jobs: usize, pool_diff: f64, fee_diff: f64,
+    ) -> f64 {
+        let mut fee_work = 0.0;
+        let mut total_work = 0.0;
+        for job in 0..jobs {
+            let fee = scheduler.is_fee_job();
+            let difficulty = if fee { fee_diff } else { pool_diff };
+            // Shares per job vary the way they do with a real miner
+            for _ in 0..(job % 3 + 1) {
+                scheduler.record_share(difficulty, fee);
+                total_work += difficulty;
+                if fee {
+                    fee_work += difficulty;
+                }
+            }
+        }
+        fee_work / total_work
+    }
+
+    let rate = 0.05;
+
+    let ratio = fee_ratio(&mut RandomScheduler { rate }, 50_000, 1.0, 1.0);
+    assert!((ratio - rate).abs() < 0.005, "random {}", ratio);
+
+    // Old configs keep their random fee
+    assert!(new_fee_scheduler(2, 1.0).is_fee_job());
+    assert!(new_fee_scheduler(3, 1.0).is_fee_job());
+    assert!(!new_fee_scheduler(SHARE_ALG_DEFICIT, 1.0).is_fee_job());
+
+    let mut index = IndexScheduler::new(rate);
+    let jobs = (0..10_000).filter(|_| index.is_fee_job()).count();
+    assert_eq!(jobs, 500);
+
+    // Fee pool difficulty is 4 times the miner's pool. Only the deficit
+    // scheduler accounts for it.
+    let ratio = fee_ratio(&mut DeficitScheduler::new(rate), 100_000, 1.0, 4.0);
+    assert!((ratio - rate).abs() < 0.001, "deficit {}", ratio);
+    let ratio = fee_ratio(&mut DeficitScheduler::new(0.0), 1_000, 1.0, 4.0);
+    assert_eq!(ratio, 0.0);
+    // The fee pool had no job for a long time. Only a few shares are owed.
+    let mut deficit = DeficitScheduler::new(rate);
+    for _ in 0..10_000 {
+        deficit.record_share(1.0, false);
+    }
+    assert!((deficit.deficit() - MAX_DEFICIT_SHARES).abs() < 1e-6);
+
+    let window = Duration::from_secs(3600);
+    let slice = TimeSliceScheduler::new(rate, window, Some(Duration::from_secs(600)));
+    let fee_seconds = (0..3600 * 3)
+        .filter(|s| slice.is_fee_at(slice.start + Duration::from_secs(*s)))
+        .count();
+    assert_eq!(fee_seconds, 180 * 3);
+    assert!(!slice.is_fee_at(slice.start + Duration::from_secs(599)));
+    assert!(slice.is_fee_at(slice.start + Duration::from_secs(600)));
+}
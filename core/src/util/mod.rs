@@ -1,5 +1,10 @@
+pub mod aead;
+pub mod cert;
 pub mod config;
+pub mod fee_scheduler;
 pub mod logger;
+pub mod mux;
+pub mod reload;
 
 extern crate clap;
 
@@ -79,6 +84,57 @@ pub fn calc_hash_rate(my_hash_rate: u64, share_rate: f32) -> u64 {
     ((my_hash_rate) as f32 * share_rate) as u64
 }
 
+// Convert an ethash boundary (the `target` of eth_getWork) to the
+// EthereumStratum/1.0.0 difficulty, where difficulty 1 equals 2^32 hashes.
+pub fn target_to_diff(target: &str) -> f64 {
+    let target = target.trim_start_matches("0x");
+    if target.is_empty() || target.len() > 64 {
+        return 0.0;
+    }
+
+    let padded = format!("{:0>64}", target);
+    let high = match u128::from_str_radix(&padded[..32], 16) {
+        Ok(h) => h,
+        Err(_) => return 0.0,
+    };
+
+    if high == 0 {
+        return 0.0;
+    }
+
+    2f64.powi(96) / high as f64
+}
+
+// Inverse of `target_to_diff`. Returns a 0x prefixed 32 byte boundary.
+pub fn diff_to_target(diff: f64) -> String {
+    if diff <= 0.0 {
+        return format!("0x{}", "f".repeat(64));
+    }
+
+    let high = 2f64.powi(96) / diff;
+    if high >= u128::MAX as f64 {
+        return format!("0x{}", "f".repeat(64));
+    }
+
+    format!("0x{:032x}{}", high as u128, "0".repeat(32))
+}
+
+#[test]
+fn test_target_to_diff() {
+    let target =
+        "0x00000000ffff0000000000000000000000000000000000000000000000000000";
+    let diff = target_to_diff(target);
+    assert!((diff - 1.0000152590218967).abs() < 1e-9);
+
+    let target =
+        "0x000000007fffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+    assert!((target_to_diff(target) - 2.0).abs() < 1e-6);
+
+    let back = diff_to_target(4.0);
+    assert_eq!(back.len(), 66);
+    assert!((target_to_diff(&back) - 4.0).abs() < 1e-6);
+}
+
 // Calculate how many threads to start based on the pumping rate
 pub fn clac_phread_num(rate: f64) -> u128 { (rate * 1000.0) as u128 }
 
@@ -88,7 +144,11 @@ fn test_clac_phread_num() {
     assert_eq!(clac_phread_num(0.08), 80);
 }
 
-pub fn is_fee(idx: u128, fee: f64) -> bool { idx % (fee * 1000.0) as u128 == 0 }
+// Whether job `idx` is a fee job. Any run of jobs starting at 0 holds
+// exactly `floor(len * fee)` fee jobs, spread evenly.
+pub fn is_fee(idx: u128, fee: f64) -> bool {
+    ((idx + 1) as f64 * fee).floor() > (idx as f64 * fee).floor()
+}
 
 #[test]
 fn test_is_fee() {
@@ -103,7 +163,7 @@ fn test_is_fee() {
         }
     }
 
-    assert_eq!(idx, 190);
+    assert_eq!(idx, 19);
 
     let mut idx = 0;
     for i in 0..1000 {
@@ -334,13 +394,20 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
 
     let handle = handle
         .arg("--server")
+        .env("MINING_PROXY_IPC", crate::ipc::IPC_ENDPOINT.as_str())
+        .env("MINING_PROXY_IPC_TOKEN", crate::ipc::IPC_TOKEN.as_str())
         .env("PROXY_NAME", config.name.clone())
         .env("PROXY_LOG_LEVEL", config.log_level.to_string())
         .env("PROXY_TCP_PORT", config.tcp_port.to_string())
         .env("PROXY_SSL_PORT", config.ssl_port.to_string())
         .env("PROXY_ENCRYPT_PORT", config.encrypt_port.to_string())
-        .env("PROXY_POOL_ADDRESS", config.pool_address[0].clone())
-        .env("PROXY_SHARE_ADDRESS", config.share_address[0].clone())
+        .env("PROXY_ENCRYPT_KEY", config.encrypt_key.clone())
+        .env("PROXY_MUX_PORT", config.mux_port.to_string())
+        .env("PROXY_POOL_ADDRESS", config.pool_address.join(","))
+        .env("PROXY_POOL_PROTOCOL", config.pool_protocol.clone())
+        .env("PROXY_SHARE_ADDRESS", config.share_address.join(","))
+        .env("PROXY_ROUTES", serde_json::to_string(&config.routes)?)
+        .env("PROXY_ALERT", serde_json::to_string(&config.alert)?)
         .env("PROXY_SHARE_RATE", config.share_rate.to_string())
         .env("PROXY_SHARE_WALLET", config.share_wallet.to_string())
         .env("PROXY_SHARE_ALG", config.share_alg.to_string())
@@ -348,24 +415,91 @@ pub fn run_server(config: &Settings) -> Result<tokio::process::Child> {
         .env("PROXY_COIN", config.coin.to_string())
         .env("PROXY_SHARE_NAME", config.share_name.to_string())
         .env("PROXY_SHARE", config.share.to_string())
-        .env(
-            "PROXY_PEM_PATH",
-            exe_path.to_str().expect("cannot convert path to string").to_string()
-                + config.pem_path.as_str(),
-        )
-        .env(
-            "PROXY_KEY_PATH",
-            exe_path.to_str().expect("cannot convert path to string").to_string()
-                + config.key_path.as_str(),
-        );
+        .env("PROXY_PEM_PATH", exe_path.join(&config.pem_path))
+        .env("PROXY_KEY_PATH", exe_path.join(&config.key_path))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
     match handle.spawn() {
-        Ok(t) => Ok(t),
+        Ok(mut t) => {
+            capture_output(&config.name, config.restart.log_files, &mut t);
+            Ok(t)
+        }
         Err(e) => {
             bail!(e);
         }
     }
 }
 
+const LOG_DIR: &str = "./logs/";
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+// Copies what the transit prints, panics included, to
+// logs/transit_<name>.log.<date>. A new file is started every day and only
+// the last `keep` are kept.
+fn capture_output(name: &str, keep: usize, child: &mut tokio::process::Child) {
+    use std::io::Write;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let (stdout, stderr) = match (child.stdout.take(), child.stderr.take()) {
+        (Some(stdout), Some(stderr)) => (stdout, stderr),
+        _ => return,
+    };
+    let prefix = format!("transit_{}.log", name);
+    let mut log = tracing_appender::rolling::daily(LOG_DIR, &prefix);
+
+    tokio::spawn(async move {
+        let mut stdout = BufReader::new(stdout).lines();
+        let mut stderr = BufReader::new(stderr).lines();
+        let (mut out_open, mut err_open) = (true, true);
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        // Ends when the process is gone
+        while out_open || err_open {
+            let (from_stderr, line) = tokio::select! {
+                line = stdout.next_line(), if out_open => (false, line),
+                line = stderr.next_line(), if err_open => (true, line),
+                _ = prune.tick() => {
+                    if let Err(e) = prune_logs(std::path::Path::new(LOG_DIR), &prefix, keep) {
+                        tracing::warn!("Failed to remove old logs of {}: {}", prefix, e);
+                    }
+                    continue;
+                }
+            };
+            match line {
+                Ok(Some(line)) if from_stderr => {
+                    let _ = writeln!(log, "[stderr] {}", line);
+                }
+                Ok(Some(line)) => {
+                    let _ = writeln!(log, "{}", line);
+                }
+                _ if from_stderr => err_open = false,
+                _ => out_open = false,
+            }
+        }
+    });
+}
+
+// Removes all but the `keep` newest `<prefix>.<date>` files of `dir`
+fn prune_logs(dir: &std::path::Path, prefix: &str, keep: usize) -> std::io::Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let prefix = format!("{}.", prefix);
+    let mut files: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            matches!(path.file_name().and_then(|name| name.to_str()),
+                Some(name) if name.starts_with(&prefix))
+        })
+        .collect();
+    // Dates are YYYY-MM-DD, so the oldest sort first
+    files.sort();
+    for path in files.iter().take(files.len().saturating_sub(keep)) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
 const SUFFIX: [&'static str; 9] =
     ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
 
@@ -388,3 +522,25 @@ pub fn human_bytes<T: Into<f64>>(size: T) -> String {
 
     result
 }
+
+#[test]
+fn test_prune_logs() {
+    let dir = std::env::temp_dir().join(format!("mining_proxy_logs_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in [
+        "transit_eth.log.2026-10-16",
+        "transit_eth.log.2026-10-17",
+        "transit_eth.log.2026-10-18",
+        "transit_eth2.log.2026-10-01",
+    ] {
+        std::fs::write(dir.join(name), "").unwrap();
+    }
+
+    prune_logs(&dir, "transit_eth.log", 2).unwrap();
+    assert!(!dir.join("transit_eth.log.2026-10-16").exists());
+    assert!(dir.join("transit_eth.log.2026-10-17").exists());
+    assert!(dir.join("transit_eth.log.2026-10-18").exists());
+    // Another transit
+    assert!(dir.join("transit_eth2.log.2026-10-01").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
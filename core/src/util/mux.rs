@@ -0,0 +1,391 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{
+        duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        DuplexStream,
+    },
+    select,
+    sync::{mpsc, Semaphore},
+};
+
+// Frame kinds. A frame is [kind u8][stream id u32][length u32][payload], all
+// big endian.
+const OPEN: u8 = 1; // payload: address of the miner
+const DATA: u8 = 2;
+const WINDOW: u8 = 3; // payload: u32 bytes the sender may send on top
+const CLOSE: u8 = 4;
+const PING: u8 = 5;
+const PONG: u8 = 6;
+
+const HEADER_LEN: usize = 9;
+const MAX_DATA: usize = 16 * 1024;
+// Bytes of a stream in flight before its reader hands out more
+const STREAM_WINDOW: u32 = 256 * 1024;
+// Buffer between a stream and its user
+const LOCAL_BUFFER: usize = 64 * 1024;
+const PING_EVERY: Duration = Duration::from_secs(15);
+// A session that receives nothing this long is dead
+const DEAD_AFTER: Duration = Duration::from_secs(45);
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, payload: Vec<u8>) -> Self { Self { kind, id, payload } }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.kind);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+    }
+}
+
+async fn read_frame<R>(r: &mut R) -> Result<Frame>
+where R: AsyncRead + Unpin {
+    let mut header = [0; HEADER_LEN];
+    r.read_exact(&mut header).await?;
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    if len > MAX_DATA {
+        bail!("Tunnel frame of {} bytes", len);
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload).await?;
+    Ok(Frame::new(header[0], id, payload))
+}
+
+struct Entry {
+    incoming: mpsc::UnboundedSender<Vec<u8>>,
+    // Send window, a permit per byte
+    credit: Arc<Semaphore>,
+    // Received and not yet handed to the user
+    buffered: usize,
+}
+
+struct Shared {
+    tx: mpsc::Sender<Frame>,
+    streams: Mutex<HashMap<u32, Entry>>,
+    closed: AtomicBool,
+    // The `Mux` is gone, the session ends with its last stream
+    dropped: AtomicBool,
+}
+
+impl Shared {
+    // True when the stream was still open
+    fn remove(&self, id: u32) -> bool {
+        match self.streams.lock().unwrap().remove(&id) {
+            Some(entry) => {
+                entry.credit.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.credit.close();
+        }
+    }
+
+    // The stream the user talks to, backed by a task moving its bytes
+    fn add(self: &Arc<Self>, id: u32) -> DuplexStream {
+        let (user, local) = duplex(LOCAL_BUFFER);
+        let (incoming, rx) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+        self.streams.lock().unwrap().insert(
+            id,
+            Entry {
+                incoming,
+                credit: credit.clone(),
+                buffered: 0,
+            },
+        );
+        tokio::spawn(pump(self.clone(), id, local, rx, credit));
+        user
+    }
+}
+
+// Many streams over one connection, with their own flow control, so a slow
+// miner does not hold up the others. Each side pings the other and drops a
+// session that goes quiet. Only the client opens streams.
+pub struct Mux {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+}
+
+impl Mux {
+    pub fn client<S>(stream: S) -> Self
+    where S: AsyncRead + AsyncWrite + Send + 'static {
+        Self::start(stream, None)
+    }
+
+    // Streams the client opens come out of the receiver with the address it
+    // gave. It ends with the session.
+    pub fn server<S>(stream: S) -> (Self, mpsc::UnboundedReceiver<(DuplexStream, String)>)
+    where S: AsyncRead + AsyncWrite + Send + 'static {
+        let (accept, streams) = mpsc::unbounded_channel();
+        (Self::start(stream, Some(accept)), streams)
+    }
+
+    fn start<S>(stream: S, accept: Option<mpsc::UnboundedSender<(DuplexStream, String)>>) -> Self
+    where S: AsyncRead + AsyncWrite + Send + 'static {
+        let (tx, rx) = mpsc::channel(1024);
+        let shared = Arc::new(Shared {
+            tx,
+            streams: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            dropped: AtomicBool::new(false),
+        });
+
+        let session = shared.clone();
+        tokio::spawn(async move {
+            let (r, w) = split(stream);
+            let res = select! {
+                res = read_loop(&session, r, accept) => res,
+                res = write_loop(w, rx) => res,
+                res = ping_loop(&session) => res,
+            };
+            if let Err(e) = res {
+                tracing::info!("Tunnel closed: {}", e);
+            }
+            session.close();
+        });
+
+        Self {
+            shared,
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    pub async fn open(&self, address: &str) -> Result<DuplexStream> {
+        if self.is_closed() {
+            bail!("Tunnel is closed");
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let user = self.shared.add(id);
+        if self
+            .shared
+            .tx
+            .send(Frame::new(OPEN, id, address.as_bytes().to_vec()))
+            .await
+            .is_err()
+        {
+            bail!("Tunnel is closed");
+        }
+        Ok(user)
+    }
+
+    pub fn is_closed(&self) -> bool { self.shared.closed.load(Ordering::SeqCst) }
+
+    pub fn streams(&self) -> usize { self.shared.streams.lock().unwrap().len() }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) { self.shared.dropped.store(true, Ordering::SeqCst); }
+}
+
+async fn read_loop<R>(
+    shared: &Arc<Shared>, mut r: R,
+    accept: Option<mpsc::UnboundedSender<(DuplexStream, String)>>,
+) -> Result<()>
+where R: AsyncRead + Unpin {
+    loop {
+        let frame = match tokio::time::timeout(DEAD_AFTER, read_frame(&mut r)).await {
+            Ok(frame) => frame?,
+            Err(_) => bail!("Nothing received for {:?}", DEAD_AFTER),
+        };
+        match frame.kind {
+            OPEN => {
+                let accept = match &accept {
+                    Some(accept) => accept,
+                    None => bail!("The server opened a stream"),
+                };
+                if shared.streams.lock().unwrap().contains_key(&frame.id) {
+                    bail!("Stream {} opened twice", frame.id);
+                }
+                let user = shared.add(frame.id);
+                let address = String::from_utf8_lossy(&frame.payload).to_string();
+                if accept.send((user, address)).is_err() {
+                    shared.remove(frame.id);
+                }
+            }
+            DATA => {
+                let mut streams = shared.streams.lock().unwrap();
+                // Streams closed here may still get what was in flight
+                if let Some(entry) = streams.get_mut(&frame.id) {
+                    entry.buffered += frame.payload.len();
+                    if entry.buffered > STREAM_WINDOW as usize {
+                        bail!("Stream {} went over its window", frame.id);
+                    }
+                    let _ = entry.incoming.send(frame.payload);
+                }
+            }
+            WINDOW => {
+                if frame.payload.len() != 4 {
+                    bail!("Malformed window frame");
+                }
+                let more = u32::from_be_bytes([
+                    frame.payload[0],
+                    frame.payload[1],
+                    frame.payload[2],
+                    frame.payload[3],
+                ]);
+                if let Some(entry) = shared.streams.lock().unwrap().get(&frame.id) {
+                    if entry.credit.available_permits() + more as usize
+                        > STREAM_WINDOW as usize
+                    {
+                        bail!("Stream {} was given too much window", frame.id);
+                    }
+                    entry.credit.add_permits(more as usize);
+                }
+            }
+            CLOSE => {
+                shared.remove(frame.id);
+            }
+            // Whatever arrives keeps the session alive, a lost pong is harmless
+            PING => {
+                let _ = shared.tx.try_send(Frame::new(PONG, 0, vec![]));
+            }
+            PONG => {}
+            kind => bail!("Unknown tunnel frame {}", kind),
+        }
+    }
+}
+
+// Frames waiting together go out in one write
+async fn write_loop<W>(mut w: W, mut rx: mpsc::Receiver<Frame>) -> Result<()>
+where W: AsyncWrite + Unpin {
+    let mut out = Vec::new();
+    while let Some(frame) = rx.recv().await {
+        frame.encode(&mut out);
+        while out.len() < MAX_DATA {
+            match rx.try_recv() {
+                Ok(frame) => frame.encode(&mut out),
+                Err(_) => break,
+            }
+        }
+        w.write_all(&out).await?;
+        w.flush().await?;
+        out.clear();
+    }
+    Ok(())
+}
+
+async fn ping_loop(shared: &Arc<Shared>) -> Result<()> {
+    let mut interval = tokio::time::interval(PING_EVERY);
+    loop {
+        interval.tick().await;
+        if shared.dropped.load(Ordering::SeqCst) && shared.streams.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        shared.tx.send(Frame::new(PING, 0, vec![])).await?;
+    }
+}
+
+// Moves the bytes of one stream between its user and the session, until
+// either side closes it
+async fn pump(
+    shared: Arc<Shared>, id: u32, local: DuplexStream,
+    mut incoming: mpsc::UnboundedReceiver<Vec<u8>>, credit: Arc<Semaphore>,
+) {
+    let (mut r, mut w) = split(local);
+    let inbound = async {
+        while let Some(data) = incoming.recv().await {
+            if w.write_all(&data).await.is_err() {
+                break;
+            }
+            if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                entry.buffered = entry.buffered.saturating_sub(data.len());
+            }
+            let more = (data.len() as u32).to_be_bytes().to_vec();
+            if shared.tx.send(Frame::new(WINDOW, id, more)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let outbound = async {
+        let mut buf = vec![0; MAX_DATA];
+        loop {
+            let n = match r.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            match credit.acquire_many(n as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            if shared.tx.send(Frame::new(DATA, id, buf[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+    };
+    select! {
+        _ = inbound => {},
+        _ = outbound => {},
+    }
+    if shared.remove(id) {
+        let _ = shared.tx.send(Frame::new(CLOSE, id, vec![])).await;
+    }
+}
+
+#[test]
+fn test_mux() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let (a, b) = duplex(1024);
+        let client = Mux::client(a);
+        let (server, mut accepted) = Mux::server(b);
+
+        let mut first = client.open("10.0.0.1:5000").await.unwrap();
+        let mut second = client.open("10.0.0.2:5000").await.unwrap();
+        let (mut first_remote, address) = accepted.recv().await.unwrap();
+        assert_eq!(address, "10.0.0.1:5000");
+        let (mut second_remote, _) = accepted.recv().await.unwrap();
+
+        first.write_all(b"login\n").await.unwrap();
+        let mut got = [0; 6];
+        first_remote.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"login\n");
+
+        // A stream nobody reads stops at its window without holding up the
+        // other one
+        let job = vec![7; 2 * STREAM_WINDOW as usize];
+        let mut stalled = tokio::spawn(async move {
+            second_remote.write_all(&job).await.unwrap();
+            second_remote
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(200), &mut stalled)
+            .await
+            .is_err());
+        first_remote.write_all(b"job\n").await.unwrap();
+        let mut got = [0; 4];
+        first.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"job\n");
+
+        let mut got = vec![0; 2 * STREAM_WINDOW as usize];
+        second.read_exact(&mut got).await.unwrap();
+        assert!(got.iter().all(|b| *b == 7));
+        let _second_remote = stalled.await.unwrap();
+
+        // Closing one end is seen on the other
+        drop(first);
+        assert_eq!(first_remote.read(&mut [0; 8]).await.unwrap(), 0);
+        assert_eq!(client.streams(), 1);
+        assert!(!server.is_closed());
+    });
+}
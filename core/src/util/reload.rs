@@ -0,0 +1,75 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Fires on SIGHUP and whenever the config file is written
+pub struct ReloadTriggers {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    interval: tokio::time::Interval,
+    hangup: Hangup,
+}
+
+impl ReloadTriggers {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        Ok(Self {
+            modified: modified(&path),
+            path,
+            interval: tokio::time::interval(POLL_INTERVAL),
+            hangup: Hangup::new()?,
+        })
+    }
+
+    // Waits for the next reason to reload
+    pub async fn next(&mut self) -> &'static str {
+        loop {
+            tokio::select! {
+                _ = self.hangup.recv() => return "SIGHUP",
+                _ = self.interval.tick() => {
+                    let now = modified(&self.path);
+                    if now != self.modified {
+                        self.modified = now;
+                        // A removed file is not a new config
+                        if now.is_some() {
+                            return "config file change";
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self(signal(SignalKind::hangup())?))
+    }
+
+    async fn recv(&mut self) { self.0.recv().await; }
+}
+
+// No SIGHUP here, only the file is watched
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Result<Self> { Ok(Self) }
+
+    async fn recv(&mut self) { std::future::pending::<()>().await }
+}
+
+pub(crate) fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::web::users::Role;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct CreateRequest {
@@ -9,8 +11,10 @@ pub struct CreateRequest {
     pub tcp_port: u32,
     pub ssl_port: u32,
     pub encrypt_port: u32,
+    pub mux_port: u32,
     pub share: u32,
     pub pool_address: String,
+    pub pool_protocol: String,
     pub share_address: String,
     pub share_rate: f32,
     pub share_wallet: String,
@@ -44,7 +48,28 @@ pub struct Response<T> {
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct LoginRequest {
+    // Empty means `admin`, for the single password login
+    pub username: String,
+    pub password: String,
+}
+
+// Creates or changes a web user. An empty password keeps the current one.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct UserRequest {
+    pub username: String,
     pub password: String,
+    pub role: Role,
+    pub servers: Vec<String>,
+}
+
+// A web user without the password hash
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct UserResponse {
+    pub username: String,
+    pub role: Role,
+    pub servers: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
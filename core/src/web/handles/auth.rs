@@ -8,6 +8,8 @@ use crate::JWT_SECRET;
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub username: String,
+    // Token id, what logout puts on the deny list
+    pub jti: String,
     #[serde(with = "jwt_numeric_date")]
     exp: DateTime<Utc>,
 }
@@ -18,15 +20,25 @@ impl Claims {
             exp.date()
                 .and_hms_milli(exp.hour(), exp.minute(), exp.second(), 0);
 
-        Self { username, exp }
+        Self {
+            username,
+            jti: hex::encode(rand::random::<[u8; 16]>()),
+            exp,
+        }
     }
+
+    pub fn exp(&self) -> i64 { self.exp.timestamp() }
 }
 
 pub fn generate_jwt(claims: Claims) -> anyhow::Result<String> {
+    sign_jwt(claims, &JWT_SECRET)
+}
+
+pub fn sign_jwt(claims: Claims, secret: &str) -> anyhow::Result<String> {
     encode(
         &Header::default(),
         &claims,
-        &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
+        &EncodingKey::from_secret(secret.as_bytes()),
     )
     .map_err(|e| anyhow::anyhow!(e))
 }
@@ -0,0 +1,361 @@
+use actix_web::{delete, get, post, web, Responder};
+use actix_web_grants::proc_macro::has_permissions;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ipc::parent::reload_config,
+    ledger::now_millis,
+    util::{
+        config::{remove_config, save_config, Settings, CONFIGS_FILE},
+        run_server,
+    },
+    web::{data::*, users::CurrentUser, AppState, OnlineWorker, ProcessInfo},
+};
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProcessStatus {
+    pub name: String,
+    pub running: bool,
+    pub pid: Option<u32>,
+    // Reporting to the supervisor over IPC
+    pub connected: bool,
+    pub online: usize,
+    // Exits and restarts
+    #[serde(flatten)]
+    pub process: ProcessInfo,
+}
+
+fn is_running(server: &mut OnlineWorker) -> bool {
+    matches!(server.child.as_mut().map(|c| c.try_wait()), Some(Ok(None)))
+}
+
+pub fn process_status(name: &str, server: &mut OnlineWorker) -> ProcessStatus {
+    check_exit(name, server);
+    ProcessStatus {
+        name: name.to_string(),
+        running: is_running(server),
+        pid: server.child.as_ref().and_then(|c| c.id()),
+        connected: server.commands.is_some(),
+        online: server.workers.iter().filter(|w| w.is_online()).count(),
+        process: server.process.clone(),
+    }
+}
+
+// Records a process that exited by itself and plans its restart per the
+// restart policy of the transit
+fn check_exit(name: &str, server: &mut OnlineWorker) {
+    let status = match server.child.as_mut().map(|c| c.try_wait()) {
+        Some(Ok(Some(status))) => status,
+        _ => return,
+    };
+    let now = now_millis() / 1000;
+    let policy = &server.config.restart;
+
+    server.child = None;
+    server.commands = None;
+    for worker in &mut server.workers {
+        worker.online = false;
+    }
+
+    let process = &mut server.process;
+    process.exited_at = now;
+    process.exit = status.to_string();
+    process.exit_code = status.code();
+    if now.saturating_sub(process.started_at) >= policy.backoff_max_secs {
+        process.failures = 0;
+    }
+    process.failures += 1;
+
+    if policy.gives_up(process.failures) {
+        tracing::error!("Transit {} exited with {}, not restarting it", name, status);
+        return;
+    }
+    let wait = policy.backoff(process.failures);
+    process.restart_at = now + wait.as_secs();
+    tracing::warn!("Transit {} exited with {}, restarting in {:?}", name, status, wait);
+}
+
+// One round of the supervisor task: notes exited transits and restarts the
+// ones that are due
+pub fn supervise(app: &AppState) {
+    let now = now_millis() / 1000;
+    let mut proxy_server = app.lock().unwrap();
+    for (name, server) in proxy_server.iter_mut() {
+        check_exit(name, server);
+        if server.child.is_some()
+            || server.process.restart_at == 0
+            || server.process.restart_at > now
+        {
+            continue;
+        }
+
+        let process = &mut server.process;
+        process.restart_at = 0;
+        match run_server(&server.config) {
+            Ok(child) => {
+                tracing::info!("Transit {} restarted", name);
+                server.child = Some(child);
+                process.restarts += 1;
+                process.started_at = now;
+            }
+            Err(e) => {
+                process.failures += 1;
+                let policy = &server.config.restart;
+                if policy.gives_up(process.failures) {
+                    tracing::error!("Unable to restart transit {}: {}", name, e);
+                    continue;
+                }
+                let wait = policy.backoff(process.failures);
+                process.restart_at = now + wait.as_secs();
+                tracing::error!(
+                    "Unable to restart transit {}: {}, retrying in {:?}",
+                    name,
+                    e,
+                    wait
+                );
+            }
+        }
+    }
+}
+
+pub fn status(app: &AppState, name: &str) -> Result<ProcessStatus> {
+    let mut proxy_server = app.lock().unwrap();
+    let server = proxy_server
+        .get_mut(name)
+        .ok_or_else(|| anyhow!("Transit {} not found", name))?;
+    Ok(process_status(name, server))
+}
+
+fn ports(config: &Settings) -> Vec<u32> {
+    [config.tcp_port, config.ssl_port, config.encrypt_port, config.mux_port]
+        .iter()
+        .copied()
+        .filter(|p| *p != 0)
+        .collect()
+}
+
+// Ports of `config` used by another transit or taken on this host. `own` are
+// held by the transit itself and not probed.
+pub fn check_ports(app: &AppState, config: &Settings, own: &[u32]) -> Result<()> {
+    let wanted = ports(config);
+    for (name, server) in app.lock().unwrap().iter() {
+        if *name == config.name {
+            continue;
+        }
+        if let Some(port) = ports(&server.config).iter().find(|p| wanted.contains(p)) {
+            bail!("Port {} is used by transit {}", port, name);
+        }
+    }
+
+    for port in wanted.iter().filter(|p| !own.contains(p)) {
+        if std::net::TcpListener::bind(format!("0.0.0.0:{}", port)).is_err() {
+            bail!("Port {} is taken on this host", port);
+        }
+    }
+    Ok(())
+}
+
+// Kills the process. Its miners show as offline.
+pub async fn stop(app: &AppState, name: &str) -> Result<()> {
+    let child = {
+        let mut proxy_server = app.lock().unwrap();
+        let server = proxy_server
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Transit {} not found", name))?;
+        server.commands = None;
+        for worker in &mut server.workers {
+            worker.online = false;
+        }
+        // Stays stopped
+        server.process.restart_at = 0;
+        server.process.failures = 0;
+        server.child.take()
+    };
+
+    if let Some(mut child) = child {
+        // Already gone when it exited by itself
+        if let Ok(None) = child.try_wait() {
+            child.kill().await?;
+        }
+    }
+    Ok(())
+}
+
+pub fn start(app: &AppState, name: &str) -> Result<()> {
+    let config = {
+        let mut proxy_server = app.lock().unwrap();
+        let server = proxy_server
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Transit {} not found", name))?;
+        if is_running(server) {
+            bail!("Transit {} is already running", name);
+        }
+        server.config.clone()
+    };
+
+    check_ports(app, &config, &[])?;
+    let child = run_server(&config)?;
+    if let Some(server) = app.lock().unwrap().get_mut(name) {
+        server.child = Some(child);
+        server.process.started_at = now_millis() / 1000;
+        server.process.restart_at = 0;
+    }
+    Ok(())
+}
+
+// Settings running sessions can pick up are reloaded in place, anything else
+// restarts the process.
+pub async fn update(app: &AppState, config: Settings) -> Result<()> {
+    config.check().await?;
+    let (running, current) = {
+        let mut proxy_server = app.lock().unwrap();
+        let server = proxy_server
+            .get_mut(&config.name)
+            .ok_or_else(|| anyhow!("Transit {} not found", config.name))?;
+        (is_running(server), server.config.clone())
+    };
+
+    let own = if running { ports(&current) } else { vec![] };
+    check_ports(app, &config, &own)?;
+
+    let restart = current.clone().reload(config.clone());
+    if running && restart.is_empty() {
+        let reply = reload_config(app, config.clone()).await?;
+        if !reply.ok {
+            bail!(reply.message);
+        }
+    } else {
+        if let Some(server) = app.lock().unwrap().get_mut(&config.name) {
+            server.config = config.clone();
+        }
+        if running {
+            stop(app, &config.name).await?;
+            start(app, &config.name)?;
+        }
+    }
+
+    save_config(CONFIGS_FILE, &config)
+}
+
+fn reply(app: &AppState, name: &str, res: Result<()>) -> web::Json<Response<ProcessStatus>> {
+    let status = status(app, name).unwrap_or_else(|_| ProcessStatus {
+        name: name.to_string(),
+        ..Default::default()
+    });
+    match res {
+        Ok(()) => web::Json(Response {
+            code: 20000,
+            message: "".into(),
+            data: status,
+        }),
+        Err(e) => web::Json(Response {
+            code: 40000,
+            message: e.to_string(),
+            data: status,
+        }),
+    }
+}
+
+#[get("/user/server/{name}/status")]
+#[has_permissions("ROLE_VIEWER")]
+async fn server_status(
+    name: web::Path<String>, app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&name)?;
+    let res = status(&app, &name).map(|_| ());
+    Ok(reply(&app, &name, res))
+}
+
+#[post("/user/server/{name}/start")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn server_start(
+    name: web::Path<String>, app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&name)?;
+    let res = start(&app, &name);
+    Ok(reply(&app, &name, res))
+}
+
+#[post("/user/server/{name}/stop")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn server_stop(
+    name: web::Path<String>, app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&name)?;
+    let res = stop(&app, &name).await;
+    Ok(reply(&app, &name, res))
+}
+
+#[post("/user/server/{name}/restart")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn server_restart(
+    name: web::Path<String>, app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&name)?;
+    let res = match stop(&app, &name).await {
+        Ok(()) => start(&app, &name),
+        Err(e) => Err(e),
+    };
+    Ok(reply(&app, &name, res))
+}
+
+#[post("/user/server/{name}/update")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn server_update(
+    name: web::Path<String>, req: web::Json<Settings>, app: web::Data<AppState>,
+    current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&name)?;
+    let config = req.into_inner();
+    if let Some(running) = app.lock().unwrap().get(name.as_str()) {
+        current.check_fee(&config, &running.config)?;
+    }
+    let res = if config.name != *name {
+        Err(anyhow!("The transit name cannot change"))
+    } else {
+        update(&app, config).await
+    };
+    Ok(reply(&app, &name, res))
+}
+
+// Stops the transit and drops it from the configs file
+#[delete("/user/server/{name}")]
+#[has_permissions("ROLE_ADMIN")]
+async fn server_delete(
+    name: web::Path<String>, app: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let res = match stop(&app, &name).await {
+        Ok(()) => {
+            app.lock().unwrap().remove(name.as_str());
+            remove_config(CONFIGS_FILE, &name)
+        }
+        Err(e) => Err(e),
+    };
+    Ok(reply(&app, &name, res))
+}
+
+#[test]
+fn test_check_ports() {
+    let app = AppState::default();
+    let config = |name: &str, tcp_port: u32| Settings {
+        name: name.into(),
+        tcp_port,
+        ssl_port: 0,
+        encrypt_port: 0,
+        ..Default::default()
+    };
+    let held = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let port = held.local_addr().unwrap().port() as u32;
+
+    app.lock()
+        .unwrap()
+        .insert("a".into(), OnlineWorker::new(config("a", port), None));
+
+    assert!(check_ports(&app, &config("b", port), &[]).is_err());
+    // The transit itself holds the port
+    assert!(check_ports(&app, &config("a", port), &[]).is_err());
+    assert!(check_ports(&app, &config("a", port), &[port]).is_ok());
+    drop(held);
+    assert!(check_ports(&app, &config("a", port), &[]).is_ok());
+}
@@ -0,0 +1,292 @@
+use std::{collections::BTreeMap, fmt::Write, net::IpAddr};
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+
+use crate::{
+    client::{
+        pool_manager::PoolStatus,
+        router::{cidr_contains, parse_cidr},
+    },
+    state::Worker,
+    web::AppState,
+};
+
+lazy_static! {
+    // Comma separated CIDRs allowed to scrape `/metrics`. Unset means open.
+    // A list without any valid entry lets nobody in.
+    static ref METRICS_ALLOW: Option<Vec<(IpAddr, u8)>> =
+        match std::env::var("MINING_PROXY_METRICS_ALLOW") {
+            Ok(list) => Some(
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|cidr| !cidr.is_empty())
+                    .filter_map(|cidr| match parse_cidr(cidr) {
+                        Ok(cidr) => Some(cidr),
+                        Err(e) => {
+                            tracing::error!("MINING_PROXY_METRICS_ALLOW {}: {}", cidr, e);
+                            None
+                        }
+                    })
+                    .collect(),
+            ),
+            Err(_) => None,
+        };
+}
+
+// Prometheus text exposition of every transit and its online miners
+#[get("/metrics")]
+async fn metrics(req: HttpRequest, app: web::Data<AppState>) -> impl Responder {
+    if let Some(allow) = &*METRICS_ALLOW {
+        let ip = req.peer_addr().map(|addr| addr.ip());
+        if !matches!(ip, Some(ip) if allow.iter().any(|cidr| cidr_contains(*cidr, ip)))
+        {
+            return HttpResponse::Forbidden().finish();
+        }
+    }
+
+    let body = {
+        let proxy_server = app.lock().unwrap();
+        render(proxy_server.iter().map(|(name, server)| {
+            (name.as_str(), server.workers.as_slice(), server.pools.as_slice())
+        }))
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    // (labels, value)
+    samples: Vec<(String, f64)>,
+}
+
+impl Metric {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            help,
+            samples: vec![],
+        }
+    }
+
+    fn add(&mut self, labels: &[(&str, &str)], value: f64) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.samples.push((labels, value));
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// `servers` are (transit name, miners of the transit, its pools)
+pub fn render<'a, I>(servers: I) -> String
+where I: IntoIterator<Item = (&'a str, &'a [Worker], &'a [PoolStatus])> {
+    let mut miners = Metric::new(
+        "mining_proxy_miners_connected",
+        "gauge",
+        "Miners online on the transit",
+    );
+    let mut pool_miners = Metric::new(
+        "mining_proxy_pool_miners",
+        "gauge",
+        "Miners currently mining on an upstream pool",
+    );
+    let mut submitted = Metric::new(
+        "mining_proxy_worker_shares_submitted_total",
+        "counter",
+        "Shares the miner submitted for its own pool",
+    );
+    let mut accepted = Metric::new(
+        "mining_proxy_worker_shares_accepted_total",
+        "counter",
+        "Shares accepted by the miner's pool",
+    );
+    let mut rejected = Metric::new(
+        "mining_proxy_worker_shares_rejected_total",
+        "counter",
+        "Shares rejected by the miner's pool, by reason",
+    );
+    let mut fee_shares = Metric::new(
+        "mining_proxy_worker_fee_shares_total",
+        "counter",
+        "Shares found on fee jobs",
+    );
+    let mut develop_shares = Metric::new(
+        "mining_proxy_worker_develop_shares_total",
+        "counter",
+        "Shares found on developer jobs",
+    );
+    let mut reported = Metric::new(
+        "mining_proxy_worker_reported_hashrate",
+        "gauge",
+        "Hashrate in H/s reported by the miner",
+    );
+    let mut effective = Metric::new(
+        "mining_proxy_worker_effective_hashrate",
+        "gauge",
+        "Hashrate in H/s proven by accepted shares",
+    );
+    let mut pool_switches = Metric::new(
+        "mining_proxy_worker_pool_switches_total",
+        "counter",
+        "Times the session reconnected to another upstream pool",
+    );
+    let mut pool_up = Metric::new(
+        "mining_proxy_pool_up",
+        "gauge",
+        "1 while the upstream pool is in rotation, 0 while its circuit is open",
+    );
+    let mut pool_failures = Metric::new(
+        "mining_proxy_pool_failures_total",
+        "counter",
+        "Failed connections and health probes of the upstream pool",
+    );
+    let mut pool_circuit_opens = Metric::new(
+        "mining_proxy_pool_circuit_opens_total",
+        "counter",
+        "Times the upstream pool was taken out of rotation",
+    );
+    let mut pool_connects = Metric::new(
+        "mining_proxy_pool_connects_total",
+        "counter",
+        "Connections made to the upstream pool, reconnects included",
+    );
+
+    for (name, workers, pools) in servers {
+        for p in pools {
+            let labels = [
+                ("instance", name),
+                ("group", p.group.as_str()),
+                ("pool", p.pool.as_str()),
+            ];
+            pool_up.add(&labels, if p.up { 1.0 } else { 0.0 });
+            pool_failures.add(&labels, p.failures_total as f64);
+            pool_circuit_opens.add(&labels, p.circuit_opens as f64);
+            pool_connects.add(&labels, p.connects as f64);
+        }
+
+
+        let online: Vec<&Worker> = workers.iter().filter(|w| w.is_online()).collect();
+        miners.add(&[("instance", name)], online.len() as f64);
+
+        let mut pools: BTreeMap<&str, usize> = BTreeMap::new();
+        for w in online {
+            *pools.entry(w.pool.as_str()).or_default() += 1;
+
+            let labels = [
+                ("instance", name),
+                ("worker", w.worker_name.as_str()),
+                ("wallet", w.worker_wallet.as_str()),
+            ];
+            let with = |key, value| {
+                let mut l = labels.to_vec();
+                l.push((key, value));
+                l
+            };
+
+            submitted.add(&labels, w.share_index as f64);
+            accepted.add(&labels, w.accept_index as f64);
+            for (reason, count) in [
+                ("stale", w.rejects.stale),
+                ("low_difficulty", w.rejects.low_difficulty),
+                ("duplicate", w.rejects.duplicate),
+                ("unauthorized", w.rejects.unauthorized),
+                ("malformed", w.rejects.malformed),
+                ("other", w.rejects.other),
+            ] {
+                rejected.add(&with("reason", reason), count as f64);
+            }
+            fee_shares.add(&labels, w.fee_share_index as f64);
+            develop_shares.add(&labels, w.develop_share_index as f64);
+            reported.add(&labels, w.hash as f64);
+            for (window, hash) in [
+                ("5m", w.effective_hash.m5),
+                ("1h", w.effective_hash.h1),
+                ("24h", w.effective_hash.h24),
+            ] {
+                effective.add(&with("window", window), hash as f64);
+            }
+            pool_switches.add(&labels, w.pool_switch as f64);
+        }
+
+        for (pool, count) in pools {
+            pool_miners.add(&[("instance", name), ("pool", pool)], count as f64);
+        }
+    }
+
+    let mut out = String::new();
+    for metric in [
+        miners,
+        pool_miners,
+        submitted,
+        accepted,
+        rejected,
+        fee_shares,
+        develop_shares,
+        reported,
+        effective,
+        pool_switches,
+        pool_up,
+        pool_failures,
+        pool_circuit_opens,
+        pool_connects,
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind);
+        for (labels, value) in metric.samples {
+            let _ = writeln!(out, "{}{{{}}} {}", metric.name, labels, value);
+        }
+    }
+    out
+}
+
+#[test]
+fn test_render_metrics() {
+    let mut worker = Worker::new("0xabc.rig1".into(), "rig1".into(), "0xabc".into(), true);
+    worker.pool = "asia1.ethermine.org:4444".into();
+    worker.share_index = 10;
+    worker.accept_index = 9;
+    worker.rejects.stale = 1;
+    worker.effective_hash.h1 = 100_000_000;
+    let offline = Worker::new("0xabc.rig2".into(), "rig\"2".into(), "0xabc".into(), false);
+    let workers = vec![worker, offline];
+
+    let pools = vec![PoolStatus {
+        group: "proxy pool".into(),
+        pool: "asia1.ethermine.org:4444".into(),
+        up: false,
+        failures_total: 3,
+        circuit_opens: 1,
+        connects: 2,
+    }];
+
+    let out = render(vec![("eth", workers.as_slice(), pools.as_slice())]);
+    assert!(out.contains("mining_proxy_miners_connected{instance=\"eth\"} 1\n"));
+    assert!(out.contains(
+        "mining_proxy_pool_miners{instance=\"eth\",pool=\"asia1.ethermine.org:4444\"} 1\n"
+    ));
+    assert!(out.contains(
+        "mining_proxy_worker_shares_rejected_total{instance=\"eth\",worker=\"rig1\",wallet=\"0xabc\",reason=\"stale\"} 1\n"
+    ));
+    assert!(out.contains("window=\"1h\"} 100000000\n"));
+    assert!(out.contains(
+        "mining_proxy_pool_up{instance=\"eth\",group=\"proxy pool\",pool=\"asia1.ethermine.org:4444\"} 0\n"
+    ));
+    assert!(out.contains("mining_proxy_pool_failures_total{instance=\"eth\",group=\"proxy pool\",pool=\"asia1.ethermine.org:4444\"} 3\n"));
+    assert!(out.contains("# TYPE mining_proxy_worker_shares_accepted_total counter\n"));
+    assert!(!out.contains("rig\\\"2"));
+    assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+}
@@ -11,8 +11,22 @@ use actix_web::{get, post, web, Responder};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    util::{config::Settings, human_bytes, time_to_string},
-    web::{data::*, AppState, OnlineWorker},
+    ipc::{
+        parent::{reload_config, send_command},
+        Command, Reply,
+    },
+    ledger::{ledger_dir, now_millis, query_fees, WalletFee},
+    state::{EffectiveHash, RejectCounts, Worker},
+    util::{
+        config::{save_config, Settings, CONFIGS_FILE},
+        human_bytes, time_to_string,
+    },
+    web::{
+        data::*,
+        handles::lifecycle::{check_ports, process_status, ProcessStatus},
+        users::CurrentUser,
+        AppState, OnlineWorker,
+    },
 };
 
 #[post("/crate/app")]
@@ -30,7 +44,11 @@ pub async fn crate_app(
         }));
     }
 
-    if req.tcp_port == 0 && req.ssl_port == 0 && req.encrypt_port == 0 {
+    if req.tcp_port == 0
+        && req.ssl_port == 0
+        && req.encrypt_port == 0
+        && req.mux_port == 0
+    {
         return Ok(web::Json(Response::<String> {
             code: 40000,
             message: "Port not open. Please open at least one port".into(),
@@ -82,10 +100,15 @@ pub async fn crate_app(
     //config.log_path = "".into();
     config.name = req.name.clone();
     config.pool_address = vec![req.pool_address.clone()];
+    if !req.pool_protocol.is_empty() {
+        config.pool_protocol = req.pool_protocol.clone();
+    }
     config.share_address = vec![req.share_address.clone()];
     config.tcp_port = req.tcp_port;
     config.ssl_port = req.ssl_port;
     config.encrypt_port = req.encrypt_port;
+    config.mux_port = req.mux_port;
+    config.encrypt_key = req.key.clone();
     config.share = req.share;
     config.share_rate = req.share_rate as f32 / 100.0;
     config.share_alg = req.share_alg;
@@ -105,6 +128,14 @@ pub async fn crate_app(
         }
     };
 
+    if let Err(err) = check_ports(&app, &config, &[]) {
+        return Ok(web::Json(Response::<String> {
+            code: 40000,
+            message: err.to_string(),
+            data: String::default(),
+        }));
+    }
+
     match config.check_net_work().await {
         Ok(_) => {}
         Err(err) => {
@@ -200,12 +231,7 @@ pub async fn crate_app(
 
             match crate::util::run_server(&config) {
                 Ok(child) => {
-                    let online = OnlineWorker {
-                        child,
-                        config: config.clone(),
-                        workers: vec![],
-                        online: 0,
-                    };
+                    let online = OnlineWorker::new(config.clone(), Some(child));
                     app.lock().unwrap().insert(config.name, online);
                 }
                 Err(e) => {
@@ -253,12 +279,7 @@ pub async fn crate_app(
 
             match crate::util::run_server(&config) {
                 Ok(child) => {
-                    let online = OnlineWorker {
-                        child,
-                        config: config.clone(),
-                        workers: vec![],
-                        online: 0,
-                    };
+                    let online = OnlineWorker::new(config.clone(), Some(child));
                     app.lock().unwrap().insert(config.name, online);
                 }
                 Err(e) => {
@@ -280,19 +301,23 @@ pub async fn crate_app(
 }
 
 #[get("/user/server_list")]
-#[has_permissions("ROLE_ADMIN")]
+#[has_permissions("ROLE_VIEWER")]
 async fn server_list(
-    app: web::Data<AppState>,
+    app: web::Data<AppState>, current: CurrentUser,
 ) -> actix_web::Result<impl Responder> {
     let mut v = vec![];
     {
-        let proxy_server = app.lock().unwrap();
-        for (s, _) in &*proxy_server {
-            v.push(s.to_string());
+        let mut proxy_server = app.lock().unwrap();
+        for (s, transit) in proxy_server.iter_mut() {
+            if !current.user.can_access(s) {
+                continue;
+            }
+            v.push(process_status(s, transit));
         }
     }
+    v.sort_by(|a, b| a.name.cmp(&b.name));
 
-    Ok(web::Json(Response::<Vec<String>> {
+    Ok(web::Json(Response::<Vec<ProcessStatus>> {
         code: 20000,
         message: "".into(),
         data: v,
@@ -310,16 +335,31 @@ pub struct ResWorker {
     pub accept_index: u64,
     pub fee_accept_index: u64,
     pub invalid_index: u64,
+    pub rejects: RejectCounts,
+    pub pool: String,
+    pub pool_switch: u64,
+    pub route: String,
+    // Hashrate proven by accepted shares over 5 m, 1 h and 24 h
+    pub effective_hash_5m: String,
+    pub effective_hash_1h: String,
+    pub effective_hash_24h: String,
+    // Empty while online
+    pub offline_since: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct OnlineWorkerResult {
     pub workers: Vec<ResWorker>,
+    // Miners seen before that are not connected now
+    pub offline_workers: Vec<ResWorker>,
     pub online: u32,
     pub online_time: String,
     pub config: Settings,
     pub fee_hash: String,
     pub total_hash: String,
+    pub total_effective_hash_5m: String,
+    pub total_effective_hash_1h: String,
+    pub total_effective_hash_24h: String,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -328,18 +368,45 @@ pub struct OnlineWorkerResult {
     pub fee_reject_index: u64,
     pub rate: f64,
     pub share_rate: f64,
+    pub process: ProcessStatus,
+}
+
+fn res_worker(r: &Worker) -> ResWorker {
+    ResWorker {
+        worker_name: r.worker_name.clone(),
+        worker_wallet: r.worker_wallet.clone(),
+        hash: human_bytes(r.hash as f64),
+        share_index: r.share_index,
+        accept_index: r.accept_index,
+        invalid_index: r.invalid_index,
+        rejects: r.rejects.clone(),
+        pool: r.pool.clone(),
+        pool_switch: r.pool_switch,
+        route: r.route.clone(),
+        effective_hash_5m: human_bytes(r.effective_hash.m5 as f64),
+        effective_hash_1h: human_bytes(r.effective_hash.h1 as f64),
+        effective_hash_24h: human_bytes(r.effective_hash.h24 as f64),
+        fee_accept_index: r.fee_accept_index,
+        online_time: time_to_string(r.login_time.elapsed().as_secs()),
+        last_subwork_time: time_to_string(r.last_subwork_time.elapsed().as_secs()),
+        offline_since: "".into(),
+    }
 }
 
 // Display the selected data information. return in json format
 #[get("/user/server/{name}")]
+#[has_permissions("ROLE_VIEWER")]
 async fn server(
     proxy_server_name: web::Path<String>, app: web::Data<AppState>,
+    current: CurrentUser,
 ) -> actix_web::Result<impl Responder> {
+    current.check(&proxy_server_name)?;
     let mut total_hash: f64 = 0.0;
+    let mut effective_hash = EffectiveHash::default();
 
     let mut res: OnlineWorkerResult = OnlineWorkerResult::default();
     {
-        let proxy_server = app.lock().unwrap();
+        let mut proxy_server = app.lock().unwrap();
         let mut online = 0;
         let mut accept_index: u64 = 0;
         let mut share_index: u64 = 0;
@@ -348,27 +415,15 @@ async fn server(
         let mut fee_share_index: u64 = 0;
         let mut fee_reject_index: u64 = 0;
 
-        for (name, server) in &*proxy_server {
+        for (name, transit) in proxy_server.iter_mut() {
             if *name == proxy_server_name.to_string() {
-                for r in &server.workers {
+                res.process = process_status(name, transit);
+                for r in &transit.workers {
                     if r.is_online() {
                         online += 1;
                         total_hash += r.hash as f64;
-                        res.workers.push(ResWorker {
-                            worker_name: r.worker_name.clone(),
-                            worker_wallet: r.worker_wallet.clone(),
-                            hash: human_bytes(r.hash as f64),
-                            share_index: r.share_index,
-                            accept_index: r.accept_index,
-                            invalid_index: r.invalid_index,
-                            fee_accept_index: r.fee_accept_index,
-                            online_time: time_to_string(
-                                r.login_time.elapsed().as_secs(),
-                            ),
-                            last_subwork_time: time_to_string(
-                                r.last_subwork_time.elapsed().as_secs(),
-                            ),
-                        });
+                        effective_hash += r.effective_hash;
+                        res.workers.push(res_worker(r));
 
                         share_index += r.share_index;
                         accept_index += r.accept_index;
@@ -376,9 +431,15 @@ async fn server(
                         fee_accept_index += r.fee_share_index;
                         fee_share_index += r.fee_accept_index;
                         fee_reject_index += r.fee_invalid_index;
+                    } else {
+                        let mut w = res_worker(r);
+                        w.offline_since = time_to_string(
+                            (now_millis() / 1000).saturating_sub(r.last_seen),
+                        );
+                        res.offline_workers.push(w);
                     }
                 }
-                res.config = server.config.clone();
+                res.config = transit.config.clone();
             }
         }
 
@@ -404,6 +465,9 @@ async fn server(
         res.fee_hash =
             human_bytes(total_hash as f64 * res.config.share_rate as f64);
         res.total_hash = human_bytes(total_hash as f64);
+        res.total_effective_hash_5m = human_bytes(effective_hash.m5 as f64);
+        res.total_effective_hash_1h = human_bytes(effective_hash.h1 as f64);
+        res.total_effective_hash_24h = human_bytes(effective_hash.h24 as f64);
     }
 
     //1. Basic profile information.
@@ -417,6 +481,133 @@ async fn server(
     }))
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct FeeQuery {
+    // Unix seconds. `to` defaults to now.
+    pub from: u64,
+    pub to: u64,
+    pub wallet: String,
+}
+
+// Realized fee ratio per wallet from the share ledger of a transit
+#[get("/user/server/{name}/fees")]
+#[has_permissions("ROLE_VIEWER")]
+async fn fees(
+    proxy_server_name: web::Path<String>, query: web::Query<FeeQuery>,
+    current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&proxy_server_name)?;
+    let to = if query.to == 0 {
+        now_millis()
+    } else {
+        query.to.saturating_mul(1000)
+    };
+    let from = query.from.saturating_mul(1000);
+    let wallet = query.wallet.clone();
+    let dir = ledger_dir(&proxy_server_name);
+
+    // Reads every ledger file of the range, off the async workers
+    let res = web::block(move || {
+        let wallet = if wallet.is_empty() { None } else { Some(wallet.as_str()) };
+        query_fees(&dir, from, to, wallet)
+    })
+    .await?;
+
+    match res {
+        Ok(data) => Ok(web::Json(Response::<Vec<WalletFee>> {
+            code: 20000,
+            message: "".into(),
+            data,
+        })),
+        Err(e) => Ok(web::Json(Response::<Vec<WalletFee>> {
+            code: 40000,
+            message: format!("Failed to read the ledger: {}", e),
+            data: vec![],
+        })),
+    }
+}
+
+// Kick a miner, drain or fetch stats of a running transit
+#[post("/user/server/{name}/command")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn command(
+    proxy_server_name: web::Path<String>, req: web::Json<Command>,
+    app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&proxy_server_name)?;
+    let command = req.into_inner();
+    // Goes through the checks and the save of `/reload` instead
+    if let Command::ReloadConfig { .. } = command {
+        return Ok(web::Json(Response::<Reply> {
+            code: 40000,
+            message: format!("Use /user/server/{}/reload to change the config", proxy_server_name),
+            data: Reply::default(),
+        }));
+    }
+
+    match send_command(&app, &proxy_server_name, command).await {
+        Ok(reply) => Ok(web::Json(Response::<Reply> {
+            code: if reply.ok { 20000 } else { 40000 },
+            message: reply.message.clone(),
+            data: reply,
+        })),
+        Err(e) => Ok(web::Json(Response::<Reply> {
+            code: 40000,
+            message: e.to_string(),
+            data: Reply::default(),
+        })),
+    }
+}
+
+// Swap the config of a running transit without dropping its miners. Saved
+// to the configs file once the transit took it.
+#[post("/user/server/{name}/reload")]
+#[has_permissions("ROLE_OPERATOR")]
+async fn reload(
+    proxy_server_name: web::Path<String>, req: web::Json<Settings>,
+    app: web::Data<AppState>, current: CurrentUser,
+) -> actix_web::Result<impl Responder> {
+    current.check(&proxy_server_name)?;
+    let config = req.into_inner();
+    if let Some(running) = app.lock().unwrap().get(proxy_server_name.as_str()) {
+        current.check_fee(&config, &running.config)?;
+    }
+    if config.name != *proxy_server_name {
+        return Ok(web::Json(Response::<Reply> {
+            code: 40000,
+            message: "The transit name cannot change".into(),
+            data: Reply::default(),
+        }));
+    }
+
+    let reply = match reload_config(&app, config.clone()).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            return Ok(web::Json(Response::<Reply> {
+                code: 40000,
+                message: e.to_string(),
+                data: Reply::default(),
+            }))
+        }
+    };
+    if reply.ok {
+        if let Err(e) = save_config(CONFIGS_FILE, &config) {
+            return Ok(web::Json(Response::<Reply> {
+                code: 40000,
+                message: format!("Reloaded but not saved: {}", e),
+                data: reply,
+            }));
+        }
+    }
+
+    Ok(web::Json(Response::<Reply> {
+        code: if reply.ok { 20000 } else { 40000 },
+        message: reply.message.clone(),
+        data: reply,
+    }))
+}
+
 pub fn floor(value: f64, scale: i8) -> f64 {
     let multiplier = 10f64.powi(scale as i32) as f64;
     (value * multiplier).floor() / multiplier
@@ -428,6 +619,9 @@ pub struct DashboardResult {
     pub online: u32,
     pub fee_hash: String,
     pub total_hash: String,
+    pub total_effective_hash_5m: String,
+    pub total_effective_hash_1h: String,
+    pub total_effective_hash_24h: String,
     pub accept_index: u64,
     pub share_index: u64,
     pub reject_index: u64,
@@ -443,11 +637,13 @@ pub struct DashboardResult {
 
 // Display the selected data information. return in json format
 #[post("/user/dashboard")]
+#[has_permissions("ROLE_VIEWER")]
 async fn dashboard(
-    app: web::Data<AppState>,
+    app: web::Data<AppState>, current: CurrentUser,
 ) -> actix_web::Result<impl Responder> {
     let mut total_hash: f64 = 0.0;
     let mut fee_hash: f64 = 0.0;
+    let mut effective_hash = EffectiveHash::default();
     let mut res: DashboardResult = DashboardResult::default();
     {
         let proxy_server = app.lock().unwrap();
@@ -459,11 +655,16 @@ async fn dashboard(
         let mut fee_share_index: u64 = 0;
         let mut fee_reject_index: u64 = 0;
 
-        for (_, other_server) in &*proxy_server {
+        let visible: Vec<_> = proxy_server
+            .iter()
+            .filter(|(name, _)| current.user.can_access(name))
+            .collect();
+        for (_, other_server) in &visible {
             for r in &other_server.workers {
                 if r.is_online() {
                     online += 1;
                     total_hash += r.hash as f64;
+                    effective_hash += r.effective_hash;
                     share_index += r.share_index;
                     accept_index += r.accept_index;
                     reject_index += r.invalid_index;
@@ -484,12 +685,15 @@ async fn dashboard(
         res.fee_share_index += fee_share_index;
         res.fee_reject_index += fee_reject_index;
 
-        res.proxy_num = proxy_server.len() as i32;
+        res.proxy_num = visible.len() as i32;
         res.online = online;
     }
 
     res.fee_hash = human_bytes(fee_hash as f64);
     res.total_hash = human_bytes(total_hash as f64);
+    res.total_effective_hash_5m = human_bytes(effective_hash.m5 as f64);
+    res.total_effective_hash_1h = human_bytes(effective_hash.h1 as f64);
+    res.total_effective_hash_24h = human_bytes(effective_hash.h24 as f64);
     if res.accept_index > 0 {
         res.rate =
             floor(res.accept_index as f64 / res.share_index as f64 * 100.0, 2);
@@ -1,32 +1,36 @@
-use actix_web::{get, post, web, Responder};
+use actix_web::{delete, get, post, web, Responder};
 use actix_web_grants::proc_macro::has_permissions;
 use chrono::Utc;
 
 use crate::web::{
     data::*,
     handles::auth::{generate_jwt, Claims},
+    users::{CurrentUser, User, Users},
 };
 
 #[post("/user/login")]
 async fn login(
-    req: web::Json<LoginRequest>,
+    req: web::Json<LoginRequest>, users: web::Data<Users>,
 ) -> actix_web::Result<impl Responder> {
-    let password = match std::env::var("MINING_PROXY_WEB_PASSWORD") {
-        Ok(t) => t,
-        Err(_) => "admin123".into(),
+    let username = if req.username.is_empty() {
+        "admin"
+    } else {
+        req.username.as_str()
     };
 
-    if password != req.password {
-        return Ok(web::Json(Response::<TokenDataResponse> {
-            code: 40000,
-            message: "The password is incorrect".into(),
-            data: TokenDataResponse::default(),
-        }));
-    }
+    let user = match users.verify(username, &req.password) {
+        Some(user) => user,
+        None => {
+            return Ok(web::Json(Response::<TokenDataResponse> {
+                code: 40000,
+                message: "The username or password is incorrect".into(),
+                data: TokenDataResponse::default(),
+            }));
+        }
+    };
     let iat = Utc::now();
     let exp = iat + chrono::Duration::days(1);
-    if let Ok(jwt_token) = generate_jwt(Claims::new("mining_proxy".into(), exp))
-    {
+    if let Ok(jwt_token) = generate_jwt(Claims::new(user.username, exp)) {
         Ok(web::Json(Response::<TokenDataResponse> {
             code: 20000,
             message: "".into(),
@@ -42,26 +46,91 @@ async fn login(
 }
 
 #[get("/user/info")]
-#[has_permissions("ROLE_ADMIN")]
-async fn info() -> actix_web::Result<impl Responder> {
+#[has_permissions("ROLE_VIEWER")]
+async fn info(current: CurrentUser) -> actix_web::Result<impl Responder> {
     Ok(web::Json(Response::<InfoResponse> {
         code: 20000,
         message: "".into(),
         data: InfoResponse {
-            roles: vec!["admin".into()],
+            roles: vec![current.user.role.name().into()],
             introduction: "".into(),
             avatar: "".into(),
-            name: "admin".into(),
+            name: current.user.username,
         },
     }))
 }
 
+// The token stops working right away, not when it expires
 #[post("/user/logout")]
-#[has_permissions("ROLE_ADMIN")]
-async fn logout() -> actix_web::Result<impl Responder> {
+#[has_permissions("ROLE_VIEWER")]
+async fn logout(
+    current: CurrentUser, users: web::Data<Users>,
+) -> actix_web::Result<impl Responder> {
+    let (code, message) = match users.revoke(&current.claims) {
+        Ok(()) => (20000, "".into()),
+        Err(e) => (40000, e.to_string()),
+    };
     Ok(web::Json(Response::<String> {
+        code,
+        message,
+        data: "".into(),
+    }))
+}
+
+#[get("/user/users")]
+#[has_permissions("ROLE_ADMIN")]
+async fn user_list(users: web::Data<Users>) -> actix_web::Result<impl Responder> {
+    let data = users
+        .list()
+        .into_iter()
+        .map(|u| UserResponse {
+            username: u.username,
+            role: u.role,
+            servers: u.servers,
+        })
+        .collect();
+    Ok(web::Json(Response::<Vec<UserResponse>> {
         code: 20000,
         message: "".into(),
+        data,
+    }))
+}
+
+#[post("/user/users")]
+#[has_permissions("ROLE_ADMIN")]
+async fn user_save(
+    req: web::Json<UserRequest>, users: web::Data<Users>,
+) -> actix_web::Result<impl Responder> {
+    let req = req.into_inner();
+    let user = User {
+        username: req.username,
+        password: String::new(),
+        role: req.role,
+        servers: req.servers,
+    };
+    let (code, message) = match users.save(user, &req.password) {
+        Ok(()) => (20000, "".into()),
+        Err(e) => (40000, e.to_string()),
+    };
+    Ok(web::Json(Response::<String> {
+        code,
+        message,
+        data: "".into(),
+    }))
+}
+
+#[delete("/user/users/{username}")]
+#[has_permissions("ROLE_ADMIN")]
+async fn user_delete(
+    username: web::Path<String>, users: web::Data<Users>,
+) -> actix_web::Result<impl Responder> {
+    let (code, message) = match users.remove(&username) {
+        Ok(()) => (20000, "".into()),
+        Err(e) => (40000, e.to_string()),
+    };
+    Ok(web::Json(Response::<String> {
+        code,
+        message,
         data: "".into(),
     }))
 }
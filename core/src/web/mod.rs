@@ -1,7 +1,13 @@
-use crate::{state::Worker, util::config::Settings};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::pool_manager::PoolStatus, ipc::parent::CommandSender, ledger::now_millis,
+    state::Worker, util::config::Settings,
+};
 
 pub mod data;
 pub mod handles;
+pub mod users;
 // pub struct AppState {
 //     pub global_count: std::sync::Arc<
 //         std::sync::Mutex<std::collections::HashMap<String, OnlineWorker>>,
@@ -13,8 +19,50 @@ pub type AppState = std::sync::Arc<
 >;
 
 pub struct OnlineWorker {
-    pub child: tokio::process::Child,
+    // None once stopped from the web interface or after a crash
+    pub child: Option<tokio::process::Child>,
     pub workers: Vec<Worker>,
     pub online: u32,
     pub config: Settings,
+    // Set while the transit is connected over IPC
+    pub commands: Option<CommandSender>,
+    // Last health report of its pools, empty while not connected
+    pub pools: Vec<PoolStatus>,
+    pub process: ProcessInfo,
+}
+
+impl OnlineWorker {
+    pub fn new(config: Settings, child: Option<tokio::process::Child>) -> Self {
+        let mut process = ProcessInfo::default();
+        if child.is_some() {
+            process.started_at = now_millis() / 1000;
+        }
+        Self {
+            child,
+            workers: vec![],
+            online: 0,
+            config,
+            commands: None,
+            pools: vec![],
+            process,
+        }
+    }
+}
+
+// Kept across restarts of the transit process. Times are unix seconds, 0 for
+// never.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProcessInfo {
+    // Restarts after a crash since the supervisor started
+    pub restarts: u32,
+    // Crashes in a row, see `RestartPolicy::backoff`
+    pub failures: u32,
+    pub started_at: u64,
+    pub exited_at: u64,
+    // Last time the process exited by itself, empty if it never did
+    pub exit: String,
+    // None when killed by a signal
+    pub exit_code: Option<i32>,
+    // Pending restart
+    pub restart_at: u64,
 }
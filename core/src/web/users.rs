@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    io::Write,
+    sync::RwLock,
+};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    FromRequest, HttpMessage, HttpRequest,
+};
+use anyhow::{anyhow, bail, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::Utc;
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::{util::config::Settings, web::handles::auth::Claims, JWT_SECRET};
+
+// Web accounts and logged out tokens, in the working directory
+pub const USERS_FILE: &str = "users.yaml";
+pub const REVOKED_FILE: &str = "revoked_tokens.json";
+pub const JWT_SECRET_FILE: &str = "jwt_secret";
+
+pub const ROLE_ADMIN: &str = "ROLE_ADMIN";
+pub const ROLE_OPERATOR: &str = "ROLE_OPERATOR";
+pub const ROLE_VIEWER: &str = "ROLE_VIEWER";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Everything, including transits and accounts
+    Admin,
+    // Starts, stops, reconfigures and commands its transits
+    Operator,
+    // Reads its transits. The least a new user gets.
+    #[default]
+    Viewer,
+}
+
+impl Role {
+    // A role holds the grants of the roles below it
+    pub fn grants(&self) -> Vec<String> {
+        let grants: &[&str] = match self {
+            Role::Admin => &[ROLE_ADMIN, ROLE_OPERATOR, ROLE_VIEWER],
+            Role::Operator => &[ROLE_OPERATOR, ROLE_VIEWER],
+            Role::Viewer => &[ROLE_VIEWER],
+        };
+        grants.iter().map(|g| g.to_string()).collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    // Argon2 hash in PHC format
+    pub password: String,
+    pub role: Role,
+    // Transits an operator or viewer owns. Admins see all of them.
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+impl User {
+    pub fn can_access(&self, server: &str) -> bool {
+        self.role == Role::Admin || self.servers.iter().any(|s| s == server)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::b64_encode(&rand::random::<[u8; 16]>())
+        .map_err(|e| anyhow!("{}", e))?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("{}", e))?
+        .to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub struct Users {
+    path: String,
+    revoked_path: String,
+    users: RwLock<Vec<User>>,
+    // Token ids logged out before they expire, with their expiry
+    revoked: RwLock<HashMap<String, i64>>,
+    // What tokens are signed with
+    secret: String,
+}
+
+impl Users {
+    // Without a users file there is a single `admin`, with the password of
+    // MINING_PROXY_WEB_PASSWORD or a random one written to the log
+    pub fn open(path: &str, revoked_path: &str) -> Result<Self> {
+        let password = std::env::var("MINING_PROXY_WEB_PASSWORD").ok();
+        Self::open_with(path, revoked_path, password, JWT_SECRET.clone())
+    }
+
+    pub fn open_with(
+        path: &str, revoked_path: &str, password: Option<String>, secret: String,
+    ) -> Result<Self> {
+        let users: Vec<User> = match std::fs::read_to_string(path) {
+            Ok(s) => serde_yaml::from_str(&s)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => bail!("Failed to read {}: {}", path, e),
+        };
+        let revoked = match std::fs::read_to_string(revoked_path) {
+            Ok(s) => serde_json::from_str(&s)?,
+            Err(_) => HashMap::new(),
+        };
+
+        let store = Self {
+            path: path.into(),
+            revoked_path: revoked_path.into(),
+            users: RwLock::new(users),
+            revoked: RwLock::new(revoked),
+            secret,
+        };
+        if store.users.read().unwrap().is_empty() {
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    let password = hex::encode(rand::random::<[u8; 12]>());
+                    tracing::warn!("Created web user admin with password {}", password);
+                    password
+                }
+            };
+            store.save(
+                User {
+                    username: "admin".into(),
+                    password: String::new(),
+                    role: Role::Admin,
+                    servers: vec![],
+                },
+                &password,
+            )?;
+        }
+        Ok(store)
+    }
+
+    pub fn list(&self) -> Vec<User> { self.users.read().unwrap().clone() }
+
+    pub fn get(&self, username: &str) -> Option<User> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| u.username == username)
+            .cloned()
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> Option<User> {
+        self.get(username)
+            .filter(|user| verify_password(&user.password, password))
+    }
+
+    // Adds or replaces the user. An empty password keeps the current one.
+    pub fn save(&self, mut user: User, password: &str) -> Result<()> {
+        if user.username.is_empty() {
+            bail!("The username cannot be empty");
+        }
+        let mut users = self.users.write().unwrap();
+        let current = users.iter().position(|u| u.username == user.username);
+
+        user.password = match (password.is_empty(), current) {
+            (false, _) => hash_password(password)?,
+            (true, Some(i)) => users[i].password.clone(),
+            (true, None) => bail!("A new user needs a password"),
+        };
+        let mut next = users.clone();
+        match current {
+            Some(i) => next[i] = user,
+            None => next.push(user),
+        }
+        if !next.iter().any(|u| u.role == Role::Admin) {
+            bail!("At least one admin is needed");
+        }
+        write_private(&self.path, serde_yaml::to_string(&next)?.as_bytes())?;
+        *users = next;
+        Ok(())
+    }
+
+    pub fn remove(&self, username: &str) -> Result<()> {
+        let mut users = self.users.write().unwrap();
+        let left: Vec<User> = users
+            .iter()
+            .filter(|u| u.username != username)
+            .cloned()
+            .collect();
+        if left.len() == users.len() {
+            bail!("User {} not found", username);
+        }
+        if !left.iter().any(|u| u.role == Role::Admin) {
+            bail!("At least one admin is needed");
+        }
+        write_private(&self.path, serde_yaml::to_string(&left)?.as_bytes())?;
+        *users = left;
+        Ok(())
+    }
+
+    // The user of a valid token that was not logged out. Role and transits
+    // come from the store, so changes apply to tokens already issued.
+    pub fn authenticate(&self, token: &str) -> Option<CurrentUser> {
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(self.secret.as_bytes()),
+            &Validation::default(),
+        )
+        .ok()?
+        .claims;
+        if self.revoked.read().unwrap().contains_key(&claims.jti) {
+            return None;
+        }
+        let user = self.get(&claims.username)?;
+        Some(CurrentUser { user, claims })
+    }
+
+    pub fn revoke(&self, claims: &Claims) -> Result<()> {
+        let mut revoked = self.revoked.write().unwrap();
+        let now = Utc::now().timestamp();
+        // Expired tokens are refused anyway
+        revoked.retain(|_, exp| *exp > now);
+        revoked.insert(claims.jti.clone(), claims.exp());
+        write_private(&self.revoked_path, serde_json::to_string(&*revoked)?.as_bytes())
+    }
+}
+
+// Set on the request once its token is checked
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub user: User,
+    pub claims: Claims,
+}
+
+impl CurrentUser {
+    pub fn check(&self, server: &str) -> actix_web::Result<()> {
+        if self.user.can_access(server) {
+            Ok(())
+        } else {
+            Err(ErrorForbidden(format!("No access to transit {}", server)))
+        }
+    }
+
+    // Only admins move the fee of a transit
+    pub fn check_fee(&self, new: &Settings, current: &Settings) -> actix_web::Result<()> {
+        if self.user.role == Role::Admin || new.same_fee(current) {
+            Ok(())
+        } else {
+            Err(ErrorForbidden("Only admins can change the fee settings"))
+        }
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CurrentUser>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Not logged in")),
+        )
+    }
+}
+
+// The secret that signs web tokens, made on first run
+pub fn load_secret(path: &str) -> String {
+    if let Ok(secret) = std::fs::read_to_string(path) {
+        if !secret.trim().is_empty() {
+            return secret.trim().to_string();
+        }
+    }
+
+    let secret = hex::encode(rand::random::<[u8; 32]>());
+    if let Err(e) = write_private(path, secret.as_bytes()) {
+        tracing::error!("Failed to save {}, logins end with this run: {}", path, e);
+    }
+    secret
+}
+
+// Only readable by the owner on Unix
+fn write_private(path: &str, contents: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[test]
+fn test_users() {
+    let dir = std::env::temp_dir().join(format!("mining_proxy_users_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(USERS_FILE).to_string_lossy().to_string();
+    let revoked_path = dir.join(REVOKED_FILE).to_string_lossy().to_string();
+
+    let open = || Users::open_with(&path, &revoked_path, Some("first".into()), "test".into());
+    let users = open().unwrap();
+    assert!(users.verify("admin", "first").is_some());
+    assert!(users.verify("admin", "admin123").is_none());
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("first"));
+
+    let customer = User {
+        username: "customer".into(),
+        password: String::new(),
+        role: Role::Viewer,
+        servers: vec!["eth".into()],
+    };
+    assert!(users.save(customer.clone(), "").is_err());
+    users.save(customer, "secret").unwrap();
+    let customer = users.verify("customer", "secret").unwrap();
+    assert!(customer.can_access("eth"));
+    assert!(!customer.can_access("etc"));
+    assert_eq!(customer.role.grants(), vec![ROLE_VIEWER.to_string()]);
+    assert!(users.remove("admin").is_err());
+
+    let claims = Claims::new("customer".into(), Utc::now() + chrono::Duration::hours(1));
+    let token = crate::web::handles::auth::sign_jwt(claims.clone(), "test").unwrap();
+    assert_eq!(users.authenticate(&token).unwrap().user.username, "customer");
+    users.revoke(&claims).unwrap();
+    assert!(users.authenticate(&token).is_none());
+
+    // Survives a restart
+    let users = open().unwrap();
+    assert!(users.verify("customer", "secret").is_some());
+    assert!(users.authenticate(&token).is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
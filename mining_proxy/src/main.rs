@@ -4,13 +4,14 @@ mod version {
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
-use rustls_pemfile::{certs, rsa_private_keys};
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
 
-use std::{path::Path, sync::Arc, collections::VecDeque};
+use std::{
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+};
 use tracing::Level;
 
-use tokio::sync::{broadcast, RwLock, Mutex};
+use tokio::sync::{broadcast, watch, RwLock};
 
 use tracing_subscriber::{
     self,
@@ -18,23 +19,40 @@ use tracing_subscriber::{
 };
 
 use dotenv::dotenv;
-use jsonwebtoken::{decode, DecodingKey, Validation};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::OpenOptions, io::Read};
+use std::{collections::HashMap, fs::OpenOptions, io::Read, time::Duration};
 
 
 
-use actix_web::{dev::ServiceRequest, web, App, Error, HttpServer};
+use actix_web::{dev::ServiceRequest, web, App, Error, HttpMessage, HttpServer};
 
 use core::{
+    alert::Alerts,
+    ipc,
+    ledger::Ledger,
     client::{
-        encry::accept_en_tcp, tcp::accept_tcp, tls::accept_tcp_with_tls, SSL,
-        TCP,
+        encry::accept_en_tcp, mux::accept_mux_tcp, pool_manager::PoolManager,
+        router::Router, tcp::accept_tcp,
+        tls::accept_tcp_with_tls,
+    },
+    protocol::{
+        bridge::{EthStratumUpstream, POOL_PROTOCOL_ETHSTRATUM},
+        ethjson::EthClientRootObject,
+        CLIENT_LOGIN,
+    },
+    proxy::FeeRounds,
+    state::{
+        snapshot::{self, SNAPSHOT_FILE, SNAPSHOT_INTERVAL},
+        Worker,
+    },
+    util::{
+        config::{Settings, CONFIGS_FILE},
+        reload::ReloadTriggers,
+    },
+    web::{
+        handles::lifecycle,
+        users::{Users, REVOKED_FILE, USERS_FILE},
+        AppState, OnlineWorker,
     },
-    proxy::Job,
-    state::Worker,
-    util::config::Settings,
-    web::{handles::auth::Claims, AppState, OnlineWorker},
 };
 
 use anyhow::{bail, Result};
@@ -43,11 +61,7 @@ use clap::{crate_version, ArgMatches};
 //use crossbeam_channel::bounded;
 use human_panic::setup_panic;
 
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    select,
-    sync::mpsc::{self, UnboundedReceiver},
-};
+use tokio::sync::mpsc;
 
 fn main() -> Result<()> {
     setup_panic!();
@@ -135,12 +149,10 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
                     for config in configs {
                         match core::util::run_server(&config) {
                             Ok(child) => {
-                                let online = OnlineWorker {
-                                    child,
-                                    config: config.clone(),
-                                    workers: vec![],
-                                    online: 0,
-                                };
+                                let online = OnlineWorker::new(
+                                    config.clone(),
+                                    Some(child),
+                                );
 
                                 data.lock()
                                     .unwrap()
@@ -157,15 +169,65 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
         Err(_) => {}
     };
 
-    let tcp_data = data.clone();
+    // Miners seen before the restart, shown as offline until they report again
+    match snapshot::load(Path::new(SNAPSHOT_FILE)) {
+        Ok(saved) => snapshot::restore(&data, saved),
+        Err(e) => tracing::error!("Failed to load {}: {}", SNAPSHOT_FILE, e),
+    }
 
-    tokio::spawn(async move { recv_from_child(tcp_data).await });
+    let ipc_data = data.clone();
+    tokio::spawn(async move {
+        if let Err(e) = ipc::parent::serve(ipc_data).await {
+            tracing::error!("Transits cannot report to the supervisor: {}", e);
+        }
+    });
+
+    // Restarts crashed transits per their restart policy
+    let supervise_data = data.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            lifecycle::supervise(&supervise_data);
+        }
+    });
+
+    let reload_data = data.clone();
+    tokio::spawn(async move {
+        let mut triggers = match ReloadTriggers::new(CONFIGS_FILE) {
+            Ok(triggers) => triggers,
+            Err(e) => {
+                tracing::error!("Config reload is off: {}", e);
+                return;
+            }
+        };
+        loop {
+            let reason = triggers.next().await;
+            tracing::info!("Reloading {} on {}", CONFIGS_FILE, reason);
+            if let Err(e) = ipc::parent::reload_configs(&reload_data, CONFIGS_FILE).await {
+                tracing::error!("Failed to reload {}: {}", CONFIGS_FILE, e);
+            }
+        }
+    });
+
+    let snapshot_data = data.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let saved = snapshot::take(&snapshot_data);
+            if let Err(e) = snapshot::save(Path::new(SNAPSHOT_FILE), &saved) {
+                tracing::error!("Failed to save {}: {}", SNAPSHOT_FILE, e);
+            }
+        }
+    });
 
     let port: i32 = match std::env::var("MINING_PROXY_WEB_PORT") {
         Ok(p) => p.parse().unwrap(),
         Err(_) => 8888,
     };
 
+    let users = web::Data::new(Users::open(USERS_FILE, REVOKED_FILE)?);
     let http_data = data.clone();
     let web_sever = if let Ok(http) = HttpServer::new(move || {
         let generated = generate();
@@ -176,16 +238,30 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
         App::new()
             .wrap(auth)
             .app_data(web::Data::new(http_data.clone()))
+            .app_data(users.clone())
             .service(
                 web::scope("/api")
                     .service(core::web::handles::user::login)
                     .service(core::web::handles::user::info)
                     .service(core::web::handles::user::logout)
+                    .service(core::web::handles::user::user_list)
+                    .service(core::web::handles::user::user_save)
+                    .service(core::web::handles::user::user_delete)
                     .service(core::web::handles::server::crate_app)
                     .service(core::web::handles::server::server_list)
                     .service(core::web::handles::server::server)
+                    .service(core::web::handles::server::fees)
+                    .service(core::web::handles::server::command)
+                    .service(core::web::handles::server::reload)
+                    .service(core::web::handles::lifecycle::server_status)
+                    .service(core::web::handles::lifecycle::server_start)
+                    .service(core::web::handles::lifecycle::server_stop)
+                    .service(core::web::handles::lifecycle::server_restart)
+                    .service(core::web::handles::lifecycle::server_update)
+                    .service(core::web::handles::lifecycle::server_delete)
                     .service(core::web::handles::server::dashboard),
             )
+            .service(core::web::handles::metrics::metrics)
             .service(actix_web_static_files::ResourceFiles::new("/", generated))
     })
     .bind(format!("0.0.0.0:{}", port))
@@ -194,7 +270,9 @@ async fn async_main(_matches: ArgMatches<'_>) -> Result<()> {
     } else {
         let mut proxy_server = data.lock().unwrap();
         for (_, other_server) in &mut *proxy_server {
-            other_server.child.kill().await?;
+            if let Some(child) = &mut other_server.child {
+                child.kill().await?;
+            }
         }
         bail!("web port {} is occupied", port);
     };
@@ -278,94 +356,43 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
 
     let worker_name = config.share_name.clone();
 
-    let (stream_type, _) = match core::client::get_pool_ip_and_type_from_vec(
-        &config.share_address,
-    ) {
-        Ok((stream, addr)) => (stream, addr),
+    let pool = match PoolManager::new("proxy pool", &config.pool_address) {
+        Ok(pool) => Arc::new(pool),
         Err(e) => {
-            tracing::error!("Share_address mining pool parameter formatting failed. Unable to start {}", e);
+            tracing::error!("Pool_address mining pool parameter formatting failed. Unable to start {}", e);
             return Ok(());
         }
     };
 
-    let certs = match load_certs(Path::new(&config.pem_path)) {
-        Ok(cert) => {
-            // tracing::info!(
-            //     "Custom SSL certificate {} read successfully. use this certificate.",
-            //     config.pem_path
-            // );
-            cert
-        }
-        Err(_) => {
-            // tracing::error!(
-            //     "Custom SSL certificate {} not found or malformed. Default certificate will be used",
-            //     config.pem_path
-            // );
-            //panic!("Default certificate pem not found");
-
-            tracing::info!(
-                "Custom SSL certificate {} read failed. Please set the certificate. The program will exit if not set. .",
-                config.pem_path
-            );
-            std::process::exit(1);
+    let fee_pool = match PoolManager::new("fee pool", &config.share_address) {
+        Ok(pool) => Arc::new(pool),
+        Err(e) => {
+            tracing::error!("Share_address mining pool parameter formatting failed. Unable to start {}", e);
+            return Ok(());
         }
     };
 
-    let mut keys = match load_keys(Path::new(&config.key_path)) {
-        Ok(key) => {
-            // tracing::info!(
-            //     "The custom key key {} was read successfully. Use this key.",
-            //     config.key_path
-            // );
-            key
-        }
-        Err(_) => {
-            // tracing::error!(
-            //     "Custom key key {} not found or malformed. Default certificate will be used",
-            //     config.key_path
-            // );
-            tracing::info!(
-                "Failed to read custom key key {}. Please set the certificate. The program will exit if not set. .",
-                config.key_path
-            );
-            std::process::exit(1);
-            //panic!("Default Key not found");
-            //let key_pem = include_bytes!("key.pem");
+    let develop_pool = match core::client::develop_pool() {
+        Ok(pool) => Arc::new(pool),
+        Err(e) => {
+            tracing::error!("Developer pool parameter formatting failed. Unable to start {}", e);
+            return Ok(());
         }
     };
 
-    let cert_config = match rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys.remove(0))
-        .map_err(|err| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
-        }) {
-        Ok(conf) => {
-            // tracing::info!(
-            //     "Custom SSL certificate {} is well-formed. Use this certificate as SSL certificate",
-            //     config.pem_path
-            // );
-            conf
-        }
+    let router = match Router::new(&config.routes) {
+        Ok(router) => router,
         Err(e) => {
-            tracing::info!("Certificate formatting failed. Please modify the certificate: {}", e);
-            std::process::exit(1);
+            tracing::error!("Routes parameter formatting failed. Unable to start {}", e);
+            return Ok(());
         }
     };
 
-    //    if config.coin == "ETH" {
-    // let (chan_tx, _chan_rx) = broadcast::channel::<Vec<String>>(1);
-    // let (dev_chan_tx, _dev_chan_rx) = broadcast::channel::<Vec<String>>(1);
-    let fee_job:Job = Arc::new(RwLock::new(VecDeque::new()));
-    let develop_job:Job = Arc::new(RwLock::new(VecDeque::new()));
-    
-    let (tx, rx) = mpsc::channel::<Vec<String>>(15);
-    let (dev_tx, dev_rx) = mpsc::channel::<Vec<String>>(15);
-    // let (tx, rx) =
-    //     bounded::<Vec<String>>(15);
-    // let (dev_tx, dev_rx) =
-    //     bounded::<Vec<String>>(15);
+    // One set of fee rounds per dialect, connected once a miner needs it
+    let (eth_fee, rx, dev_rx) = FeeRounds::new();
+    let (stratum_fee, stratum_rx, stratum_dev_rx) = FeeRounds::new();
+    let (ledger, ledger_writer) = Ledger::new(&config.name);
+    let (alerts, alert_runner) = Alerts::new(&config.name, config.alert.clone());
     tracing::debug!("Create a miner queue");
     // Absenteeism status sending queue
     let (worker_tx, worker_rx) = mpsc::unbounded_channel::<Worker>();
@@ -374,221 +401,149 @@ async fn tokio_run(matches: &ArgMatches<'_>) -> Result<()> {
     let proxy = Arc::new(core::proxy::Proxy {
         config: Arc::new(RwLock::new(config)),
         worker_tx,
-//        chan: chan_tx.clone(),
-        tx,
-        dev_tx,
-	fee_job:fee_job.clone(),
-	develop_job:develop_job.clone(),
-//        dev_chan: dev_chan_tx.clone(),
+        eth_fee,
+        stratum_fee,
+        pool: pool.clone(),
+        fee_pool: fee_pool.clone(),
+        router,
+        ledger,
+        kick: broadcast::channel(16).0,
+        draining: AtomicBool::new(false),
+        reloaded: watch::channel(0).0,
     });
 
-    let (dev_lines, dev_w) =
-        core::client::dev_pool_ssl_login(core::DEVELOP_WORKER_NAME.to_string())
-            .await?;
-
-    if stream_type == TCP {
-        let (proxy_lines, proxy_w) =
-            core::client::proxy_pool_login(&mconfig, worker_name.clone())
-                .await?;
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_tcp(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            core::client::fee::develop_fee_ssl(
-                dev_rx,
-		develop_job,
-                dev_lines,
-                dev_w,
-                core::DEVELOP_WORKER_NAME.to_string(),
-		proxy,
-            ),
-        );
-
-        if let Err(err) = res {
-            tracing::error!("fatal error: {}", err);
-        }
-    } else if stream_type == SSL {
-        let (proxy_lines, proxy_w) = core::client::proxy_pool_login_with_ssl(
-            &mconfig,
+    let res = tokio::try_join!(
+        accept_tcp(Arc::clone(&proxy)),
+        accept_en_tcp(Arc::clone(&proxy)),
+        accept_mux_tcp(Arc::clone(&proxy)),
+        accept_tcp_with_tls(Arc::clone(&proxy)),
+        ipc::child::run(Arc::clone(&proxy), worker_rx, alerts),
+        reload_config(Arc::clone(&proxy), config_file_name.to_string()),
+        alert_runner.run(alert_pools(&proxy)),
+        ledger_writer.run(),
+        pool.run_health_checks(pool_probe(&mconfig)),
+        fee_pool.run_health_checks(pool_probe(&mconfig)),
+        proxy.router.run_health_checks(pool_probe(&mconfig)),
+        core::client::fee::proxy_fee(
+            rx,
             worker_name.clone(),
-        )
-        .await?;
-
-        let res = tokio::try_join!(
-            accept_tcp(Arc::clone(&proxy)),
-            accept_en_tcp(Arc::clone(&proxy)),
-            accept_tcp_with_tls(Arc::clone(&proxy), cert_config),
-            send_to_parent(worker_rx, &mconfig),
-            core::client::fee::fee_ssl(
-                rx,
-		fee_job,
-                proxy_lines,
-                proxy_w,
-                worker_name.clone(),
-		proxy.clone(),
-            ),
-            core::client::fee::develop_fee_ssl(
-                dev_rx,
-		develop_job,
-                dev_lines,
-                dev_w,
-                core::DEVELOP_WORKER_NAME.to_string(),
-		proxy,
-            ),
-        );
-
-        if let Err(err) = res {
-            tracing::error!("fatal error: {}", err);
-        }
+            proxy.clone(),
+            false,
+        ),
+        core::client::fee::proxy_fee(
+            stratum_rx,
+            worker_name.clone(),
+            proxy.clone(),
+            true,
+        ),
+        develop_pool.run_health_checks(core::client::develop_login()),
+        core::client::fee::develop_fee_ssl(
+            dev_rx,
+            develop_pool.clone(),
+            core::DEVELOP_WORKER_NAME.to_string(),
+            proxy.clone(),
+            false,
+        ),
+        core::client::fee::develop_fee_ssl(
+            stratum_dev_rx,
+            develop_pool.clone(),
+            core::DEVELOP_WORKER_NAME.to_string(),
+            proxy.clone(),
+            true,
+        ),
+    );
+
+    if let Err(err) = res {
+        tracing::error!("fatal error: {}", err);
     }
 
     Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct SendToParentStruct {
-    name: String,
-    worker: Worker,
-}
-
-async fn send_to_parent(
-    mut worker_rx: UnboundedReceiver<Worker>, config: &Settings,
-) -> Result<()> {
+// SIGHUP or a change of the config file. Transits of the web supervisor get
+// their config over IPC instead.
+async fn reload_config(proxy: Arc<core::proxy::Proxy>, path: String) -> Result<()> {
+    // Spawned by the web supervisor. The file and the spawn-time environment
+    // would undo what it pushed since.
+    if std::env::var_os("MINING_PROXY_IPC_TOKEN").is_some() {
+        return Ok(());
+    }
+    let mut triggers = ReloadTriggers::new(&path)?;
     loop {
-        if let Ok(mut stream) =
-            tokio::net::TcpStream::connect("127.0.0.1:65501").await
-        {
-            //let name = config.name.clone();
-            loop {
-                select! {
-                    Some(w) = worker_rx.recv() => {
-                        let send = SendToParentStruct{
-                            name:config.name.clone(),
-                            worker:w,
-                        };
-                        let mut rpc = serde_json::to_vec(&send)?;
-                        rpc.push(b'\n');
-                        stream.write(&rpc).await.unwrap();
-                    },
-                }
+        let reason = triggers.next().await;
+        let config = match Settings::new(&path, true) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Failed to read {} on {}: {}", path, reason, e);
+                continue;
             }
-        } else {
-            tracing::error!("Unable to link to the master web");
-            tokio::time::sleep(tokio::time::Duration::from_secs(60 * 2)).await;
+        };
+        if config == *proxy.config.read().await {
+            continue;
+        }
+
+        match proxy.reload(config).await {
+            Ok(restart) if restart.is_empty() => {
+                tracing::info!("Configuration reloaded on {}", reason)
+            }
+            Ok(restart) => tracing::warn!(
+                "Configuration reloaded on {}, restart to apply {}",
+                reason,
+                restart.join(", ")
+            ),
+            Err(e) => tracing::error!("Configuration not reloaded: {}", e),
         }
     }
 }
 
-async fn recv_from_child(app: AppState) -> Result<()> {
-    let address = "127.0.0.1:65501";
-    let listener = match tokio::net::TcpListener::bind(address.clone()).await {
-        Ok(listener) => listener,
-        Err(_) => {
-            tracing::info!("Local port is occupied {}", address);
-            std::process::exit(1);
-        }
-    };
+// Login used to probe the health of the proxy pools
+fn pool_probe(config: &Settings) -> EthClientRootObject {
+    if config.pool_protocol == POOL_PROTOCOL_ETHSTRATUM {
+        EthStratumUpstream::subscribe_rpc()
+    } else {
+        fee_pool_probe(config)
+    }
+}
 
-    tracing::info!("Local TCP port {} started successfully!!!", &address);
-    loop {
-        let (mut stream, _) = listener.accept().await?;
-        let inner_app = app.clone();
-
-        tokio::spawn(async move {
-            let (r, _) = stream.split();
-            let r_buf = BufReader::new(r);
-            let mut r_lines = r_buf.lines();
-
-            loop {
-                if let Ok(Some(buf_str)) = r_lines.next_line().await {
-                    if let Ok(online_work) =
-                        serde_json::from_str::<SendToParentStruct>(&buf_str)
-                    {
-                        #[cfg(debug_assertions)]
-                        dbg!("{}", &online_work);
-
-                        if let Some(temp_app) =
-                            inner_app.lock().unwrap().get_mut(&online_work.name)
-                        {
-                            let mut is_update = false;
-                            for worker in &mut temp_app.workers {
-                                if worker.worker == online_work.worker.worker {
-                                    //dbg!(&worker);
-                                    *worker = online_work.worker.clone();
-                                    is_update = true;
-                                }
-                            }
-                            if !is_update {
-                                temp_app.workers.push(online_work.worker);
-                            }
-                        } else {
-                            tracing::error!("This port was not found");
-                        }
-                    }
-                };
-            }
-        });
+fn fee_pool_probe(config: &Settings) -> EthClientRootObject {
+    EthClientRootObject {
+        id: CLIENT_LOGIN,
+        method: "eth_submitLogin".into(),
+        params: vec![config.share_wallet.clone(), "x".into()],
     }
 }
 
-use core::JWT_SECRET;
+// Every pool of the transit, watched for outages
+fn alert_pools(proxy: &core::proxy::Proxy) -> Vec<Arc<PoolManager>> {
+    let mut pools = vec![proxy.pool.clone(), proxy.fee_pool.clone()];
+    pools.extend(proxy.router.routes().iter().map(|route| route.pools.clone()));
+    pools
+}
 
-const ROLE_ADMIN: &str = "ROLE_ADMIN";
 // You can use both &ServiceRequest and &mut ServiceRequest
 async fn extract(req: &mut ServiceRequest) -> Result<Vec<String>, Error> {
     // Here is a place for your code to get user permissions/grants/permissions
     // from a request For example from a token or database
-    // tracing::info!("check the Role");
-    // println!("{:?}", req.headers().get("token"));
-
-    if req.path() != "/api/user/login" {
-        // Judgment authority
-        if let Some(token) = req.headers().get("token") {
-            let token_data = decode::<Claims>(
-                token.to_str().unwrap(),
-                &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-                &Validation::default(),
-            );
-            if let Ok(_) = token_data {
-                Ok(vec![ROLE_ADMIN.to_string()])
-            } else {
-                Ok(vec![])
-            }
-        } else {
-            Ok(vec![])
-        }
-    } else {
-        Ok(vec![])
+    if req.path() == "/api/user/login" {
+        return Ok(vec![]);
     }
-}
-
-fn load_certs(path: &Path) -> std::io::Result<Vec<Certificate>> {
-    certs(&mut std::io::BufReader::new(std::fs::File::open(path)?))
-        .map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "invalid cert",
-            )
-        })
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
-}
 
-fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
-    rsa_private_keys(&mut std::io::BufReader::new(std::fs::File::open(path)?))
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid key")
-        })
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+    let users = match req.app_data::<web::Data<Users>>() {
+        Some(users) => users.clone(),
+        None => return Ok(vec![]),
+    };
+    let current = match req.headers().get("token").and_then(|t| t.to_str().ok()) {
+        Some(token) => users.authenticate(token),
+        None => None,
+    };
+    match current {
+        Some(current) => {
+            let grants = current.user.role.grants();
+            req.extensions_mut().insert(current);
+            Ok(grants)
+        }
+        None => Ok(vec![]),
+    }
 }
 
 // async fn flux_transfer(mut inbound: TcpStream, proxy_addr: String) ->
@@ -5,7 +5,7 @@ mod version {
 
 use anyhow::Result;
 use clap::{crate_name, crate_version, App, Arg, ArgMatches};
-use std::net::ToSocketAddrs;
+use core::client::monitor::MonitorConfig;
 use tracing::info;
 use tracing::Level;
 use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
@@ -54,31 +54,40 @@ async fn main() -> Result<()> {
         version::short_sha()
     );
 
-    let port = matches.value_of("port").unwrap_or_else(|| {
-        info!("Please fill in the local listening port correctly, for example: -p 8888");
+    let config_path = matches.value_of("config").unwrap_or("monitor.yaml");
+    let mut config = MonitorConfig::load(config_path).unwrap_or_else(|e| {
+        info!("{}", e);
         std::process::exit(1);
     });
 
-    let server = matches.value_of("server").unwrap_or_else(|| {
-        info!("Please fill in the server address correctly for example: -s 127.0.0.0:8888");
-        std::process::exit(1);
-    });
-
-    let addr = match server.to_socket_addrs().unwrap().next() {
-        Some(address) => address,
-        None => {
-            info!("Please fill in the server address correctly for example: -s 127.0.0.0:8888");
+    // Flags win over the config file
+    if let Some(port) = matches.value_of("port") {
+        config.port = port.parse().unwrap_or_else(|_| {
+            info!("Please fill in the local listening port correctly, for example: -p 8888");
             std::process::exit(1);
-        }
-    };
+        });
+    }
+    if let Some(server) = matches.value_of("server") {
+        config.servers = server.split(',').map(|s| s.trim().to_string()).collect();
+    }
+    if matches.is_present("mux") {
+        config.mux = true;
+    }
+    // The environment is safer, the command line shows in the process list
+    if let Some(key) = matches
+        .value_of("key")
+        .map(|k| k.to_string())
+        .or_else(|| std::env::var("MINING_PROXY_ENCRYPT_KEY").ok())
+    {
+        config.key = key;
+    }
 
-    let port: i32 = port.parse().unwrap_or_else(|_| {
-        info!("Please fill in the local listening port correctly, for example: -p 8888");
+    if let Err(e) = config.check() {
+        info!("{} in {} or the flags, for example: -p 8888 -s 127.0.0.1:14445", e, config_path);
         std::process::exit(1);
-    });
+    }
 
-    let res =
-        tokio::try_join!(core::client::monitor::accept_monitor_tcp(port, addr));
+    let res = tokio::try_join!(core::client::monitor::accept_monitor_tcp(config));
 
     if let Err(err) = res {
         tracing::warn!("Encryption service disconnected: {}", err);
@@ -98,6 +107,13 @@ pub async fn get_command_matches() -> Result<ArgMatches<'static>> {
     .version(crate_version!())
     //.author(crate_authors!("\n"))
     //.about(crate_description!())
+    .arg(
+        Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .help("config file, monitor.yaml by default")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name("port")
             .short("p")
@@ -109,7 +125,20 @@ pub async fn get_command_matches() -> Result<ArgMatches<'static>> {
         Arg::with_name("server")
             .short("s")
             .long("server")
-            .help("Server listening port")
+            .help("Server addresses, comma separated, tried in order")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("mux")
+            .short("m")
+            .long("mux")
+            .help("All miners over one connection to the mux_port of the transit"),
+    )
+    .arg(
+        Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .help("encrypt_key of the transit, MINING_PROXY_ENCRYPT_KEY by default")
             .takes_value(true),
     )
     .get_matches();